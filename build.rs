@@ -0,0 +1,37 @@
+//! Select the backend behind `XSave::save()` and `XSave::load()`
+//!
+//! With the `asm` feature, the crate either issues the XSAVE instructions
+//! or drives a software model of them. The choice depends on the target,
+//! on Miri and on the `soft` feature, so it is made once here and exposed
+//! to the crate as three cfgs:
+//!
+//! * `xsave_hw`: the instructions are issued (x86_64 only)
+//! * `xsave_soft`: the software model is used (under Miri, with the `soft`
+//!   feature, or on wasm32), which requires `std`
+//! * `xsave_asm`: either of them, so that the CPU state can be used at all
+//!
+//! On other targets the `asm` feature has no effect, and the crate stays
+//! `no_std` without any of the functions touching the CPU state.
+
+use std::env::var_os;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(xsave_asm, xsave_hw, xsave_soft)");
+
+    let arch = var_os("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let asm = var_os("CARGO_FEATURE_ASM").is_some();
+    let soft = var_os("CARGO_CFG_MIRI").is_some()
+        || var_os("CARGO_FEATURE_SOFT").is_some()
+        || arch == "wasm32";
+
+    if asm && soft {
+        println!("cargo:rustc-cfg=xsave_soft");
+    } else if asm && arch == "x86_64" {
+        println!("cargo:rustc-cfg=xsave_hw");
+    }
+
+    if asm && (soft || arch == "x86_64") {
+        println!("cargo:rustc-cfg=xsave_asm");
+    }
+}
@@ -0,0 +1,288 @@
+//! The aliasing of the XMM, YMM and ZMM registers
+//!
+//! Each 512-bit vector register is split across several state components:
+//! for registers 0 through 15, bits 127:0 live in SSE, bits 255:128 in AVX
+//! and bits 511:256 in ZMM_Hi256, while registers 16 through 31 live
+//! entirely in Hi16_ZMM. Writes with a VEX or EVEX encoding also zero the
+//! bits of the register above the destination, up to the maximum vector
+//! length enabled in XCR0, whereas legacy SSE writes preserve them.
+
+use super::*;
+
+/// A vector register, by width and index
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VectorReg {
+    Xmm(u8),
+    Ymm(u8),
+    Zmm(u8),
+}
+
+/// The encoding of an instruction writing a vector register
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Legacy SSE, which preserves the upper bits of the register
+    Legacy,
+
+    /// VEX, which zeroes the upper bits of the register
+    Vex,
+
+    /// EVEX, which zeroes the upper bits of the register
+    Evex,
+}
+
+/// The effect of writing a vector register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriteEffect {
+    /// The components holding the written bits
+    pub written: XStateBv,
+
+    /// The components holding the bits which are zeroed
+    pub zeroed: XStateBv,
+
+    /// The range of bits which are zeroed, from inclusive to exclusive
+    pub zeroed_bits: (u16, u16),
+}
+
+/// The component and in-component offset of one 128-bit register lane
+#[inline]
+fn lane(index: u8, lane: u16) -> (Component, usize) {
+    let index = usize::from(index);
+    let lane = usize::from(lane);
+
+    match (index, lane) {
+        (16.., _) => (Component::Avx512Hi16Zmm, (index - 16) * 64 + lane * 16),
+        (_, 0) => (Component::Sse, index * 16),
+        (_, 1) => (Component::Avx, index * 16),
+        _ => (Component::Avx512ZmmHi256, index * 32 + (lane - 2) * 16),
+    }
+}
+
+/// The components holding the given 128-bit lanes of a register
+#[inline]
+fn lanes(index: u8, lanes: (u16, u16)) -> XStateBv {
+    (lanes.0..lanes.1).fold(XStateBv::empty(), |bv, l| bv | lane(index, l).0.bit())
+}
+
+impl VectorReg {
+    /// The index of the register
+    #[inline]
+    pub const fn index(self) -> u8 {
+        match self {
+            Self::Xmm(i) | Self::Ymm(i) | Self::Zmm(i) => i,
+        }
+    }
+
+    /// The width of the register in bits
+    #[inline]
+    pub const fn width(self) -> u16 {
+        match self {
+            Self::Xmm(..) => 128,
+            Self::Ymm(..) => 256,
+            Self::Zmm(..) => 512,
+        }
+    }
+
+    /// The components holding the bits of this register
+    #[inline]
+    pub fn components(self) -> XStateBv {
+        lanes(self.index(), (0, self.width() / 128))
+    }
+
+    /// Describe the effect of writing this register
+    ///
+    /// `xcr0` determines the maximum vector length. This fails with
+    /// `XSaveError::InvalidRegister` if the register cannot be written with
+    /// the encoding, or with `XSaveError::UnsupportedComponents` if the
+    /// write would raise #UD because XCR0 does not enable the register. VEX
+    /// encodings also require SSE and AVX to be enabled, and EVEX encodings
+    /// the AVX-512 components as well, whatever the register.
+    pub fn write_effect(
+        self,
+        encoding: Encoding,
+        xcr0: XStateBv,
+    ) -> Result<WriteEffect, XSaveError> {
+        let index = self.index();
+        let encodable = match (encoding, self) {
+            (_, _) if index >= 32 => false,
+            (Encoding::Evex, _) => true,
+            (Encoding::Vex, Self::Zmm(..)) => false,
+            (Encoding::Vex, _) => index < 16,
+            (Encoding::Legacy, Self::Xmm(..)) => index < 16,
+            (Encoding::Legacy, _) => false,
+        };
+
+        if !encodable {
+            return Err(XSaveError::InvalidRegister(index));
+        }
+
+        let required = self.components()
+            | match encoding {
+                Encoding::Legacy => XStateBv::empty(),
+                Encoding::Vex => XStateBv::SSE | XStateBv::AVX,
+                Encoding::Evex => XStateBv::SSE | XStateBv::AVX | XStateBv::AVX512,
+            };
+
+        let missing = required - xcr0;
+        if !missing.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(missing));
+        }
+
+        let maxvl = match () {
+            _ if xcr0.contains(XStateBv::AVX512) => 512,
+            _ if xcr0.contains(XStateBv::AVX) => 256,
+            _ => 128,
+        };
+
+        let zeroed_bits = match encoding {
+            Encoding::Legacy => (self.width(), self.width()),
+            _ => (self.width(), maxvl.max(self.width())),
+        };
+
+        Ok(WriteEffect {
+            written: self.components(),
+            zeroed: lanes(index, (zeroed_bits.0 / 128, zeroed_bits.1 / 128)),
+            zeroed_bits,
+        })
+    }
+}
+
+impl XSave {
+    /// The bytes of one 128-bit lane of a vector register
+    #[inline]
+    fn lane_mut(&mut self, index: u8, l: u16) -> Option<&mut [u8]> {
+        let (component, offset) = lane(index, l);
+        let (start, end) = match component {
+            Component::Sse => {
+                let start = 160 + offset;
+                (start, start + 16)
+            }
+            _ => {
+                let (start, _) = self.component_range(component)?;
+                (start + offset, start + offset + 16)
+            }
+        };
+
+        self.as_bytes_mut().get_mut(start..end)
+    }
+
+    /// Apply a vector register write, following the aliasing rules
+    ///
+    /// `value` holds the little-endian bytes written, which must match the
+    /// width of the register. Any component which is written but absent
+    /// from `xstate_bv` is enabled first, so it starts from its init state.
+    pub fn write_vector(
+        &mut self,
+        reg: VectorReg,
+        encoding: Encoding,
+        xcr0: XStateBv,
+        value: &[u8],
+    ) -> Result<WriteEffect, XSaveError> {
+        let effect = reg.write_effect(encoding, xcr0)?;
+        if value.len() != usize::from(reg.width() / 8) {
+            return Err(XSaveError::InvalidLength(value.len()));
+        }
+
+        for component in Component::ALL {
+            if effect.written.contains(component.bit())
+                && !self.header.xstate_bv.contains(component.bit())
+            {
+                self.enable(component)?;
+            }
+        }
+
+        let index = reg.index();
+        for (l, chunk) in (0..).zip(value.chunks(16)) {
+            if let Some(lane) = self.lane_mut(index, l) {
+                lane.copy_from_slice(chunk);
+            }
+        }
+
+        for l in effect.zeroed_bits.0 / 128..effect.zeroed_bits.1 / 128 {
+            if self.header.xstate_bv.contains(lane(index, l).0.bit()) {
+                if let Some(lane) = self.lane_mut(index, l) {
+                    lane.fill(0);
+                }
+            }
+        }
+
+        Ok(effect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVX: XStateBv = XStateBv::from_bits_truncate(0b111);
+    const ZMM: XStateBv = XStateBv::from_bits_truncate(0b1110_0111);
+
+    #[test]
+    fn effect() {
+        let ymm3 = VectorReg::Ymm(3).write_effect(Encoding::Vex, ZMM).unwrap();
+        assert_eq!(ymm3.written, XStateBv::SSE | XStateBv::AVX);
+        assert_eq!(ymm3.zeroed, XStateBv::AVX512_ZMM_HI256);
+        assert_eq!(ymm3.zeroed_bits, (256, 512));
+
+        let xmm3 = VectorReg::Xmm(3)
+            .write_effect(Encoding::Legacy, ZMM)
+            .unwrap();
+        assert_eq!(xmm3.zeroed, XStateBv::empty());
+
+        let xmm3 = VectorReg::Xmm(3).write_effect(Encoding::Vex, AVX).unwrap();
+        assert_eq!(xmm3.zeroed, XStateBv::AVX);
+        assert_eq!(xmm3.zeroed_bits, (128, 256));
+
+        let zmm20 = VectorReg::Zmm(20)
+            .write_effect(Encoding::Evex, ZMM)
+            .unwrap();
+        assert_eq!(zmm20.written, XStateBv::AVX512_HI16_ZMM);
+
+        assert_eq!(
+            VectorReg::Ymm(16).write_effect(Encoding::Vex, ZMM),
+            Err(XSaveError::InvalidRegister(16))
+        );
+        assert_eq!(
+            VectorReg::Zmm(0).write_effect(Encoding::Evex, AVX),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX512))
+        );
+
+        // VEX raises #UD unless XCR0[2:1] = 11b, even for an XMM register.
+        let sse = XStateBv::X87 | XStateBv::SSE;
+        assert_eq!(
+            VectorReg::Xmm(3).write_effect(Encoding::Vex, sse),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX))
+        );
+        assert!(VectorReg::Xmm(3)
+            .write_effect(Encoding::Legacy, sse)
+            .is_ok());
+    }
+
+    #[test]
+    fn write() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576 + 3 * 16] = 0xAA;
+
+        xsave
+            .write_vector(VectorReg::Xmm(3), Encoding::Legacy, AVX, &[1; 16])
+            .unwrap();
+        assert_eq!(xsave.legacy.xmm[3].0, [1; 16]);
+        assert_eq!(xsave.as_bytes()[576 + 3 * 16], 0xAA);
+
+        xsave
+            .write_vector(VectorReg::Xmm(3), Encoding::Vex, AVX, &[2; 16])
+            .unwrap();
+        assert_eq!(xsave.as_bytes()[576 + 3 * 16], 0);
+
+        xsave
+            .write_vector(VectorReg::Zmm(17), Encoding::Evex, ZMM, &[3; 64])
+            .unwrap();
+        assert!(xsave.header.xstate_bv.contains(XStateBv::AVX512_HI16_ZMM));
+        assert_eq!(xsave.as_bytes()[1664 + 64..1664 + 128], [3; 64]);
+
+        assert_eq!(
+            xsave.write_vector(VectorReg::Ymm(0), Encoding::Vex, AVX, &[0; 16]),
+            Err(XSaveError::InvalidLength(16))
+        );
+    }
+}
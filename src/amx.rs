@@ -0,0 +1,267 @@
+//! Typed access to the AMX tile configuration
+//!
+//! The TILECFG component holds the 64-byte configuration loaded by
+//! `ldtilecfg`: the palette, the row at which an interrupted tile
+//! instruction restarts, and the shape of each tile. Only palette 0, which
+//! leaves the tiles unconfigured, and palette 1 are architectural.
+
+use super::*;
+
+/// The offset of the start row
+const START_ROW: usize = 1;
+
+/// The reserved bytes between the start row and the column widths
+const RESERVED: usize = 2;
+
+/// The offset of the bytes per row of each tile, as `u16` values
+const COLSB: usize = 16;
+
+/// The offset of the rows of each tile
+const ROWS: usize = 48;
+
+/// The number of tile slots in the configuration
+const TILE_SLOTS: usize = 16;
+
+/// The number of tiles available in palette 1
+const PALETTE1_TILES: usize = 8;
+
+/// The largest number of rows of a tile in palette 1
+const PALETTE1_MAX_ROWS: u8 = 16;
+
+/// The largest number of bytes per row of a tile in palette 1
+const PALETTE1_MAX_COLSB: u16 = 64;
+
+/// The AMX tile configuration, as loaded by `ldtilecfg`
+#[repr(C, align(64))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ConstDefault)]
+pub struct TileCfg(pub [u8; 64]);
+
+impl Default for TileCfg {
+    #[inline]
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TileCfg {
+    /// The palette, where 0 means the tiles are unconfigured
+    #[inline]
+    pub fn palette_id(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// Set the palette
+    #[inline]
+    pub fn set_palette_id(&mut self, palette: u8) {
+        self.0[0] = palette;
+    }
+
+    /// The row at which an interrupted tile instruction restarts
+    #[inline]
+    pub fn start_row(&self) -> u8 {
+        self.0[START_ROW]
+    }
+
+    /// Set the restart row
+    #[inline]
+    pub fn set_start_row(&mut self, row: u8) {
+        self.0[START_ROW] = row;
+    }
+
+    /// The bytes per row of every tile slot
+    pub fn colsb(&self) -> [u16; TILE_SLOTS] {
+        let mut colsb = [0; TILE_SLOTS];
+        for (value, bytes) in colsb.iter_mut().zip(self.0[COLSB..ROWS].chunks_exact(2)) {
+            *value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+
+        colsb
+    }
+
+    /// Set the bytes per row of every tile slot
+    pub fn set_colsb(&mut self, colsb: [u16; TILE_SLOTS]) {
+        for (value, bytes) in colsb.iter().zip(self.0[COLSB..ROWS].chunks_exact_mut(2)) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// The rows of every tile slot
+    #[inline]
+    pub fn rows(&self) -> [u8; TILE_SLOTS] {
+        let mut rows = [0; TILE_SLOTS];
+        rows.copy_from_slice(&self.0[ROWS..]);
+        rows
+    }
+
+    /// Set the rows of every tile slot
+    #[inline]
+    pub fn set_rows(&mut self, rows: [u8; TILE_SLOTS]) {
+        self.0[ROWS..].copy_from_slice(&rows);
+    }
+
+    /// Set the shape of one tile, in rows and bytes per row
+    ///
+    /// This fails with `XSaveError::InvalidRegister` if there is no such
+    /// tile slot.
+    pub fn set_tile(&mut self, tile: u8, rows: u8, colsb: u16) -> Result<(), XSaveError> {
+        let index = usize::from(tile);
+        if index >= TILE_SLOTS {
+            return Err(XSaveError::InvalidRegister(tile));
+        }
+
+        self.0[ROWS + index] = rows;
+        self.0[COLSB + index * 2..][..2].copy_from_slice(&colsb.to_le_bytes());
+        Ok(())
+    }
+
+    /// Check that `ldtilecfg` would accept this configuration
+    ///
+    /// Palette 0 requires every other byte to be zero. Palette 1 requires
+    /// the reserved bytes and the slots beyond its eight tiles to be zero,
+    /// and each tile to have at most 16 rows of at most 64 bytes, with
+    /// either both dimensions zero or neither. The first offending byte is
+    /// reported as `XSaveError::InvalidTileConfig` with its offset in the
+    /// configuration.
+    pub fn validate(&self) -> Result<(), XSaveError> {
+        let first_nonzero = |range: core::ops::Range<usize>| {
+            let start = range.start;
+            match self.0[range].iter().position(|b| *b != 0) {
+                Some(i) => Err(XSaveError::InvalidTileConfig(start + i)),
+                None => Ok(()),
+            }
+        };
+
+        match self.palette_id() {
+            0 => first_nonzero(START_ROW..self.0.len()),
+            1 => {
+                first_nonzero(RESERVED..COLSB)?;
+
+                let colsb = self.colsb();
+                let rows = self.rows();
+                for tile in 0..PALETTE1_TILES {
+                    if colsb[tile] > PALETTE1_MAX_COLSB || (rows[tile] == 0) != (colsb[tile] == 0) {
+                        return Err(XSaveError::InvalidTileConfig(COLSB + tile * 2));
+                    }
+
+                    if rows[tile] > PALETTE1_MAX_ROWS {
+                        return Err(XSaveError::InvalidTileConfig(ROWS + tile));
+                    }
+                }
+
+                first_nonzero(COLSB + PALETTE1_TILES * 2..ROWS)?;
+                first_nonzero(ROWS + PALETTE1_TILES..self.0.len())
+            }
+            _ => Err(XSaveError::InvalidTileConfig(0)),
+        }
+    }
+}
+
+impl XSave {
+    /// The tile configuration
+    ///
+    /// An absent component is in its initial configuration, which is
+    /// all zero.
+    pub fn tilecfg(&self) -> TileCfg {
+        let mut cfg = TileCfg::DEFAULT;
+        if let Some(bytes) = self.component_bytes(Component::AmxTileCfg) {
+            cfg.0.copy_from_slice(bytes);
+        }
+
+        cfg
+    }
+
+    /// Set the tile configuration, marking the component present
+    ///
+    /// This fails, leaving the buffer unchanged, if the configuration is
+    /// invalid or the buffer has no room for the component.
+    pub fn set_tilecfg(&mut self, cfg: &TileCfg) -> Result<(), XSaveError> {
+        cfg.validate()?;
+
+        let (start, end) = self
+            .component_range(Component::AmxTileCfg)
+            .ok_or(XSaveError::UnsupportedComponents(XStateBv::AMX_TILECFG))?;
+
+        self.as_bytes_mut()[start..end].copy_from_slice(&cfg.0);
+        self.header.xstate_bv |= XStateBv::AMX_TILECFG;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors() {
+        let mut cfg = TileCfg::DEFAULT;
+        assert_eq!(cfg.validate(), Ok(()));
+
+        cfg.set_palette_id(1);
+        cfg.set_start_row(3);
+        cfg.set_tile(2, 16, 64).unwrap();
+        assert_eq!(cfg.set_tile(16, 1, 1), Err(XSaveError::InvalidRegister(16)));
+
+        assert_eq!(cfg.palette_id(), 1);
+        assert_eq!(cfg.start_row(), 3);
+        assert_eq!(cfg.rows()[2], 16);
+        assert_eq!(cfg.colsb()[2], 64);
+        assert_eq!(&cfg.0[16 + 4..16 + 6], &[64, 0]);
+        assert_eq!(cfg.0[48 + 2], 16);
+
+        let mut copy = TileCfg::DEFAULT;
+        copy.set_palette_id(1);
+        copy.set_start_row(3);
+        copy.set_rows(cfg.rows());
+        copy.set_colsb(cfg.colsb());
+        assert_eq!(copy, cfg);
+    }
+
+    #[test]
+    fn validate() {
+        let mut cfg = TileCfg::DEFAULT;
+        cfg.set_start_row(1);
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(1)));
+
+        cfg.set_palette_id(1);
+        assert_eq!(cfg.validate(), Ok(()));
+
+        cfg.set_palette_id(2);
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(0)));
+        cfg.set_palette_id(1);
+
+        cfg.set_tile(0, 16, 65).unwrap();
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(16)));
+        cfg.set_tile(0, 17, 64).unwrap();
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(48)));
+        cfg.set_tile(0, 0, 64).unwrap();
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(16)));
+        cfg.set_tile(0, 16, 64).unwrap();
+        assert_eq!(cfg.validate(), Ok(()));
+
+        cfg.set_tile(8, 1, 4).unwrap();
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(32)));
+        cfg.0[32] = 0;
+        assert_eq!(cfg.validate(), Err(XSaveError::InvalidTileConfig(56)));
+    }
+
+    #[test]
+    fn xsave() {
+        let mut xsave = XSave::DEFAULT;
+        assert_eq!(xsave.tilecfg(), TileCfg::DEFAULT);
+
+        let mut cfg = TileCfg::DEFAULT;
+        cfg.set_palette_id(1);
+        cfg.set_tile(7, 8, 32).unwrap();
+        xsave.set_tilecfg(&cfg).unwrap();
+        assert_eq!(xsave.tilecfg(), cfg);
+        assert_eq!(xsave.as_bytes()[2752], 1);
+        assert!(xsave.header.xstate_bv.contains(XStateBv::AMX_TILECFG));
+
+        cfg.set_palette_id(3);
+        assert_eq!(
+            xsave.set_tilecfg(&cfg),
+            Err(XSaveError::InvalidTileConfig(0))
+        );
+        assert_eq!(xsave.tilecfg().palette_id(), 1);
+    }
+}
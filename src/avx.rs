@@ -0,0 +1,230 @@
+//! A smaller buffer for the x87, SSE and AVX state alone
+
+use super::*;
+
+/// The offset of the YMM upper halves in the standard format
+const YMMH: usize = 576;
+
+/// An XSAVE area holding only the x87, SSE and AVX components
+///
+/// At 832 bytes, this is the standard format area truncated after the AVX
+/// component; the header, offsets and semantics match `XSave`. It suits
+/// embedders keeping many saved states, such as green thread runtimes,
+/// which never enable AVX-512 or AMX. Saves and restores request only
+/// the three components in `XSaveAvx::MASK`, leaving the others alone.
+#[repr(C, align(64))]
+#[derive(Copy, Clone, Debug)]
+pub struct XSaveAvx {
+    pub legacy: XSaveLegacy,
+    pub header: XSaveHeader,
+    pub ymmh: [Xmm; 16],
+}
+
+impl ConstDefault for XSaveAvx {
+    const DEFAULT: Self = Self {
+        legacy: XSaveLegacy::DEFAULT,
+        header: XSaveHeader::DEFAULT,
+        ymmh: [Xmm::DEFAULT; 16],
+    };
+}
+
+impl Default for XSaveAvx {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            legacy: XSaveLegacy::default(),
+            ..Self::DEFAULT
+        }
+    }
+}
+
+impl XSaveAvx {
+    /// The components held by this buffer
+    pub const MASK: XStateBv =
+        XStateBv::from_bits_truncate(XStateBv::X87.bits | XStateBv::SSE.bits | XStateBv::AVX.bits);
+
+    /// Save the x87, SSE and AVX state
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub extern "C" fn save(&mut self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            soft::save(&mut xsave);
+
+            let saved = Self::truncate(&xsave);
+            self.legacy = saved.legacy;
+            self.ymmh = saved.ymmh;
+            self.header.xstate_bv = (self.header.xstate_bv - Self::MASK) | saved.header.xstate_bv;
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "xsave   [{}]",
+                in(reg) self,
+                in("rax") Self::MASK.bits,
+                in("rdx") 0usize,
+            )
+        }
+    }
+
+    /// Load the x87, SSE and AVX state
+    ///
+    /// The bits of other components in `xstate_bv` are ignored as long as
+    /// XCR0 enables them. As for `XSave::load()`, `xrstor` raises #GP if
+    /// any bit is set outside XCR0 or the header is not in the standard
+    /// format.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub extern "C" fn load(&self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            soft::save(&mut xsave);
+            self.write_into(&mut xsave);
+            soft::load(&xsave);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "xrstor  [{}]",
+                in(reg) self,
+                in("rax") Self::MASK.bits,
+                in("rdx") 0usize,
+                clobber_abi("C"),
+            )
+        }
+    }
+}
+
+impl XSaveAvx {
+    /// Copy the components held by an `XSave`, dropping any others
+    fn truncate(xsave: &XSave) -> Self {
+        let mut ymmh = [Xmm::DEFAULT; 16];
+        let bytes = &xsave.as_bytes()[YMMH..];
+        for (xmm, chunk) in ymmh.iter_mut().zip(bytes.chunks_exact(16)) {
+            xmm.0.copy_from_slice(chunk);
+        }
+
+        let mut header = xsave.header;
+        header.xstate_bv &= Self::MASK;
+
+        Self {
+            legacy: xsave.legacy,
+            header,
+            ymmh,
+        }
+    }
+
+    /// Overwrite the components held by this buffer in an `XSave`
+    ///
+    /// The rest of the header is copied too, while the `xstate_bv` bits of
+    /// other components are kept.
+    fn write_into(&self, xsave: &mut XSave) {
+        let others = xsave.header.xstate_bv - Self::MASK;
+        xsave.legacy = self.legacy;
+        xsave.header = self.header;
+        xsave.header.xstate_bv = others | (self.header.xstate_bv & Self::MASK);
+
+        let bytes = &mut xsave.as_bytes_mut()[YMMH..];
+        for (chunk, xmm) in bytes.chunks_exact_mut(16).zip(self.ymmh.iter()) {
+            chunk.copy_from_slice(&xmm.0);
+        }
+    }
+}
+
+impl From<&XSaveAvx> for XSave {
+    /// Widen the buffer; the other components are in their initial state
+    #[inline]
+    fn from(avx: &XSaveAvx) -> Self {
+        let mut xsave = XSave::DEFAULT;
+        avx.write_into(&mut xsave);
+        xsave
+    }
+}
+
+impl TryFrom<&XSave> for XSaveAvx {
+    type Error = XSaveError;
+
+    /// Narrow the buffer, which must not hold any other component
+    ///
+    /// The compacted format is reported as `XSaveError::InvalidMask` and
+    /// other components present in `xstate_bv` as
+    /// `XSaveError::UnsupportedComponents`.
+    fn try_from(xsave: &XSave) -> Result<Self, XSaveError> {
+        if xsave.header.xcomp_bv.contains(XCompBv::COMPACT) {
+            return Err(XSaveError::InvalidMask(XStateBv::empty()));
+        }
+
+        let other = xsave.header.xstate_bv - XSaveAvx::MASK;
+        if !other.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(other));
+        }
+
+        Ok(Self::truncate(xsave))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::align_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<XSaveAvx>(), 832);
+        assert_eq!(align_of::<XSaveAvx>(), 64);
+        assert_eq!(
+            XSaveAvx::SIZE,
+            size_for(XSaveAvx::MASK, false, &ComponentTable::ARCHITECTURAL)
+        );
+    }
+
+    #[test]
+    fn convert() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.legacy.xmm[2] = Xmm([0x22; 16]);
+        xsave.as_bytes_mut()[YMMH + 32] = 0xAA;
+
+        let avx = XSaveAvx::try_from(&xsave).unwrap();
+        assert_eq!(avx.header.xstate_bv, XSaveAvx::MASK);
+        assert_eq!(avx.ymmh[2].0[0], 0xAA);
+
+        let back = XSave::from(&avx);
+        assert_eq!(back.as_bytes()[..], xsave.as_bytes()[..]);
+
+        xsave.header.xstate_bv |= XStateBv::AVX512_OPMASK;
+        assert_eq!(
+            XSaveAvx::try_from(&xsave).err(),
+            Some(XSaveError::UnsupportedComponents(XStateBv::AVX512_OPMASK))
+        );
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn roundtrip() {
+        match component::xcr0() {
+            Some(xcr0) if xcr0.contains(XStateBv::AVX) => (),
+            _ => return,
+        }
+
+        let mut original = XSaveAvx::DEFAULT;
+        original.save();
+
+        let mut avx = original;
+        avx.header.xstate_bv |= XSaveAvx::MASK;
+        avx.legacy.fcw = Fcw::from_bits_truncate(0x027F);
+        avx.ymmh[15] = Xmm([0x5A; 16]);
+        avx.load();
+
+        let mut saved = XSaveAvx::DEFAULT;
+        saved.save();
+        original.load();
+
+        assert_eq!(saved.legacy.fcw.bits, 0x027F);
+        assert_eq!(saved.ymmh[15].0, [0x5A; 16]);
+    }
+}
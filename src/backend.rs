@@ -0,0 +1,243 @@
+//! Interchangeable implementations of the save and restore instructions
+//!
+//! Code written against `Backend` can run with `DefaultBackend`, which is
+//! whatever this build uses for `XSave::save()` and `XSave::load()`, or
+//! against `SoftBackend`, a software model whose state is an ordinary
+//! value. The latter makes it possible to run the same code in unit tests
+//! and deterministic simulators, with one model per simulated CPU.
+//!
+//! The trait is for code which takes a backend as a parameter. The methods
+//! of `XSave` and the other buffer types do not go through it, so they
+//! always use the default.
+
+use super::*;
+
+/// An implementation of `xsave` and `xrstor`
+pub trait Backend {
+    /// Read XCR0, if it is available
+    fn xcr0(&self) -> Option<XStateBv>;
+
+    /// Save the state of every enabled component
+    fn save(&mut self, xsave: &mut XSave);
+
+    /// Restore the state of every enabled component
+    ///
+    /// Like `XSave::load()`, this does not check `xstate_bv` against XCR0
+    /// and faults on buffers which the instruction rejects.
+    fn load(&mut self, xsave: &XSave);
+
+    /// Restore the state, refusing components which are not enabled
+    ///
+    /// This behaves like `XSave::try_load()`.
+    fn try_load(&mut self, xsave: &XSave) -> Result<(), XSaveError> {
+        if let Some(xcr0) = self.xcr0() {
+            let unsupported = xsave.header.xstate_bv - xcr0;
+            if !unsupported.is_empty() {
+                return Err(XSaveError::UnsupportedComponents(unsupported));
+            }
+        }
+
+        self.load(xsave);
+        Ok(())
+    }
+}
+
+/// The backend used by `XSave::save()` and `XSave::load()`
+///
+/// This is not an implementation of its own: it calls those methods, so
+/// it issues the instructions of the current CPU, or, under Miri, when the
+/// `soft` feature is enabled, or on wasm32, drives the per-thread software
+/// model which the build selects instead.
+#[cfg(xsave_asm)]
+#[derive(Debug, Default)]
+pub struct DefaultBackend(());
+
+#[cfg(xsave_asm)]
+impl DefaultBackend {
+    /// Create a new instance
+    #[inline]
+    pub const fn new() -> Self {
+        Self(())
+    }
+}
+
+#[cfg(xsave_asm)]
+impl Backend for DefaultBackend {
+    #[inline]
+    fn xcr0(&self) -> Option<XStateBv> {
+        component::xcr0()
+    }
+
+    #[inline]
+    fn save(&mut self, xsave: &mut XSave) {
+        xsave.save()
+    }
+
+    #[inline]
+    fn load(&mut self, xsave: &XSave) {
+        xsave.load()
+    }
+
+    #[inline]
+    fn try_load(&mut self, xsave: &XSave) -> Result<(), XSaveError> {
+        xsave.try_load()
+    }
+}
+
+/// A software model of the extended CPU state
+///
+/// The model supports the standard format and the user components which
+/// fit in an `XSave`. Restoring a buffer which would fault on hardware
+/// panics.
+#[derive(Copy, Clone, Debug)]
+pub struct SoftBackend {
+    state: XSave,
+}
+
+impl SoftBackend {
+    /// The components enabled in the model's XCR0
+    pub const XCR0: XStateBv = XStateBv::from_bits_truncate(0x2FF);
+
+    /// The MXCSR bits supported by the model
+    pub const MXCSR_MASK: MxCsr = MxCsr::from_bits_truncate(0xFFFF);
+
+    /// Create a model with every component in its initial configuration
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            state: XSave {
+                legacy: XSaveLegacy {
+                    mxcsr_mask: Self::MXCSR_MASK,
+                    ..XSaveLegacy::DEFAULT
+                },
+                ..XSave::DEFAULT
+            },
+        }
+    }
+
+    /// The current state of the model
+    #[inline]
+    pub fn state(&self) -> &XSave {
+        &self.state
+    }
+
+    /// The reason `xrstor` would fault on a buffer, if it would
+    pub(crate) fn fault(&self, xsave: &XSave) -> Option<&'static str> {
+        let header = &xsave.header;
+        if header.xcomp_bv.contains(XCompBv::COMPACT) {
+            return Some("the compacted format is not modelled");
+        }
+
+        if !header.xcomp_bv.is_empty() || header.reserved != [0; 6] {
+            return Some("reserved header bits are set");
+        }
+
+        if !Self::XCR0.contains(header.xstate_bv) {
+            return Some("components are not enabled in XCR0");
+        }
+
+        if !Self::MXCSR_MASK.contains(xsave.legacy.mxcsr) {
+            return Some("reserved MXCSR bits are set");
+        }
+
+        None
+    }
+
+    /// Model `xrstor` on a buffer which does not fault
+    pub(crate) fn restore(&mut self, xsave: &XSave) {
+        let mut state = *xsave;
+        state.legacy.mxcsr_mask = Self::MXCSR_MASK;
+        for component in Component::ALL {
+            if !state.header.xstate_bv.contains(component.bit()) {
+                let _ = state.reset(component);
+            }
+        }
+
+        self.state = state;
+    }
+}
+
+impl Default for SoftBackend {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for SoftBackend {
+    #[inline]
+    fn xcr0(&self) -> Option<XStateBv> {
+        Some(Self::XCR0)
+    }
+
+    #[inline]
+    fn save(&mut self, xsave: &mut XSave) {
+        xsave.legacy = self.state.legacy;
+        xsave.header.xstate_bv = self.state.header.xstate_bv;
+        xsave.extend = self.state.extend;
+    }
+
+    fn load(&mut self, xsave: &XSave) {
+        if let Some(reason) = self.fault(xsave) {
+            panic!("#GP in xrstor: {}", reason);
+        }
+
+        self.restore(xsave);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Code generic over the backend, as a downstream crate would write it
+    fn swap_xmm0<B: Backend>(backend: &mut B, value: Xmm) -> Xmm {
+        let mut xsave = XSave::fxsave_default();
+        backend.save(&mut xsave);
+
+        let old = xsave.legacy.xmm[0];
+        xsave.legacy.xmm[0] = value;
+        xsave.header.xstate_bv |= XStateBv::SSE;
+        backend.try_load(&xsave).unwrap();
+        old
+    }
+
+    #[test]
+    fn soft() {
+        let mut a = SoftBackend::new();
+        let mut b = SoftBackend::default();
+
+        assert_eq!(swap_xmm0(&mut a, Xmm([0xAA; 16])).0, [0; 16]);
+        assert_eq!(a.state().legacy.xmm[0].0, [0xAA; 16]);
+        assert_eq!(b.state().legacy.xmm[0].0, [0; 16]);
+        assert_eq!(swap_xmm0(&mut a, Xmm([0; 16])).0, [0xAA; 16]);
+        assert_eq!(swap_xmm0(&mut b, Xmm([1; 16])).0, [0; 16]);
+
+        let mut xsave = XSave::fxsave_default();
+        xsave.header.xstate_bv = XStateBv::AMX_TILECFG;
+        assert_eq!(
+            a.try_load(&xsave),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AMX_TILECFG))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "#GP in xrstor")]
+    fn fault() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.header.xcomp_bv = XCompBv::COMPACT;
+        SoftBackend::new().load(&xsave);
+    }
+
+    #[test]
+    #[cfg(all(xsave_asm, target_arch = "x86_64"))]
+    fn dynamic() {
+        let soft = SoftBackend::new();
+        let backends: [&dyn Backend; 2] = [&DefaultBackend::new(), &soft];
+
+        for backend in backends {
+            let xcr0 = backend.xcr0().unwrap();
+            assert!(xcr0.contains(XStateBv::X87 | XStateBv::SSE));
+        }
+    }
+}
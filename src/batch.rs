@@ -0,0 +1,109 @@
+//! Saving and restoring many contexts in one pass
+//!
+//! A VMM stopping hundreds of vCPUs at a barrier spends much of each switch
+//! waiting for the next area to arrive from memory. `XSave::save_all()` and
+//! `XSave::load_all()` walk a slice of areas in order and prefetch the
+//! cache lines of the next one while the current one is being processed.
+
+#[cfg(all(xsave_hw, not(doc)))]
+use core::arch::asm;
+
+use super::*;
+
+/// The size of a cache line
+const CACHE_LINE: usize = 64;
+
+/// The bytes written or read by nearly every save and restore
+///
+/// These are the legacy area, the header and the AVX component. The
+/// remaining components are absent in most contexts.
+const HOT: usize = 832;
+
+/// Start fetching the frequently used cache lines of an area
+#[inline(always)]
+fn prefetch(xsave: &XSave) {
+    for line in xsave.as_bytes()[..HOT].chunks(CACHE_LINE) {
+        #[cfg(xsave_soft)]
+        let _ = line;
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "prefetcht0 [{}]",
+                in(reg) line.as_ptr(),
+                options(nostack, preserves_flags, readonly),
+            )
+        }
+    }
+}
+
+impl XSave {
+    /// Save a sequence of contexts, one into each area
+    ///
+    /// For each area in turn, `switch` is called with its index to bring
+    /// the matching context onto the CPU, for instance by running the vCPU
+    /// until it reaches the barrier, and the state is then saved into it.
+    pub fn save_all(areas: &mut [XSave], mut switch: impl FnMut(usize)) {
+        for i in 0..areas.len() {
+            if let Some(next) = areas.get(i + 1) {
+                prefetch(next);
+            }
+
+            switch(i);
+            areas[i].save();
+        }
+    }
+
+    /// Restore a sequence of contexts, one from each area
+    ///
+    /// Each area is restored in turn, and `run` is then called with its
+    /// index to run the matching context before the next one is restored.
+    pub fn load_all(areas: &[XSave], mut run: impl FnMut(usize)) {
+        for (i, area) in areas.iter().enumerate() {
+            if let Some(next) = areas.get(i + 1) {
+                prefetch(next);
+            }
+
+            area.load();
+            run(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch() {
+        let mut backup = XSave::DEFAULT;
+        backup.save();
+
+        let mut areas = [XSave::fxsave_default(); 4];
+        for (i, area) in areas.iter_mut().enumerate() {
+            area.legacy.mxcsr.set_rounding_mode(match i {
+                0 => RoundingMode::Nearest,
+                1 => RoundingMode::Down,
+                2 => RoundingMode::Up,
+                _ => RoundingMode::TowardZero,
+            });
+        }
+
+        let mut seen = [MxCsr::empty(); 4];
+        XSave::load_all(&areas, |i| {
+            let mut current = XSave::DEFAULT;
+            current.save();
+            seen[i] = current.legacy.mxcsr;
+        });
+
+        let mut saved = [XSave::DEFAULT; 4];
+        XSave::save_all(&mut saved, |i| areas[i].load());
+
+        backup.load();
+
+        for i in 0..4 {
+            assert_eq!(seen[i], areas[i].legacy.mxcsr);
+            assert_eq!(saved[i].legacy.mxcsr, areas[i].legacy.mxcsr);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Detection of the XSAVE capabilities of the current CPU
+//!
+//! Everything here is read with CPUID alone, which needs no help from the
+//! OS, so it works equally well in a bootloader or a kernel before it has
+//! enabled XSAVE.
+
+#[cfg(target_arch = "x86_64")]
+use super::component::{cpuid, has_xsave};
+use super::*;
+
+/// The XSAVE capabilities of a CPU
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the CPU supports `xsave` and friends (CPUID.1:ECX.XSAVE)
+    pub xsave: bool,
+
+    /// Whether the OS has enabled XSAVE in CR4 (CPUID.1:ECX.OSXSAVE)
+    ///
+    /// Until it has, `xgetbv` and every save or restore instruction fault.
+    pub osxsave: bool,
+
+    /// Whether `xsaveopt` is supported
+    pub xsaveopt: bool,
+
+    /// Whether `xsavec` and the compacted format are supported
+    pub xsavec: bool,
+
+    /// Whether `xgetbv` with ECX = 1 is supported
+    pub xgetbv1: bool,
+
+    /// Whether `xsaves` and `xrstors` are supported
+    pub xsaves: bool,
+
+    /// The components which may be enabled in XCR0 and IA32_XSS
+    pub supported: XStateMasks,
+
+    /// The size of the standard format area for the components in XCR0
+    pub enabled_size: u32,
+
+    /// The size of the standard format area for every supported component
+    pub max_size: u32,
+
+    /// The layout of every supported component
+    pub components: ComponentTable,
+}
+
+impl Capabilities {
+    /// The capabilities of a CPU with no XSAVE support
+    pub const NONE: Self = Self {
+        xsave: false,
+        osxsave: false,
+        xsaveopt: false,
+        xsavec: false,
+        xgetbv1: false,
+        xsaves: false,
+        supported: XStateMasks {
+            xcr0: XStateBv::empty(),
+            xss: XStateBv::empty(),
+        },
+        enabled_size: 0,
+        max_size: 0,
+        components: ComponentTable::empty(),
+    };
+
+    /// Detect the capabilities of the current CPU
+    ///
+    /// If the CPU does not support XSAVE, this is `Capabilities::NONE`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        if !has_xsave() {
+            return Self::NONE;
+        }
+
+        let sizes = cpuid(0xD, 0);
+        let features = cpuid(0xD, 1).eax;
+
+        Self {
+            xsave: true,
+            osxsave: cpuid(1, 0).ecx & (1 << 27) != 0,
+            xsaveopt: features & (1 << 0) != 0,
+            xsavec: features & (1 << 1) != 0,
+            xgetbv1: features & (1 << 2) != 0,
+            xsaves: features & (1 << 3) != 0,
+            supported: XStateMasks::supported(),
+            enabled_size: sizes.ebx,
+            max_size: sizes.ecx,
+            components: ComponentTable::detect(),
+        }
+    }
+}
+
+impl Default for Capabilities {
+    #[inline]
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        let caps = Capabilities::detect();
+        assert_eq!(caps.components, ComponentTable::detect());
+        assert_eq!(caps.supported, XStateMasks::supported());
+
+        if caps.xsave {
+            assert!(caps.supported.xcr0.contains(XStateBv::X87 | XStateBv::SSE));
+            assert!(caps.enabled_size <= caps.max_size);
+            assert_eq!(
+                caps.max_size as usize,
+                size_for(caps.supported.xcr0, false, &caps.components)
+            );
+        } else {
+            assert_eq!(caps, Capabilities::NONE);
+        }
+    }
+}
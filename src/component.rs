@@ -0,0 +1,886 @@
+//! The layout of the XSAVE state components
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+
+use core::ops::Range;
+
+use super::*;
+
+/// The size of the legacy area and header, where the extended area begins
+pub(crate) const EXTENDED: usize = size_of::<XSaveLegacy>() + size_of::<XSaveHeader>();
+
+/// Execute CPUID
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // SAFETY: CPUID is always available on x86_64. The intrinsic is only
+    // safe to call on newer compilers than our MSRV.
+    #[allow(unused_unsafe)]
+    unsafe {
+        __cpuid_count(leaf, subleaf)
+    }
+}
+
+/// Whether the CPU supports XSAVE and enumerates it with CPUID leaf 0xD
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn has_xsave() -> bool {
+    cpuid(0, 0).eax >= 0xD && cpuid(1, 0).ecx & (1 << 26) != 0
+}
+
+/// An architectural state component
+#[non_exhaustive]
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Component {
+    X87 = 0,
+    Sse = 1,
+    Avx = 2,
+    BndRegs = 3,
+    BndCsr = 4,
+    Avx512Opmask = 5,
+    Avx512ZmmHi256 = 6,
+    Avx512Hi16Zmm = 7,
+    Pt = 8,
+    Pkru = 9,
+    AmxTileCfg = 17,
+    AmxTileData = 18,
+}
+
+impl Component {
+    /// Every component, in index order
+    pub const ALL: [Self; 12] = [
+        Self::X87,
+        Self::Sse,
+        Self::Avx,
+        Self::BndRegs,
+        Self::BndCsr,
+        Self::Avx512Opmask,
+        Self::Avx512ZmmHi256,
+        Self::Avx512Hi16Zmm,
+        Self::Pt,
+        Self::Pkru,
+        Self::AmxTileCfg,
+        Self::AmxTileData,
+    ];
+
+    /// The index of the component in XCR0 and `xstate_bv`
+    #[inline]
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The `xstate_bv` bit of the component
+    #[inline]
+    pub const fn bit(self) -> XStateBv {
+        XStateBv {
+            bits: 1 << self.index(),
+        }
+    }
+}
+
+impl From<Component> for XStateBv {
+    #[inline]
+    fn from(component: Component) -> Self {
+        component.bit()
+    }
+}
+
+/// The layout of a single state component, as reported by CPUID leaf 0xD
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ComponentInfo {
+    /// The size of the component in bytes
+    pub size: u32,
+
+    /// The offset of the component in the standard format
+    pub offset: u32,
+
+    /// Whether the component is supervisor state, managed by IA32_XSS
+    ///
+    /// Supervisor components have no standard format offset.
+    pub supervisor: bool,
+
+    /// Whether the component is 64-byte aligned in the compacted format
+    pub align64: bool,
+}
+
+/// The layout of every extended state component
+///
+/// Components 0 (X87) and 1 (SSE) live in the legacy area, so only the
+/// entries for components 2 through 63 are meaningful.
+///
+/// The sizes of supervisor components are implementation specific, so
+/// `ComponentTable::ARCHITECTURAL` marks them as supervisor state but
+/// reports a size of zero. Use `ComponentTable::detect()` to find them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ComponentTable([ComponentInfo; 64]);
+
+impl Default for ComponentTable {
+    #[inline]
+    fn default() -> Self {
+        Self::ARCHITECTURAL
+    }
+}
+
+impl ComponentTable {
+    /// The architectural layout of the user components known to this crate
+    pub const ARCHITECTURAL: Self = {
+        const fn info(size: u32, offset: u32, align64: bool) -> ComponentInfo {
+            ComponentInfo {
+                size,
+                offset,
+                supervisor: false,
+                align64,
+            }
+        }
+
+        let mut table = [info(0, 0, false); 64];
+        table[2] = info(256, 576, false);
+        table[3] = info(64, 960, false);
+        table[4] = info(64, 1024, false);
+        table[5] = info(64, 1088, false);
+        table[6] = info(512, 1152, false);
+        table[7] = info(1024, 1664, false);
+        table[8].supervisor = true;
+        table[9] = info(8, 2688, false);
+
+        // PASID, CET_U, CET_S, HDC, UINTR, LBR and HWP
+        let mut i = 10;
+        while i <= 16 {
+            table[i].supervisor = true;
+            i += 1;
+        }
+
+        table[17] = info(64, 2752, true);
+        table[18] = info(8192, 2816, true);
+        Self(table)
+    };
+
+    /// Create a table with no components
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(
+            [ComponentInfo {
+                size: 0,
+                offset: 0,
+                supervisor: false,
+                align64: false,
+            }; 64],
+        )
+    }
+
+    /// Read the layout of the current CPU's components from CPUID
+    ///
+    /// If the CPU does not support XSAVE, the table is empty.
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        let mut table = Self::empty();
+        if !has_xsave() {
+            return table;
+        }
+
+        for (i, info) in table.0.iter_mut().enumerate().skip(2) {
+            let leaf = cpuid(0xD, i as u32);
+            *info = ComponentInfo {
+                size: leaf.eax,
+                offset: leaf.ebx,
+                supervisor: leaf.ecx & (1 << 0) != 0,
+                align64: leaf.ecx & (1 << 1) != 0,
+            };
+        }
+
+        table
+    }
+
+    /// The components which are supervisor state
+    pub fn supervisor(&self) -> XStateBv {
+        let mut bits = 0;
+        for (i, info) in self.0.iter().enumerate() {
+            if info.supervisor {
+                bits |= 1 << i;
+            }
+        }
+
+        XStateBv { bits }
+    }
+
+    /// Get the layout of a component by its index
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&ComponentInfo> {
+        self.0.get(index)
+    }
+
+    /// Set the layout of a component by its index
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than 64.
+    #[inline]
+    pub fn set(&mut self, index: usize, info: ComponentInfo) {
+        self.0[index] = info;
+    }
+}
+
+impl XStateMasks {
+    /// Read the components supported by the current CPU from CPUID
+    ///
+    /// `xcr0` holds the user components which may be enabled in XCR0 and
+    /// `xss` the supervisor components which may be enabled in IA32_XSS.
+    /// If the CPU does not support XSAVE, both are empty.
+    #[cfg(target_arch = "x86_64")]
+    pub fn supported() -> Self {
+        let mut masks = Self::default();
+        if !has_xsave() {
+            return masks;
+        }
+
+        let user = cpuid(0xD, 0);
+        masks.xcr0.bits = u64::from(user.eax) | u64::from(user.edx) << 32;
+
+        let supervisor = cpuid(0xD, 1);
+        masks.xss.bits = u64::from(supervisor.ecx) | u64::from(supervisor.edx) << 32;
+        masks
+    }
+}
+
+impl XSave {
+    /// The byte range of an extended component within this buffer
+    ///
+    /// The range depends on whether the header selects the compacted
+    /// format. It is `None` if the component has no known location in the
+    /// current format or does not fit within the buffer. In the compacted
+    /// format, that includes any component placed after one whose size is
+    /// implementation specific, such as PT or CET.
+    #[inline]
+    pub(crate) fn component_range(&self, component: Component) -> Option<(usize, usize)> {
+        let caps = &ComponentTable::ARCHITECTURAL;
+        let index = component.index();
+        let info = *caps.0.get(index)?;
+
+        let offset = if self.header.xcomp_bv.contains(XCompBv::COMPACT) {
+            if self.header.xcomp_bv.bits & component.bit().bits == 0 {
+                return None;
+            }
+
+            let below = XStateBv {
+                bits: self.header.xcomp_bv.bits & ((1 << index) - 1),
+            };
+
+            let unsized_below = caps
+                .0
+                .iter()
+                .enumerate()
+                .take(index)
+                .skip(2)
+                .any(|(i, info)| below.bits & (1 << i) != 0 && info.size == 0);
+            if unsized_below {
+                return None;
+            }
+
+            match (size_for(below, true, caps), info.align64) {
+                (offset, true) => (offset + 63) & !63,
+                (offset, false) => offset,
+            }
+        } else if info.supervisor {
+            return None;
+        } else {
+            info.offset as usize
+        };
+
+        let end = offset + info.size as usize;
+        if info.size == 0 || end > size_of::<XSave>() {
+            return None;
+        }
+
+        Some((offset, end))
+    }
+
+    /// Reset the data of a component to its initial configuration
+    #[inline]
+    pub(crate) fn reset(&mut self, component: Component) -> Result<(), XSaveError> {
+        match component {
+            Component::X87 => {
+                let legacy = &mut self.legacy;
+                legacy.fcw = Fcw::DEFAULT;
+                legacy.fsw = Fsw::DEFAULT;
+                legacy.ftw = 0;
+                legacy.fop = 0;
+                legacy.fip = 0;
+                legacy.fdp = 0;
+                legacy.mm = [MmField::DEFAULT; 8];
+            }
+
+            Component::Sse => self.legacy.xmm = [Xmm::DEFAULT; 16],
+
+            _ => {
+                let range = self.component_range(component);
+                match range.and_then(|(start, end)| self.as_bytes_mut().get_mut(start..end)) {
+                    Some(bytes) => bytes.fill(0),
+                    None => return Err(XSaveError::UnsupportedComponents(component.bit())),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the data of a component is in its initial configuration
+    ///
+    /// A component with no room in the buffer has no data, so it is
+    /// considered initial.
+    #[inline]
+    fn is_initial(&self, component: Component) -> bool {
+        match component {
+            Component::X87 => {
+                let legacy = &self.legacy;
+                legacy.fcw.bits == Fcw::DEFAULT.bits
+                    && legacy.fsw.bits == 0
+                    && legacy.ftw == 0
+                    && legacy.fop == 0
+                    && legacy.fip == 0
+                    && legacy.fdp == 0
+                    && legacy.mm.iter().all(|field| field.mm.0 == [0; 10])
+            }
+
+            Component::Sse => self.legacy.xmm.iter().all(|xmm| xmm.0 == [0; 16]),
+
+            _ => self
+                .component_range(component)
+                .and_then(|(start, end)| self.as_bytes().get(start..end))
+                .map_or(true, |bytes| bytes.iter().all(|b| *b == 0)),
+        }
+    }
+
+    /// The present components whose data is not in its initial configuration
+    ///
+    /// A component may be marked present in `xstate_bv` while still holding
+    /// its initial data, for instance after `xsave` without the init
+    /// optimization. Only the components returned here need migrating or
+    /// logging.
+    pub fn touched_components(&self) -> XStateBv {
+        Component::ALL
+            .iter()
+            .filter(|c| self.header.xstate_bv.contains(c.bit()))
+            .filter(|c| !self.is_initial(**c))
+            .fold(XStateBv::empty(), |acc, c| acc | c.bit())
+    }
+
+    /// Mark a component as present, initializing its data
+    ///
+    /// The data is reset to the component's initial configuration, so the
+    /// buffer never claims a component whose contents are stale. This fails
+    /// with `XSaveError::UnsupportedComponents`, leaving the buffer
+    /// unchanged, if the component has no room in the buffer.
+    #[inline]
+    pub fn enable(&mut self, component: Component) -> Result<(), XSaveError> {
+        self.reset(component)?;
+        self.header.xstate_bv |= component.bit();
+        Ok(())
+    }
+
+    /// Mark a component as absent, clearing its data
+    ///
+    /// This fails with `XSaveError::UnsupportedComponents`, leaving the
+    /// buffer unchanged, if the component has no room in the buffer.
+    #[inline]
+    pub fn disable(&mut self, component: Component) -> Result<(), XSaveError> {
+        self.reset(component)?;
+        self.header.xstate_bv -= component.bit();
+        Ok(())
+    }
+}
+
+impl XSave {
+    /// Whether a byte of the extended area belongs to a known component
+    ///
+    /// In the standard format, this includes the leading part of a
+    /// component which is cut off by the end of the buffer.
+    fn is_known(&self, offset: usize) -> bool {
+        let compacted = self.header.xcomp_bv.contains(XCompBv::COMPACT);
+        Component::ALL.iter().any(|c| {
+            let info = ComponentTable::ARCHITECTURAL.0[c.index()];
+            let (start, end) = match self.component_range(*c) {
+                Some(range) => range,
+                None if compacted || info.supervisor => return false,
+                None => (info.offset as usize, (info.offset + info.size) as usize),
+            };
+
+            (start..end).contains(&offset)
+        })
+    }
+
+    /// Find the nonzero bytes of the extended area outside every component
+    ///
+    /// Such bytes may belong to components of future CPUs, or may have been
+    /// smuggled into the area; either way this crate would carry them along
+    /// without understanding them. Each contiguous run of them is reported
+    /// as one range of offsets into the buffer.
+    pub fn unknown_nonzero_ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let bytes = self.as_bytes();
+        let mut offset = EXTENDED;
+
+        core::iter::from_fn(move || {
+            let unknown =
+                |i: &usize| bytes.get(*i).map_or(false, |b| *b != 0) && !self.is_known(*i);
+
+            let start = (offset..bytes.len()).find(unknown)?;
+            let end = (start..bytes.len())
+                .find(|i| !unknown(i))
+                .unwrap_or(bytes.len());
+
+            offset = end;
+            Some(start..end)
+        })
+    }
+
+    /// Enable a group of components, or none of them
+    ///
+    /// Components already present in `xstate_bv` keep their data.
+    fn enable_group(&mut self, group: &[Component]) -> Result<(), XSaveError> {
+        let missing = group
+            .iter()
+            .filter(|c| !matches!(c, Component::X87 | Component::Sse))
+            .filter(|c| self.component_range(**c).is_none())
+            .fold(XStateBv::empty(), |acc, c| acc | c.bit());
+
+        if !missing.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(missing));
+        }
+
+        for component in group {
+            if !self.header.xstate_bv.contains(component.bit()) {
+                self.enable(*component)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Enable the components used by AVX-512, initializing their data
+    ///
+    /// This includes the SSE and AVX components, which AVX-512 requires.
+    /// Those already present, such as live XMM and YMM state, are left
+    /// untouched. Nothing is changed if any of the components has no room
+    /// in the buffer.
+    pub fn enable_avx512(&mut self) -> Result<(), XSaveError> {
+        self.enable_group(&[
+            Component::Sse,
+            Component::Avx,
+            Component::Avx512Opmask,
+            Component::Avx512ZmmHi256,
+            Component::Avx512Hi16Zmm,
+        ])
+    }
+
+    /// Enable the AMX tile components, initializing their data
+    ///
+    /// The 8 KiB of tile data does not fit in an `XSave`, so this fails
+    /// with `XSaveError::UnsupportedComponents` until a larger buffer is
+    /// used. Nothing is changed on failure.
+    pub fn enable_amx(&mut self) -> Result<(), XSaveError> {
+        self.enable_group(&[Component::AmxTileCfg, Component::AmxTileData])
+    }
+
+    /// Enable the MPX bounds components, initializing their data
+    ///
+    /// Nothing is changed if any of the components has no room in the
+    /// buffer.
+    pub fn enable_mpx(&mut self) -> Result<(), XSaveError> {
+        self.enable_group(&[Component::BndRegs, Component::BndCsr])
+    }
+
+    /// Complete a partial set of AVX-512 components in `xstate_bv`
+    ///
+    /// Any of the three components which is missing is enabled, and so is
+    /// initialized, so that the buffer can be restored on any CPU with
+    /// AVX-512 enabled.
+    pub fn fix_avx512(&mut self) -> Result<(), XSaveError> {
+        if !self.header.xstate_bv.intersects(XStateBv::AVX512) {
+            return Ok(());
+        }
+
+        for component in [
+            Component::Avx512Opmask,
+            Component::Avx512ZmmHi256,
+            Component::Avx512Hi16Zmm,
+        ] {
+            if !self.header.xstate_bv.contains(component.bit()) {
+                self.enable(component)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the size of the `xsaves` area for the enabled components
+///
+/// This is the size required for all components currently enabled in
+/// XCR0 and IA32_XSS in the compacted format. It is `None` if the CPU does
+/// not support `xsaves`.
+#[cfg(target_arch = "x86_64")]
+pub fn xsaves_size() -> Option<usize> {
+    if !has_xsave() {
+        return None;
+    }
+
+    let leaf = cpuid(0xD, 1);
+    if leaf.eax & (1 << 3) == 0 {
+        return None;
+    }
+
+    Some(leaf.ebx as usize)
+}
+
+/// Read the user state components enabled in XCR0
+///
+/// This is `None` if the OS has not enabled XSAVE, in which case `xgetbv`
+/// is unavailable.
+#[inline]
+#[cfg(xsave_hw)]
+pub(crate) fn xcr0() -> Option<XStateBv> {
+    if cpuid(1, 0).ecx & (1 << 27) == 0 {
+        return None;
+    }
+
+    let mut lo = 0u32;
+    let mut hi = 0u32;
+
+    #[cfg(not(doc))]
+    unsafe {
+        asm!(
+            "xgetbv",
+            in("ecx") 0u32,
+            inout("eax") lo,
+            inout("edx") hi,
+            options(nomem, nostack, preserves_flags),
+        )
+    }
+
+    Some(XStateBv {
+        bits: u64::from(lo) | u64::from(hi) << 32,
+    })
+}
+
+/// Read CR0, if the current privilege level allows it
+#[inline]
+#[cfg(xsave_hw)]
+pub(crate) fn cr0() -> Option<u64> {
+    let mut cs = 0u16;
+
+    #[cfg(not(doc))]
+    unsafe {
+        asm!("mov {:x}, cs", inout(reg) cs, options(nomem, nostack, preserves_flags))
+    }
+
+    // The RPL of CS is the current privilege level.
+    if cs & 3 != 0 {
+        return None;
+    }
+
+    let mut cr0 = 0u64;
+
+    #[cfg(not(doc))]
+    unsafe {
+        asm!("mov {}, cr0", inout(reg) cr0, options(nomem, nostack, preserves_flags))
+    }
+
+    Some(cr0)
+}
+
+/// Check that the XSAVE instructions can execute without faulting
+///
+/// Without CPUID.1:ECX.XSAVE, or with CR4.OSXSAVE clear, every XSAVE
+/// instruction raises an invalid-opcode fault. At CPL 0, where CR0 is
+/// readable, CR0.EM must also be clear, and CR0.TS, which would raise a
+/// device-not-available fault.
+#[cfg(xsave_hw)]
+pub(crate) fn preflight() -> Result<(), XSaveError> {
+    const EM: u64 = 1 << 2;
+    const TS: u64 = 1 << 3;
+
+    if !has_xsave() {
+        return Err(XSaveError::UnsupportedCpu);
+    }
+
+    if cpuid(1, 0).ecx & (1 << 27) == 0 {
+        return Err(XSaveError::NotEnabled);
+    }
+
+    if let Some(cr0) = cr0() {
+        if cr0 & (EM | TS) != 0 {
+            return Err(XSaveError::NotEnabled);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(xsave_soft)]
+pub(crate) use super::soft::{preflight, xcr0};
+
+/// Calculate the size of an XSAVE area holding the specified components
+///
+/// This reproduces the computation behind the sizes reported by CPUID leaf
+/// 0xD, so that areas can be sized for masks other than the current XCR0.
+/// In the standard format, each component lives at its fixed offset. In
+/// the compacted format, the components are packed in order, aligning
+/// those which require it to 64 bytes.
+#[inline]
+pub fn size_for(mask: XStateBv, compacted: bool, caps: &ComponentTable) -> usize {
+    let mut size = EXTENDED;
+
+    for (i, info) in caps.0.iter().enumerate().skip(2) {
+        if mask.bits & (1 << i) == 0 {
+            continue;
+        }
+
+        let len = info.size as usize;
+        if compacted {
+            if info.align64 {
+                size = (size + 63) & !63;
+            }
+            size += len;
+        } else if !info.supervisor {
+            size = size.max(info.offset as usize + len);
+        }
+    }
+
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVX: XStateBv = XStateBv::from_bits_truncate(0b111);
+    const AVX512: XStateBv = XStateBv::from_bits_truncate(0b1110_0111);
+
+    #[test]
+    fn standard() {
+        let caps = ComponentTable::ARCHITECTURAL;
+        assert_eq!(size_for(XStateBv::X87 | XStateBv::SSE, false, &caps), 576);
+        assert_eq!(size_for(AVX, false, &caps), 832);
+        assert_eq!(size_for(AVX512, false, &caps), 2688);
+        assert_eq!(size_for(XStateBv::all(), false, &caps), 11008);
+    }
+
+    #[test]
+    fn compacted() {
+        let caps = ComponentTable::ARCHITECTURAL;
+        let pkru = AVX | XStateBv::PKRU;
+        assert_eq!(size_for(pkru, true, &caps), 840);
+
+        let amx = pkru | XStateBv::AMX_TILECFG | XStateBv::AMX_TILEDATA;
+        assert_eq!(size_for(amx, true, &caps), 960 + 8192);
+    }
+
+    #[test]
+    fn component() {
+        for component in Component::ALL {
+            assert_eq!(XStateBv::from(component).bits, 1 << component.index());
+        }
+
+        let mut xsave = XSave::DEFAULT;
+        xsave.legacy.xmm[3] = Xmm([0xAA; 16]);
+        xsave.as_bytes_mut()[576..832].fill(0xAA);
+        xsave.as_bytes_mut()[2688] = 0xAA;
+
+        xsave.enable(Component::Sse).unwrap();
+        xsave.enable(Component::Avx).unwrap();
+        assert_eq!(xsave.header.xstate_bv, XStateBv::SSE | XStateBv::AVX);
+        assert_eq!(xsave.legacy.xmm[3].0, [0; 16]);
+        assert!(xsave.as_bytes()[576..832].iter().all(|b| *b == 0));
+        assert_eq!(xsave.as_bytes()[2688], 0xAA);
+
+        xsave.disable(Component::Avx).unwrap();
+        xsave.disable(Component::Pkru).unwrap();
+        assert_eq!(xsave.header.xstate_bv, XStateBv::SSE);
+        assert_eq!(xsave.as_bytes()[2688], 0);
+
+        for component in [Component::Pt, Component::AmxTileData] {
+            assert_eq!(
+                xsave.enable(component),
+                Err(XSaveError::UnsupportedComponents(component.bit()))
+            );
+        }
+        assert_eq!(xsave.header.xstate_bv, XStateBv::SSE);
+    }
+
+    #[test]
+    fn touched() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable_avx512().unwrap();
+        assert_eq!(xsave.touched_components(), XStateBv::empty());
+
+        xsave.legacy.fcw = Fcw::empty();
+        xsave.legacy.xmm[15].0[15] = 1;
+        xsave.as_bytes_mut()[1152 + 511] = 1;
+        assert_eq!(
+            xsave.touched_components(),
+            XStateBv::X87 | XStateBv::SSE | XStateBv::AVX512_ZMM_HI256
+        );
+
+        // Absent components are initial, whatever the buffer holds
+        xsave.header.xstate_bv -= XStateBv::SSE;
+        assert_eq!(
+            xsave.touched_components(),
+            XStateBv::X87 | XStateBv::AVX512_ZMM_HI256
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let mut xsave = XSave::fxsave_default();
+        assert_eq!(xsave.unknown_nonzero_ranges().next(), None);
+
+        // Within AVX, which is known whether present or not, and the
+        // start of the tile data, which is cut off
+        xsave.as_bytes_mut()[600] = 1;
+        xsave.as_bytes_mut()[3071] = 1;
+        assert_eq!(xsave.unknown_nonzero_ranges().next(), None);
+
+        // After PKRU
+        xsave.as_bytes_mut()[2696] = 1;
+        xsave.as_bytes_mut()[2698..2701].fill(1);
+
+        let mut ranges = xsave.unknown_nonzero_ranges();
+        assert_eq!(ranges.next(), Some(2696..2697));
+        assert_eq!(ranges.next(), Some(2698..2701));
+        assert_eq!(ranges.next(), None);
+        drop(ranges);
+
+        // Compacted with only the legacy components, nothing else is known
+        xsave.header.xcomp_bv = XCompBv::COMPACT;
+        xsave.as_bytes_mut()[2696..2701].fill(0);
+
+        let mut ranges = xsave.unknown_nonzero_ranges();
+        assert_eq!(ranges.next(), Some(600..601));
+        assert_eq!(ranges.next(), Some(3071..3072));
+        assert_eq!(ranges.next(), None);
+    }
+
+    #[test]
+    fn groups() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.enable_avx512().unwrap();
+        xsave.enable_mpx().unwrap();
+        assert_eq!(
+            xsave.header.xstate_bv,
+            XStateBv::SSE | XStateBv::AVX | XStateBv::AVX512 | XStateBv::BNDREGS | XStateBv::BNDCSR
+        );
+        assert_eq!(
+            xsave.enable_amx(),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AMX_TILEDATA))
+        );
+        assert!(!xsave.header.xstate_bv.contains(XStateBv::AMX_TILECFG));
+    }
+
+    #[test]
+    fn enable_avx512_live() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[0] = Xmm([0xAA; 16]);
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576] = 0xBB;
+        xsave.as_bytes_mut()[1152] = 0xCC;
+
+        xsave.enable_avx512().unwrap();
+        assert!(xsave.header.xstate_bv.contains(XStateBv::AVX512));
+        assert_eq!(xsave.legacy.xmm[0].0, [0xAA; 16]);
+        assert_eq!(xsave.as_bytes()[576], 0xBB);
+        assert_eq!(xsave.as_bytes()[1152], 0);
+    }
+
+    #[test]
+    fn fix_avx512() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.fix_avx512().unwrap();
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+
+        xsave.as_bytes_mut()[1152] = 0xAA;
+        xsave.enable(Component::Avx512Opmask).unwrap();
+        xsave.fix_avx512().unwrap();
+        assert!(xsave.header.xstate_bv.contains(XStateBv::AVX512));
+        assert_eq!(xsave.header.xstate_bv.check_avx512(), Ok(()));
+        assert_eq!(xsave.as_bytes()[1152], 0);
+    }
+
+    #[test]
+    fn component_compacted() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xcomp_bv = XCompBv::COMPACT
+            | XCompBv {
+                bits: 0b11 | 1 << 9,
+            };
+        xsave.as_bytes_mut()[576] = 0xAA;
+
+        assert_eq!(xsave.component_range(Component::Pkru), Some((576, 584)));
+        assert_eq!(
+            xsave.enable(Component::Avx),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX))
+        );
+
+        xsave.enable(Component::Pkru).unwrap();
+        assert_eq!(xsave.as_bytes()[576], 0);
+    }
+
+    #[test]
+    fn component_compacted_unsized() {
+        let mut xsave = XSave::DEFAULT;
+        let cet_u = 1 << 11;
+        xsave.header.xcomp_bv = XCompBv::COMPACT
+            | XCompBv {
+                bits: 0b11 | cet_u | XStateBv::AMX_TILECFG.bits,
+            };
+
+        assert_eq!(xsave.component_range(Component::AmxTileCfg), None);
+        assert_eq!(
+            xsave.enable(Component::AmxTileCfg),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AMX_TILECFG))
+        );
+
+        xsave.header.xcomp_bv = XCompBv::COMPACT
+            | XCompBv {
+                bits: 0b11 | XStateBv::PT.bits | XStateBv::PKRU.bits,
+            };
+        assert_eq!(xsave.component_range(Component::Pkru), None);
+
+        xsave.header.xcomp_bv.bits &= !XStateBv::PT.bits;
+        assert_eq!(xsave.component_range(Component::Pkru), Some((576, 584)));
+    }
+
+    #[test]
+    fn supervisor() {
+        let caps = ComponentTable::ARCHITECTURAL;
+        assert_eq!(caps.supervisor().bits, 0x1_FD00);
+        assert_eq!(size_for(AVX | XStateBv::PT, false, &caps), 832);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn supported() {
+        let caps = ComponentTable::detect();
+        let supported = XStateMasks::supported();
+        assert!(caps.supervisor().contains(supported.xss));
+        assert!(!caps.supervisor().intersects(supported.xcr0));
+
+        if let Some(size) = xsaves_size() {
+            assert!(size >= size_for(XStateBv::X87 | XStateBv::SSE, true, &caps));
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn detect() {
+        let caps = ComponentTable::detect();
+        if !has_xsave() {
+            assert_eq!(caps, ComponentTable::empty());
+            return;
+        }
+
+        let supported = XStateMasks::supported().xcr0;
+        let size = cpuid(0xD, 0).ecx as usize;
+        assert_eq!(size_for(supported, false, &caps), size);
+    }
+}
@@ -0,0 +1,327 @@
+//! A checksummed container for saved state snapshots
+//!
+//! Snapshots written to disk may be truncated or corrupted long before
+//! anyone tries to restore them, and restoring garbage faults at best. A
+//! container frames an `XSave` with a header holding a magic number, a
+//! format version, the component masks of the CPU which made it and a
+//! CRC-32 of the payload, all little-endian:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | `MAGIC`                                 |
+//! | 4      | 2    | `VERSION`                               |
+//! | 6      | 2    | Reserved, zero                          |
+//! | 8      | 8    | Supported XCR0 components of the source |
+//! | 16     | 8    | Supported IA32_XSS components           |
+//! | 24     | 4    | Payload length                          |
+//! | 28     | 4    | CRC-32 (IEEE) of the payload            |
+//! | 32     |      | Payload: the `XSave`, little-endian     |
+//!
+//! With the `alloc` feature, the payload can be sealed by a `Sealer`, for
+//! instance with SGX sealing or an SEV-SNP derived key, without this crate
+//! depending on any particular cryptography. Sealed containers begin with
+//! `SEALED_MAGIC` instead, and their checksum covers the sealed payload.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::le::{U16, U32, U64};
+use super::*;
+
+/// The magic number which begins every unsealed container
+pub const MAGIC: [u8; 4] = *b"XSVC";
+
+/// The magic number which begins every sealed container
+pub const SEALED_MAGIC: [u8; 4] = *b"XSVE";
+
+/// The version of the container format
+pub const VERSION: u16 = 1;
+
+/// The size of the container header
+pub const HEADER_SIZE: usize = size_of::<Header>();
+
+/// The size of a container holding an `XSave`
+pub const SIZE: usize = HEADER_SIZE + size_of::<XSave>();
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Header {
+    magic: [u8; 4],
+    version: U16,
+    reserved: U16,
+    xcr0: U64,
+    xss: U64,
+    len: U32,
+    crc: U32,
+}
+
+impl Header {
+    /// Create the header for a payload
+    fn new(magic: [u8; 4], source: XStateMasks, payload: &[u8]) -> Self {
+        Self {
+            magic,
+            version: VERSION.into(),
+            reserved: 0.into(),
+            xcr0: source.xcr0.bits.into(),
+            xss: source.xss.bits.into(),
+            len: (payload.len() as u32).into(),
+            crc: crc32(payload).into(),
+        }
+    }
+
+    /// The source masks recorded in the header
+    fn source(&self) -> XStateMasks {
+        XStateMasks {
+            xcr0: XStateBv {
+                bits: self.xcr0.get(),
+            },
+            xss: XStateBv {
+                bits: self.xss.get(),
+            },
+        }
+    }
+
+    /// Parse and verify a container, returning its header and payload
+    fn read(bytes: &[u8], magic: [u8; 4]) -> Result<(Self, &[u8]), XSaveError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(XSaveError::InvalidLength(bytes.len()));
+        }
+
+        // SAFETY: the header has an alignment of one, no padding, and every
+        // bit pattern is valid; the length was just checked.
+        let header = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) };
+        if header.magic != magic {
+            return Err(XSaveError::InvalidMagic);
+        }
+
+        if header.version.get() != VERSION {
+            return Err(XSaveError::UnsupportedVersion(header.version.get().into()));
+        }
+
+        if header.reserved.get() != 0 {
+            return Err(XSaveError::ReservedByte(6));
+        }
+
+        let len = header.len.get() as usize;
+        let payload = match bytes.get(HEADER_SIZE..).and_then(|p| p.get(..len)) {
+            Some(payload) => payload,
+            None => return Err(XSaveError::InvalidLength(bytes.len())),
+        };
+
+        let crc = crc32(payload);
+        if crc != header.crc.get() {
+            return Err(XSaveError::ChecksumMismatch(crc));
+        }
+
+        Ok((header, payload))
+    }
+
+    /// Serialize the header to the start of a buffer
+    fn write(&self, bytes: &mut [u8]) {
+        // SAFETY: as above; the caller has checked the length.
+        unsafe { core::ptr::write_unaligned(bytes.as_mut_ptr() as *mut Self, *self) }
+    }
+}
+
+/// Compute the IEEE CRC-32 of some bytes
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = match crc & 1 {
+                    0 => crc >> 1,
+                    _ => (crc >> 1) ^ 0xEDB8_8320,
+                };
+                bit += 1;
+            }
+
+            table[i] = crc;
+            i += 1;
+        }
+
+        table
+    };
+
+    !bytes.iter().fold(!0u32, |crc, b| {
+        TABLE[usize::from(crc as u8 ^ b)] ^ (crc >> 8)
+    })
+}
+
+/// Parse a little-endian payload as by `XSave::from_bytes_strict()`
+fn decode(payload: &[u8]) -> Result<XSave, XSaveError> {
+    match payload.try_into() {
+        Ok(bytes) => XSave::from_bytes_strict(XSave::from_le_bytes(bytes).as_bytes()),
+        Err(..) => Err(XSaveError::InvalidLength(payload.len())),
+    }
+}
+
+/// Seal an `XSave` into a container, returning the container's length
+///
+/// `source` describes the CPU which produced the state; it is returned by
+/// `open()`, so that the state can be checked against the restoring CPU.
+/// This fails with `XSaveError::InvalidLength`, holding the length
+/// required, if the buffer is too short.
+pub fn seal(xsave: &XSave, source: XStateMasks, buf: &mut [u8]) -> Result<usize, XSaveError> {
+    if buf.len() < SIZE {
+        return Err(XSaveError::InvalidLength(SIZE));
+    }
+
+    let payload = xsave.le_bytes();
+    Header::new(MAGIC, source, &payload).write(buf);
+    buf[HEADER_SIZE..SIZE].copy_from_slice(&payload);
+    Ok(SIZE)
+}
+
+/// Open a container, returning the `XSave` and the masks of its source
+///
+/// The header and checksum are verified before the payload is parsed with
+/// `XSave::from_bytes_strict()`. A truncated container is reported as
+/// `XSaveError::InvalidLength` and a corrupt payload as
+/// `XSaveError::ChecksumMismatch`, holding the checksum found.
+pub fn open(bytes: &[u8]) -> Result<(XSave, XStateMasks), XSaveError> {
+    let (header, payload) = Header::read(bytes, MAGIC)?;
+    Ok((decode(payload)?, header.source()))
+}
+
+/// A means of sealing container payloads
+///
+/// Implementations typically encrypt and authenticate the payload with a
+/// key bound to the platform or enclave.
+#[cfg(feature = "alloc")]
+pub trait Sealer {
+    /// Seal a payload
+    fn seal(&self, payload: &[u8]) -> Vec<u8>;
+
+    /// Open a sealed payload, failing if it is not authentic
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, XSaveError>;
+}
+
+/// Seal an `XSave` into a container, sealing its payload with `sealer`
+///
+/// This is otherwise the same as `seal()`.
+#[cfg(feature = "alloc")]
+pub fn seal_with<S: Sealer + ?Sized>(xsave: &XSave, source: XStateMasks, sealer: &S) -> Vec<u8> {
+    let payload = sealer.seal(&xsave.le_bytes());
+
+    let mut container = Vec::with_capacity(HEADER_SIZE + payload.len());
+    container.resize(HEADER_SIZE, 0);
+    Header::new(SEALED_MAGIC, source, &payload).write(&mut container);
+    container.extend_from_slice(&payload);
+    container
+}
+
+/// Open a sealed container, opening its payload with `sealer`
+///
+/// The checksum is verified before the sealer is used. This is otherwise
+/// the same as `open()`.
+#[cfg(feature = "alloc")]
+pub fn open_with<S: Sealer + ?Sized>(
+    bytes: &[u8],
+    sealer: &S,
+) -> Result<(XSave, XStateMasks), XSaveError> {
+    let (header, sealed) = Header::read(bytes, SEALED_MAGIC)?;
+    let payload = sealer.open(sealed)?;
+    Ok((decode(&payload)?, header.source()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.fip = 0x1234;
+
+        let source = XStateMasks {
+            xcr0: XStateBv::X87 | XStateBv::SSE | XStateBv::AVX,
+            xss: XStateBv::PT,
+        };
+
+        let mut buf = [0; SIZE + 1];
+        assert_eq!(seal(&xsave, source, &mut buf), Ok(SIZE));
+        assert_eq!(buf[..4], MAGIC);
+
+        let (copy, masks) = open(&buf[..SIZE]).unwrap();
+        assert_eq!(copy.as_bytes()[..], xsave.as_bytes()[..]);
+        assert_eq!(masks, source);
+
+        assert_eq!(
+            seal(&xsave, source, &mut buf[..SIZE - 1]),
+            Err(XSaveError::InvalidLength(SIZE))
+        );
+    }
+
+    #[test]
+    fn damaged() {
+        let mut buf = [0; SIZE];
+        seal(&XSave::DEFAULT, XStateMasks::default(), &mut buf).unwrap();
+
+        assert_eq!(
+            open(&buf[..SIZE - 1]).err(),
+            Some(XSaveError::InvalidLength(SIZE - 1))
+        );
+        assert_eq!(open(&buf[..8]).err(), Some(XSaveError::InvalidLength(8)));
+
+        buf[100] ^= 1;
+        assert!(matches!(open(&buf), Err(XSaveError::ChecksumMismatch(_))));
+        buf[100] ^= 1;
+
+        buf[4] = 2;
+        assert_eq!(open(&buf).err(), Some(XSaveError::UnsupportedVersion(2)));
+        buf[4] = 1;
+
+        buf[0] = 0;
+        assert_eq!(open(&buf).err(), Some(XSaveError::InvalidMagic));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn sealed() {
+        struct Xor(u8);
+
+        impl Sealer for Xor {
+            fn seal(&self, payload: &[u8]) -> Vec<u8> {
+                let mut sealed: Vec<u8> = payload.iter().map(|b| b ^ self.0).collect();
+                sealed.push(self.0);
+                sealed
+            }
+
+            fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, XSaveError> {
+                match sealed.split_last() {
+                    Some((key, payload)) if *key == self.0 => {
+                        Ok(payload.iter().map(|b| b ^ self.0).collect())
+                    }
+                    _ => Err(XSaveError::InvalidMagic),
+                }
+            }
+        }
+
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.fdp = 0x5678;
+
+        let container = seal_with(&xsave, XStateMasks::default(), &Xor(0x5A));
+        assert_eq!(container.len(), SIZE + 1);
+        assert_eq!(container[..4], SEALED_MAGIC);
+        assert_eq!(container[HEADER_SIZE], 0x7F ^ 0x5A);
+        assert_eq!(open(&container).err(), Some(XSaveError::InvalidMagic));
+
+        let (copy, _) = open_with(&container, &Xor(0x5A)).unwrap();
+        assert_eq!(copy.legacy.fdp, 0x5678);
+        assert_eq!(
+            open_with(&container, &Xor(1)).err(),
+            Some(XSaveError::InvalidMagic)
+        );
+    }
+}
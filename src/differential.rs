@@ -0,0 +1,219 @@
+//! Differential testing of the software model against the hardware
+//!
+//! The software backend used under Miri and with the `soft` feature is
+//! only useful while it behaves like the real instructions. With the
+//! `differential` feature, both are built side by side, and `run()`
+//! restores the same buffer with each before saving it again, reporting
+//! every byte where the results differ. CPU vendor quirks show up here as
+//! mismatches on the machines which have them, except for the x87 pointers
+//! which AMD CPUs only save on exceptions; see `XSave::normalize()`.
+//!
+//! The comparison covers the components which the model supports and the
+//! OS has enabled. The MXCSR_MASK field, which the model fixes, and the
+//! software-available bytes of the legacy area, which `xsave` never
+//! writes, are excluded.
+
+use core::arch::asm;
+use core::ops::Range;
+
+use super::*;
+
+/// The byte ranges of the legacy area which are compared
+const LEGACY: [Range<usize>; 2] = [0..28, 32..416];
+
+/// The byte range holding `xstate_bv`
+const XSTATE_BV: Range<usize> = 512..520;
+
+/// The offset of `xcomp_bv`
+const XCOMP_BV: usize = 520;
+
+/// A byte which differs between the hardware and the software model
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The offset of the byte within the `XSave`
+    pub offset: usize,
+
+    /// The byte saved by the hardware
+    pub hardware: u8,
+
+    /// The byte saved by the software model
+    pub software: u8,
+}
+
+/// The results of restoring and saving a buffer with both backends
+///
+/// Components absent from each saved `xstate_bv` are reset to their
+/// initial values, since neither backend is required to write them.
+#[derive(Copy, Clone, Debug)]
+pub struct Report {
+    /// The components which were compared
+    pub mask: XStateBv,
+
+    /// The state saved by the hardware
+    pub hardware: XSave,
+
+    /// The state saved by the software model
+    pub software: XSave,
+}
+
+impl Report {
+    /// The byte ranges which are compared
+    fn ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let extended = Component::ALL
+            .iter()
+            .filter(move |c| !matches!(c, Component::X87 | Component::Sse))
+            .filter(move |c| self.mask.contains(c.bit()))
+            .filter_map(move |c| self.software.component_range(*c))
+            .map(|(start, end)| start..end);
+
+        LEGACY.into_iter().chain([XSTATE_BV]).chain(extended)
+    }
+
+    /// The bytes which differ between the two saved states
+    pub fn mismatches(&self) -> impl Iterator<Item = Mismatch> + '_ {
+        let hardware = self.hardware.as_bytes();
+        let software = self.software.as_bytes();
+
+        self.ranges()
+            .flatten()
+            .filter(move |i| hardware[*i] != software[*i])
+            .map(move |offset| Mismatch {
+                offset,
+                hardware: hardware[offset],
+                software: software[offset],
+            })
+    }
+
+    /// Whether the two saved states agree
+    #[inline]
+    pub fn matches(&self) -> bool {
+        self.mismatches().next().is_none()
+    }
+}
+
+/// Restore `input` with `xrstor` and save it again with `xsave`
+///
+/// The current state of the components in `mask` is saved beforehand and
+/// restored afterwards, all within one block so that the compiler cannot
+/// observe the intermediate state.
+fn hardware(input: &XSave, mask: XStateBv) -> XSave {
+    let mut backup = XSave::DEFAULT;
+    let mut output = XSave::DEFAULT;
+
+    unsafe {
+        asm!(
+            "xsave   [{backup}]",
+            "xrstor  [{input}]",
+            "xsave   [{output}]",
+            "xrstor  [{backup}]",
+            backup = in(reg) &mut backup,
+            input = in(reg) input,
+            output = in(reg) &mut output,
+            in("eax") mask.bits as u32,
+            in("edx") (mask.bits >> 32) as u32,
+            options(nostack),
+        )
+    }
+
+    output
+}
+
+/// Reset the components which a save left out of `xstate_bv`
+fn normalize(mut xsave: XSave) -> XSave {
+    for component in Component::ALL {
+        if !xsave.header.xstate_bv.contains(component.bit()) {
+            let _ = xsave.reset(component);
+        }
+    }
+
+    xsave
+}
+
+/// Restore and save `input` with both the hardware and the software model
+///
+/// This replaces the calling thread's software model state. The input
+/// must be in the standard format with reserved header bits clear, hold
+/// only components supported by both backends and have an MXCSR valid for
+/// both. Otherwise, it is rejected instead of faulting.
+pub fn run(input: &XSave) -> Result<Report, XSaveError> {
+    let xcr0 = component::xcr0().ok_or(XSaveError::UnsupportedComponents(SoftBackend::XCR0))?;
+    let mask = SoftBackend::XCR0 & xcr0;
+
+    let header = &input.header;
+    if !header.xcomp_bv.is_empty() || header.reserved != [0; 6] {
+        return Err(XSaveError::ReservedByte(XCOMP_BV));
+    }
+
+    let unsupported = header.xstate_bv - mask;
+    if !unsupported.is_empty() {
+        return Err(XSaveError::UnsupportedComponents(unsupported));
+    }
+
+    let supported = SoftBackend::MXCSR_MASK & MxCsr::detect_mask();
+    if !supported.contains(input.legacy.mxcsr) {
+        return Err(XSaveError::MxCsrReserved(input.legacy.mxcsr.bits));
+    }
+
+    let mut hardware = normalize(hardware(input, mask));
+
+    let mut software = XSave::DEFAULT;
+    soft::load(input);
+    soft::save(&mut software);
+    let mut software = normalize(software);
+
+    if Vendor::detect().pointers_on_exception_only() {
+        hardware.normalize();
+        software.normalize();
+    }
+
+    Ok(Report {
+        mask,
+        hardware,
+        software,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agree() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.fcw = Fcw::from_bits_truncate(0x027F);
+        xsave.legacy.mxcsr = MxCsr::from_bits_truncate(0x1FA0);
+        xsave.legacy.xmm[3] = Xmm([0x5A; 16]);
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576 + 48] = 0xA5;
+
+        let report = run(&xsave).unwrap();
+        assert!(report.mask.contains(XStateBv::AVX));
+        assert_eq!(report.mismatches().next(), None);
+        assert!(report.matches());
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut report = run(&XSave::fxsave_default()).unwrap();
+        report.hardware.legacy.xmm[0].0[1] = 1;
+
+        let mismatch = report.mismatches().next().unwrap();
+        assert_eq!(mismatch.offset, 161);
+        assert_eq!((mismatch.hardware, mismatch.software), (1, 0));
+        assert!(!report.matches());
+    }
+
+    #[test]
+    fn reject() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.header.xstate_bv = XStateBv::AMX_TILECFG;
+        assert_eq!(
+            run(&xsave).err(),
+            Some(XSaveError::UnsupportedComponents(XStateBv::AMX_TILECFG))
+        );
+
+        let mut xsave = XSave::fxsave_default();
+        xsave.header.xcomp_bv = XCompBv::COMPACT;
+        assert_eq!(run(&xsave).err(), Some(XSaveError::ReservedByte(520)));
+    }
+}
@@ -0,0 +1,196 @@
+//! Symbolic formatting of the control, status and component registers
+
+use core::fmt::{self, Display, Formatter};
+
+use super::*;
+
+/// A set of exceptions, from the six low bits of a flag or mask field
+struct Exceptions(u32);
+
+impl Display for Exceptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 & 0x3F {
+            0 => f.write_str("none"),
+            0x3F => f.write_str("all"),
+            bits => {
+                let mut sep = "";
+                for exception in FpException::ALL {
+                    if bits & exception.fsw().bits as u32 != 0 {
+                        write!(f, "{}{}", sep, exception.symbol())?;
+                        sep = "|";
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for RoundingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Nearest => "RN",
+            Self::Down => "RD",
+            Self::Up => "RU",
+            Self::TowardZero => "RZ",
+        })
+    }
+}
+
+impl Display for Fcw {
+    /// Formats as, for example, `PC=64, RN, masks=all`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let precision = match (self.bits >> 8) & 0b11 {
+            0b00 => "24",
+            0b10 => "53",
+            0b11 => "64",
+            _ => "reserved",
+        };
+
+        write!(f, "PC={}, {}", precision, self.rounding_mode())?;
+
+        if self.contains(Self::INFINITY_CONTROL) {
+            f.write_str(", IC")?;
+        }
+
+        write!(f, ", masks={}", Exceptions(self.bits.into()))
+    }
+}
+
+impl Display for Fsw {
+    /// Formats as, for example, `TOP=7, flags=Z|P, ES, C1`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TOP={}", (self.bits >> 11) & 0b111)?;
+
+        let flags = u32::from(self.bits) & 0x3F;
+        if flags != 0 {
+            write!(f, ", flags={}", Exceptions(flags))?;
+        }
+
+        let names = [
+            (Self::STACK_FAULT, "SF"),
+            (Self::EXCEPTION_SUMMARY, "ES"),
+            (Self::CONDITION0, "C0"),
+            (Self::CONDITION1, "C1"),
+            (Self::CONDITION2, "C2"),
+            (Self::CONDITION3, "C3"),
+            (Self::FPU_BUSY, "B"),
+        ];
+
+        for (flag, name) in names {
+            if self.contains(flag) {
+                write!(f, ", {}", name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for MxCsr {
+    /// Formats as, for example, `RN, FTZ, masks=all`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.rounding_mode())?;
+
+        if self.contains(Self::DENORMALS_ARE_ZEROS) {
+            f.write_str(", DAZ")?;
+        }
+
+        if self.contains(Self::FLUSH_TO_ZERO) {
+            f.write_str(", FTZ")?;
+        }
+
+        write!(f, ", masks={}", Exceptions(self.bits >> 7))?;
+
+        if self.bits & 0x3F != 0 {
+            write!(f, ", flags={}", Exceptions(self.bits))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for XStateBv {
+    /// Formats as, for example, `X87|SSE|AVX`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::X87, "X87"),
+            (Self::SSE, "SSE"),
+            (Self::AVX, "AVX"),
+            (Self::BNDREGS, "BNDREGS"),
+            (Self::BNDCSR, "BNDCSR"),
+            (Self::AVX512_OPMASK, "OPMASK"),
+            (Self::AVX512_ZMM_HI256, "ZMM_Hi256"),
+            (Self::AVX512_HI16_ZMM, "Hi16_ZMM"),
+            (Self::PT, "PT"),
+            (Self::PKRU, "PKRU"),
+            (Self::AMX_TILECFG, "TILECFG"),
+            (Self::AMX_TILEDATA, "TILEDATA"),
+        ];
+
+        if self.bits == 0 {
+            return f.write_str("none");
+        }
+
+        let mut sep = "";
+        for (component, name) in names {
+            if self.contains(component) {
+                write!(f, "{}{}", sep, name)?;
+                sep = "|";
+            }
+        }
+
+        let unknown = self.bits & !Self::all().bits;
+        if unknown != 0 {
+            write!(f, "{}{:#x}", sep, unknown)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn control() {
+        assert_eq!(Fcw::DEFAULT.to_string(), "PC=64, RN, masks=all");
+        assert_eq!(
+            Fcw::from_bits_truncate(0x1E7A).to_string(),
+            "PC=53, RZ, IC, masks=D|O|U|P"
+        );
+        assert_eq!(MxCsr::DEFAULT.to_string(), "RN, masks=all");
+        assert_eq!(
+            MxCsr::from_bits_truncate(0x9FC0).to_string(),
+            "RN, DAZ, FTZ, masks=all"
+        );
+        assert_eq!(
+            MxCsr::from_bits_truncate(0x5D85).to_string(),
+            "RU, masks=I|D|O|U|P, flags=I|Z"
+        );
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(Fsw::empty().to_string(), "TOP=0");
+        assert_eq!(Fsw { bits: 0xB8A4 }.to_string(), "TOP=7, flags=Z|P, ES, B");
+    }
+
+    #[test]
+    fn components() {
+        assert_eq!(XStateBv::empty().to_string(), "none");
+        assert_eq!(
+            (XStateBv::X87 | XStateBv::SSE | XStateBv::AVX).to_string(),
+            "X87|SSE|AVX"
+        );
+        assert_eq!(
+            XStateBv { bits: 1 | 1 << 40 }.to_string(),
+            "X87|0x10000000000"
+        );
+    }
+}
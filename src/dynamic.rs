@@ -0,0 +1,419 @@
+//! Heap allocated XSAVE areas sized at runtime
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use super::component::EXTENDED;
+use super::*;
+
+/// A 64-byte aligned block of an area
+#[repr(C, align(64))]
+#[derive(Copy, Clone, Debug)]
+struct Block([u8; 64]);
+
+/// An XSAVE area in the standard format, sized when it is created
+///
+/// An `XSave` has room for the components which existed when this crate
+/// was written, so it cannot hold AMX tile data or components defined
+/// since. This area is instead sized by CPUID leaf 0xD for every component
+/// the CPU supports, and offers the same save, restore, validation and
+/// conversion operations. Components are located with the table read from
+/// CPUID when the area is created, so that components unknown to this
+/// crate are saved and restored too.
+#[derive(Clone, Debug)]
+pub struct XSaveDyn {
+    blocks: Box<[Block]>,
+    len: usize,
+    table: ComponentTable,
+}
+
+impl XSaveDyn {
+    /// Allocate an area large enough for every component the CPU supports
+    ///
+    /// Without CPUID leaf 0xD, the area is the size of an `XSave` and uses
+    /// `ComponentTable::ARCHITECTURAL`.
+    pub fn new() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        let (size, table) = match Capabilities::detect() {
+            caps if caps.xsave => (caps.max_size as usize, caps.components),
+            _ => (0, ComponentTable::ARCHITECTURAL),
+        };
+
+        #[cfg(not(target_arch = "x86_64"))]
+        let (size, table) = (0, ComponentTable::ARCHITECTURAL);
+
+        Self::zeroed(size.max(size_of::<XSave>()), table)
+    }
+
+    /// Allocate an area of the given size
+    ///
+    /// The size must leave room for the legacy area and the header. Saves
+    /// and restores request only the components which fit, located with
+    /// `ComponentTable::ARCHITECTURAL`.
+    pub fn with_size(size: usize) -> Result<Self, XSaveError> {
+        match size < EXTENDED {
+            true => Err(XSaveError::InvalidLength(size)),
+            false => Ok(Self::zeroed(size, ComponentTable::ARCHITECTURAL)),
+        }
+    }
+
+    /// Allocate a zeroed area and initialize its legacy area and header
+    fn zeroed(len: usize, table: ComponentTable) -> Self {
+        let mut area = Self {
+            blocks: vec![Block([0; 64]); (len + 63) / 64].into_boxed_slice(),
+            len,
+            table,
+        };
+
+        *area.legacy_mut() = XSaveLegacy::default();
+        *area.header_mut() = XSaveHeader::DEFAULT;
+        area
+    }
+
+    /// Enlarge the area to at least `size` bytes, keeping its contents
+    ///
+    /// The added bytes are zero. This is how an area allocated without
+    /// room for a large component, such as AMX tile data, is extended on
+    /// its first use; see `msr::handle_xfd_fault()`.
+    pub fn grow(&mut self, size: usize) {
+        if size <= self.len {
+            return;
+        }
+
+        let mut blocks = vec![Block([0; 64]); (size + 63) / 64].into_boxed_slice();
+        blocks[..self.blocks.len()].copy_from_slice(&self.blocks);
+        self.blocks = blocks;
+        self.len = size;
+    }
+
+    /// The table locating the components of the area
+    #[inline]
+    pub(crate) fn table(&self) -> &ComponentTable {
+        &self.table
+    }
+
+    /// The size of the area in bytes
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// The bytes of the area
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: the blocks are contiguous and have no padding.
+        let bytes =
+            unsafe { core::slice::from_raw_parts(self.blocks.as_ptr().cast::<u8>(), self.len) };
+        bytes
+    }
+
+    /// The bytes of the area, mutably
+    #[inline]
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in `as_bytes()`.
+        let ptr = self.blocks.as_mut_ptr().cast::<u8>();
+        unsafe { core::slice::from_raw_parts_mut(ptr, self.len) }
+    }
+
+    /// The legacy area
+    #[inline]
+    pub fn legacy(&self) -> &XSaveLegacy {
+        // SAFETY: the area is aligned, holds at least the legacy area and
+        // header, and every bit pattern is valid for both.
+        unsafe { &*self.blocks.as_ptr().cast::<XSaveLegacy>() }
+    }
+
+    /// The mutable legacy area
+    #[inline]
+    pub fn legacy_mut(&mut self) -> &mut XSaveLegacy {
+        // SAFETY: as in `legacy()`.
+        unsafe { &mut *self.blocks.as_mut_ptr().cast::<XSaveLegacy>() }
+    }
+
+    /// The header
+    #[inline]
+    pub fn header(&self) -> &XSaveHeader {
+        // SAFETY: as in `legacy()`; the header is the ninth block.
+        unsafe { &*self.blocks.as_ptr().add(8).cast::<XSaveHeader>() }
+    }
+
+    /// The mutable header
+    #[inline]
+    pub fn header_mut(&mut self) -> &mut XSaveHeader {
+        // SAFETY: as in `header()`.
+        unsafe { &mut *self.blocks.as_mut_ptr().add(8).cast::<XSaveHeader>() }
+    }
+
+    /// The extended area, which runs to the end of the area
+    #[inline]
+    pub fn extended(&self) -> &[u8] {
+        &self.as_bytes()[EXTENDED..]
+    }
+
+    /// The mutable extended area
+    #[inline]
+    pub fn extended_mut(&mut self) -> &mut [u8] {
+        &mut self.as_bytes_mut()[EXTENDED..]
+    }
+
+    /// The user components whose standard format data fits within the area
+    pub fn rfbm(&self) -> XStateBv {
+        let caps = &self.table;
+        let mut rfbm = XStateBv::X87 | XStateBv::SSE;
+
+        for i in 2..64 {
+            if let Some(info) = caps.get(i) {
+                let end = info.offset as usize + info.size as usize;
+                if !info.supervisor && info.size != 0 && end <= self.len {
+                    rfbm.bits |= 1 << i;
+                }
+            }
+        }
+
+        rfbm
+    }
+
+    /// Validate the area as `xrstor` would before loading it
+    ///
+    /// The legacy area and header are checked as by
+    /// `XSave::from_bytes_strict()`, and every component present must fit
+    /// within the area. Components which do not fit are reported as
+    /// `XSaveError::UnsupportedComponents`.
+    pub fn validate(&self) -> Result<(), XSaveError> {
+        self.prefix()?;
+
+        let header = self.header();
+        let compacted = header.xcomp_bv.contains(XCompBv::COMPACT);
+        let missing = header.xstate_bv - self.rfbm();
+        if !compacted && !missing.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(missing));
+        }
+
+        let size = size_for(header.xstate_bv, compacted, &self.table);
+        match size > self.len {
+            true => Err(XSaveError::UnsupportedComponents(header.xstate_bv)),
+            false => Ok(()),
+        }
+    }
+
+    /// Parse the bytes which overlap an `XSave`, padded with zeros
+    fn prefix(&self) -> Result<XSave, XSaveError> {
+        let mut bytes = [0; size_of::<XSave>()];
+        let len = self.len.min(bytes.len());
+        bytes[..len].copy_from_slice(&self.as_bytes()[..len]);
+        XSave::from_bytes_strict(&bytes)
+    }
+
+    /// Convert the area into an `XSave`
+    ///
+    /// This fails with `XSaveError::UnsupportedComponents` if a component
+    /// present in the area does not fit in an `XSave`.
+    pub fn to_xsave(&self) -> Result<XSave, XSaveError> {
+        self.validate()?;
+
+        let xsave = self.prefix()?;
+        let header = &xsave.header;
+        let compacted = header.xcomp_bv.contains(XCompBv::COMPACT);
+        let size = size_for(header.xstate_bv, compacted, &ComponentTable::ARCHITECTURAL);
+        if size > size_of::<XSave>() {
+            let missing = Component::ALL
+                .iter()
+                .filter(|c| header.xstate_bv.contains(c.bit()))
+                .filter(|c| !matches!(c, Component::X87 | Component::Sse))
+                .filter(|c| xsave.component_range(**c).is_none())
+                .fold(XStateBv::empty(), |acc, c| acc | c.bit());
+            return Err(XSaveError::UnsupportedComponents(missing));
+        }
+
+        Ok(xsave)
+    }
+
+    /// Save the extended CPU state into the area
+    ///
+    /// Only the components which fit within the area are saved.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn save(&mut self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            soft::save(&mut xsave);
+            xsave.header.xstate_bv &= self.rfbm();
+
+            let len = self.len.min(size_of::<XSave>());
+            self.as_bytes_mut()[..len].copy_from_slice(&xsave.as_bytes()[..len]);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            let rfbm = self.rfbm().bits;
+            asm!(
+                "xsave   [{}]",
+                in(reg) self.blocks.as_mut_ptr(),
+                in("eax") rfbm as u32,
+                in("edx") (rfbm >> 32) as u32,
+                options(nostack),
+            )
+        }
+    }
+
+    /// Load the extended CPU state from the area
+    ///
+    /// Only the components which fit within the area are restored. This
+    /// does not check `xstate_bv` against XCR0; see `try_load()`.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn load(&self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            let len = self.len.min(size_of::<XSave>());
+            xsave.as_bytes_mut()[..len].copy_from_slice(&self.as_bytes()[..len]);
+            xsave.header.xstate_bv &= self.rfbm();
+            soft::load(&xsave);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            let rfbm = self.rfbm().bits;
+            asm!(
+                "xrstor  [{}]",
+                in(reg) self.blocks.as_ptr(),
+                in("eax") rfbm as u32,
+                in("edx") (rfbm >> 32) as u32,
+                clobber_abi("C"),
+                options(nostack),
+            )
+        }
+    }
+
+    /// Load the extended CPU state, refusing components the OS has not enabled
+    ///
+    /// This behaves like `XSave::try_load()`.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn try_load(&self) -> Result<(), XSaveError> {
+        component::preflight()?;
+
+        if let Some(xcr0) = component::xcr0() {
+            let unsupported = (self.header().xstate_bv & self.rfbm()) - xcr0;
+            if !unsupported.is_empty() {
+                return Err(XSaveError::UnsupportedComponents(unsupported));
+            }
+        }
+
+        self.load();
+        Ok(())
+    }
+}
+
+impl Default for XSaveDyn {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&XSave> for XSaveDyn {
+    /// Copy an `XSave` into an area sized for the current CPU
+    fn from(xsave: &XSave) -> Self {
+        let mut area = Self::new();
+        area.as_bytes_mut()[..size_of::<XSave>()].copy_from_slice(xsave.as_bytes());
+        area
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert!(XSaveDyn::new().size() >= size_of::<XSave>());
+        assert_eq!(
+            XSaveDyn::with_size(575).err(),
+            Some(XSaveError::InvalidLength(575))
+        );
+
+        let area = XSaveDyn::with_size(832).unwrap();
+        assert_eq!(area.as_bytes().len(), 832);
+        assert_eq!(area.as_bytes().as_ptr() as usize % 64, 0);
+        assert_eq!(area.rfbm(), XStateBv::X87 | XStateBv::SSE | XStateBv::AVX);
+        assert_eq!(area.legacy().mxcsr, MxCsr::DEFAULT);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn detected() {
+        let area = XSaveDyn::new();
+        if component::has_xsave() {
+            let supported = XStateMasks::supported().xcr0;
+            assert_eq!(area.rfbm(), supported | XStateBv::X87 | XStateBv::SSE);
+        } else {
+            assert_eq!(
+                area.rfbm(),
+                XSaveDyn::with_size(area.size()).unwrap().rfbm()
+            );
+        }
+    }
+
+    #[test]
+    fn grow() {
+        let mut area = XSaveDyn::with_size(832).unwrap();
+        area.extended_mut()[255] = 0xAA;
+
+        area.grow(600);
+        assert_eq!(area.size(), 832);
+
+        area.grow(11008);
+        assert_eq!(area.size(), 11008);
+        assert_eq!(area.as_bytes().as_ptr() as usize % 64, 0);
+        assert_eq!(area.extended()[255], 0xAA);
+        assert!(area.extended()[256..].iter().all(|b| *b == 0));
+        assert!(area.rfbm().contains(XStateBv::AMX_TILEDATA));
+    }
+
+    #[test]
+    fn convert() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[600] = 0xAA;
+
+        let mut area = XSaveDyn::from(&xsave);
+        assert_eq!(area.extended()[600 - EXTENDED], 0xAA);
+        assert_eq!(area.validate(), Ok(()));
+        assert_eq!(
+            area.to_xsave()
+                .map(|x| x.as_bytes()[..] == xsave.as_bytes()[..]),
+            Ok(true)
+        );
+
+        area.header_mut().xstate_bv |= XStateBv::AMX_TILEDATA;
+        assert_eq!(
+            area.to_xsave().err(),
+            Some(XSaveError::UnsupportedComponents(XStateBv::AMX_TILEDATA))
+        );
+
+        let mut small = XSaveDyn::with_size(832).unwrap();
+        small.header_mut().xstate_bv = XStateBv::AVX512_OPMASK;
+        assert_eq!(
+            small.validate(),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX512_OPMASK))
+        );
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn roundtrip() {
+        let mut area = XSaveDyn::new();
+        area.save();
+
+        let mut saved = XSaveDyn::new();
+        area.try_load().unwrap();
+        saved.save();
+
+        let mask = area.header().xstate_bv & (XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(saved.header().xstate_bv & mask, mask);
+        assert_eq!(saved.legacy().fcw, area.legacy().fcw);
+    }
+}
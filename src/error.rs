@@ -0,0 +1,89 @@
+//! The errors returned by this crate
+
+use core::fmt;
+
+use super::XStateBv;
+
+/// An error produced while validating or converting an `XSave`
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum XSaveError {
+    /// The buffer length does not match the expected size
+    InvalidLength(usize),
+
+    /// A reserved byte at the given offset is nonzero
+    ReservedByte(usize),
+
+    /// MXCSR has reserved bits set
+    MxCsrReserved(u32),
+
+    /// The components are not supported or enabled
+    UnsupportedComponents(XStateBv),
+
+    /// The components form an architecturally invalid mask
+    InvalidMask(XStateBv),
+
+    /// The buffer at the given address is not sufficiently aligned
+    Misaligned(usize),
+
+    /// The register with the given index cannot be accessed this way
+    InvalidRegister(u8),
+
+    /// The address at the given offset is not in canonical form
+    NonCanonical(usize),
+
+    /// An allocation of the given number of bytes failed
+    OutOfMemory(usize),
+
+    /// The serialized format has an unsupported version
+    UnsupportedVersion(u32),
+
+    /// The serialized data does not begin with the expected magic number
+    InvalidMagic,
+
+    /// The serialized data does not match its checksum, which is given
+    ChecksumMismatch(u32),
+
+    /// The tile configuration has an illegal byte at the given offset
+    InvalidTileConfig(usize),
+
+    /// The CPU does not support the XSAVE instructions
+    UnsupportedCpu,
+
+    /// The XSAVE instructions are supported but not enabled for use
+    NotEnabled,
+
+    /// A buffer saved at the given epoch has been superseded
+    StaleState(u64),
+
+    /// A pointer is null
+    NullPointer,
+}
+
+impl fmt::Display for XSaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(len) => write!(f, "invalid buffer length: {}", len),
+            Self::ReservedByte(offset) => write!(f, "nonzero reserved byte at offset {}", offset),
+            Self::MxCsrReserved(bits) => write!(f, "reserved MXCSR bits set: {:#x}", bits),
+            Self::UnsupportedComponents(bv) => write!(f, "unsupported components: {:?}", bv),
+            Self::InvalidMask(bv) => write!(f, "invalid component mask: {:?}", bv),
+            Self::Misaligned(addr) => write!(f, "misaligned buffer at {:#x}", addr),
+            Self::InvalidRegister(index) => write!(f, "invalid register index: {}", index),
+            Self::NonCanonical(offset) => write!(f, "non-canonical address at offset {}", offset),
+            Self::OutOfMemory(size) => write!(f, "failed to allocate {} bytes", size),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version: {}", version)
+            }
+            Self::InvalidMagic => write!(f, "invalid magic number"),
+            Self::ChecksumMismatch(crc) => write!(f, "checksum mismatch: found {:#010x}", crc),
+            Self::InvalidTileConfig(offset) => {
+                write!(f, "invalid tile configuration at offset {}", offset)
+            }
+            Self::UnsupportedCpu => write!(f, "XSAVE is not supported by the CPU"),
+            Self::NotEnabled => write!(f, "XSAVE is not enabled"),
+            Self::StaleState(epoch) => write!(f, "stale state from epoch {}", epoch),
+            Self::NullPointer => write!(f, "null pointer"),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Floating point exceptions
+
+use super::*;
+
+/// A floating point exception
+///
+/// Both the x87 FPU and SSE share the same six exception classes, which
+/// are reported and masked using the same bit positions in FSW, FCW and
+/// MXCSR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FpException {
+    /// Invalid operation (#I)
+    InvalidOperation,
+
+    /// Denormalized operand (#D)
+    Denormal,
+
+    /// Divide by zero (#Z)
+    DivideByZero,
+
+    /// Numeric overflow (#O)
+    Overflow,
+
+    /// Numeric underflow (#U)
+    Underflow,
+
+    /// Inexact result (#P)
+    Precision,
+}
+
+impl FpException {
+    /// All exceptions, in bit order
+    pub const ALL: [Self; 6] = [
+        Self::InvalidOperation,
+        Self::Denormal,
+        Self::DivideByZero,
+        Self::Overflow,
+        Self::Underflow,
+        Self::Precision,
+    ];
+
+    /// The bit position shared by the FSW/FCW/MXCSR flags and masks
+    #[inline]
+    const fn bit(self) -> u32 {
+        self as u32
+    }
+
+    /// The mnemonic letter of this exception, as in `#Z`
+    #[inline]
+    pub(crate) const fn symbol(self) -> &'static str {
+        match self {
+            Self::InvalidOperation => "I",
+            Self::Denormal => "D",
+            Self::DivideByZero => "Z",
+            Self::Overflow => "O",
+            Self::Underflow => "U",
+            Self::Precision => "P",
+        }
+    }
+
+    /// The x87 status word flag for this exception
+    #[inline]
+    pub const fn fsw(self) -> Fsw {
+        Fsw::from_bits_truncate(1 << self.bit())
+    }
+
+    /// The x87 control word mask for this exception
+    #[inline]
+    pub const fn fcw(self) -> Fcw {
+        Fcw::from_bits_truncate(1 << self.bit())
+    }
+
+    /// The MXCSR flag for this exception
+    #[inline]
+    pub const fn mxcsr_flag(self) -> MxCsr {
+        MxCsr::from_bits_truncate(1 << self.bit())
+    }
+
+    /// The MXCSR mask for this exception
+    #[inline]
+    pub const fn mxcsr_mask(self) -> MxCsr {
+        MxCsr::from_bits_truncate(1 << (self.bit() + 7))
+    }
+
+    /// Operands for `divss xmm0, xmm1` which raise this exception
+    fn divss_operands(self) -> (f32, f32) {
+        match self {
+            Self::InvalidOperation => (0.0, 0.0),
+            Self::Denormal => (1.0, f32::from_bits(1)),
+            Self::DivideByZero => (1.0, 0.0),
+            Self::Overflow => (f32::MAX, 0.5),
+            Self::Underflow => (f32::MIN_POSITIVE, 3.0),
+            Self::Precision => (1.0, 3.0),
+        }
+    }
+}
+
+/// The floating point exceptions pending in both the x87 FPU and SSE
+///
+/// This combines the sticky exception flags of FSW and MXCSR with the
+/// masks of FCW and MXCSR, answering in one place which exceptions have
+/// occurred and which of those would trap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FpExceptions {
+    x87_flags: u8,
+    x87_masks: u8,
+    sse_flags: u8,
+    sse_masks: u8,
+}
+
+impl FpExceptions {
+    /// Summarize the exceptions of the given registers
+    #[inline]
+    pub const fn new(fcw: Fcw, fsw: Fsw, mxcsr: MxCsr) -> Self {
+        Self {
+            x87_flags: fsw.bits as u8 & 0x3F,
+            x87_masks: fcw.bits as u8 & 0x3F,
+            sse_flags: mxcsr.bits as u8 & 0x3F,
+            sse_masks: (mxcsr.bits >> 7) as u8 & 0x3F,
+        }
+    }
+
+    #[inline]
+    fn iter(bits: u8) -> impl Iterator<Item = FpException> {
+        FpException::ALL
+            .into_iter()
+            .filter(move |e| bits & (1 << e.bit()) != 0)
+    }
+
+    /// The exceptions flagged in FSW
+    #[inline]
+    pub fn x87_pending(&self) -> impl Iterator<Item = FpException> {
+        Self::iter(self.x87_flags)
+    }
+
+    /// The exceptions flagged in MXCSR
+    #[inline]
+    pub fn sse_pending(&self) -> impl Iterator<Item = FpException> {
+        Self::iter(self.sse_flags)
+    }
+
+    /// The exceptions flagged in either FSW or MXCSR
+    #[inline]
+    pub fn pending(&self) -> impl Iterator<Item = FpException> {
+        Self::iter(self.x87_flags | self.sse_flags)
+    }
+
+    /// The exceptions which are flagged and unmasked in either unit
+    ///
+    /// An unmasked x87 exception raises #MF on the next waiting x87
+    /// instruction. SSE exceptions only trap when raised by an instruction,
+    /// so an unmasked SSE flag means a trap was, or would have been,
+    /// delivered.
+    #[inline]
+    pub fn unmasked(&self) -> impl Iterator<Item = FpException> {
+        let x87 = self.x87_flags & !self.x87_masks;
+        let sse = self.sse_flags & !self.sse_masks;
+        Self::iter(x87 | sse)
+    }
+
+    /// Whether any exception is flagged in either unit
+    #[inline]
+    pub const fn is_pending(&self) -> bool {
+        self.x87_flags | self.sse_flags != 0
+    }
+}
+
+impl From<&XSaveLegacy> for FpExceptions {
+    #[inline]
+    fn from(legacy: &XSaveLegacy) -> Self {
+        Self::new(legacy.fcw, legacy.fsw, legacy.mxcsr)
+    }
+}
+
+impl From<fenv::FpEnv> for FpExceptions {
+    #[inline]
+    fn from(env: fenv::FpEnv) -> Self {
+        Self::new(env.fcw, env.fsw, env.mxcsr)
+    }
+}
+
+impl XSave {
+    /// Create a state with a pending unmasked x87 exception
+    ///
+    /// The exception flag is set in FSW along with the exception summary
+    /// and busy bits, and the corresponding FCW mask is cleared. After
+    /// `load()`, the next waiting x87 instruction (such as `fwait`)
+    /// raises #MF.
+    pub fn x87_exception(exception: FpException) -> Self {
+        let mut xsave = Self::DEFAULT;
+        xsave.legacy.fcw.remove(exception.fcw());
+        xsave.legacy.fsw = exception.fsw() | Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY;
+        xsave.header.xstate_bv = XStateBv::X87;
+        xsave
+    }
+
+    /// Create a state in which `divss xmm0, xmm1` raises an SSE exception
+    ///
+    /// SSE has no notion of a pending exception: setting an MXCSR flag
+    /// never traps by itself, so no state can make the exception pending
+    /// on `load()`. Instead, the exception is unmasked in MXCSR and `xmm0`
+    /// and `xmm1` are loaded with operands such that, after `load()`, the
+    /// caller executing `divss xmm0, xmm1` raises #XM for exactly this
+    /// exception.
+    pub fn sse_exception(exception: FpException) -> Self {
+        let (dividend, divisor) = exception.divss_operands();
+
+        let mut xsave = Self::DEFAULT;
+        xsave.legacy.mxcsr.remove(exception.mxcsr_mask());
+        xsave.legacy.xmm[0].0[..4].copy_from_slice(&dividend.to_le_bytes());
+        xsave.legacy.xmm[1].0[..4].copy_from_slice(&divisor.to_le_bytes());
+        xsave.header.xstate_bv = XStateBv::SSE;
+        xsave
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn x87() {
+        for exception in FpException::ALL {
+            let xsave = XSave::x87_exception(exception);
+            assert!(!xsave.legacy.fcw.contains(exception.fcw()));
+            assert!(xsave.legacy.fsw.contains(exception.fsw()));
+            assert!(xsave.legacy.fsw.contains(Fsw::EXCEPTION_SUMMARY));
+            assert_eq!(xsave.header.xstate_bv, XStateBv::X87);
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let mut legacy = XSave::x87_exception(FpException::DivideByZero).legacy;
+        legacy.mxcsr |= MxCsr::PRECISION | MxCsr::OVERFLOW;
+        legacy.mxcsr.remove(MxCsr::OVERFLOW_MASK);
+
+        let summary = FpExceptions::from(&legacy);
+        assert!(summary.is_pending());
+        assert!(summary.x87_pending().eq([FpException::DivideByZero]));
+        assert!(summary
+            .sse_pending()
+            .eq([FpException::Overflow, FpException::Precision]));
+        assert!(summary
+            .unmasked()
+            .eq([FpException::DivideByZero, FpException::Overflow]));
+
+        let clean = FpExceptions::from(&XSaveLegacy::DEFAULT);
+        assert!(!clean.is_pending());
+        assert_eq!(clean.pending().count(), 0);
+    }
+
+    #[test]
+    fn sse() {
+        let xsave = XSave::sse_exception(FpException::DivideByZero);
+        assert_eq!(xsave.legacy.mxcsr.bits, 0x1D80);
+        assert_eq!(xsave.legacy.xmm[0].0[..4], 1.0f32.to_le_bytes());
+        assert_eq!(xsave.legacy.xmm[1].0[..4], 0.0f32.to_le_bytes());
+        assert_eq!(xsave.header.xstate_bv, XStateBv::SSE);
+    }
+
+    /// Whether a test is running in the child spawned by `raise()`
+    #[cfg(all(xsave_hw, unix))]
+    fn in_child() -> bool {
+        std::env::var_os("XSAVE_RAISE_CHILD").is_some()
+    }
+
+    /// Run a test in a child process, returning the signal which killed it
+    #[cfg(all(xsave_hw, unix))]
+    fn signal_of(test: &str) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads=1"])
+            .env("XSAVE_RAISE_CHILD", "1")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap()
+            .signal()
+    }
+
+    #[test]
+    #[cfg(all(xsave_hw, unix))]
+    fn raise_x87_child() {
+        if in_child() {
+            XSave::x87_exception(FpException::DivideByZero).load();
+            unsafe { core::arch::asm!("fwait", options(nomem, nostack)) }
+        }
+    }
+
+    #[test]
+    #[cfg(all(xsave_hw, unix))]
+    fn raise_sse_child() {
+        if in_child() {
+            // Nothing may touch xmm0 or xmm1 between the load and divss.
+            XSave::sse_exception(FpException::DivideByZero).load();
+            unsafe {
+                core::arch::asm!(
+                    "divss xmm0, xmm1",
+                    out("xmm0") _,
+                    options(nomem, nostack),
+                )
+            }
+        }
+    }
+
+    /// The states really fault, #MF and #XM both arriving as SIGFPE
+    #[test]
+    #[cfg(all(xsave_hw, unix))]
+    fn raise() {
+        const SIGFPE: i32 = 8;
+
+        if in_child() {
+            return;
+        }
+
+        assert_eq!(signal_of("exception::tests::raise_x87_child"), Some(SIGFPE));
+        assert_eq!(signal_of("exception::tests::raise_sse_child"), Some(SIGFPE));
+    }
+}
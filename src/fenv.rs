@@ -0,0 +1,515 @@
+//! Floating point environment control
+//!
+//! This module provides a `fenv.h` replacement for `no_std` runtimes. Only
+//! the control and status registers are touched, so it is far cheaper than
+//! saving and restoring the full extended state.
+//!
+//! The free functions in this module adjust the live registers in place,
+//! applying each change to both the x87 FPU and SSE. Where `save()` and
+//! `load()` use the software model, so do they.
+
+#[cfg(xsave_hw)]
+use core::arch::asm;
+
+use super::*;
+
+/// The x87 environment as stored by `fnstenv` in 32-bit protected mode
+#[cfg(xsave_hw)]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct X87Env {
+    fcw: Fcw,
+    reserved0: u16,
+    fsw: Fsw,
+    reserved1: u16,
+    other: [u32; 5],
+}
+
+/// A floating point environment
+///
+/// This covers the x87 control word, the x87 exception flags and MXCSR.
+/// Any other `fsw` bits are ignored when the environment is installed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FpEnv {
+    pub fcw: Fcw,
+    pub fsw: Fsw,
+    pub mxcsr: MxCsr,
+}
+
+impl FpEnv {
+    /// The x87 status word bits covered by the environment
+    const FLAGS: Fsw = Fsw::from_bits_truncate(0x3F);
+
+    /// Capture the current floating point environment
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn capture() -> Self {
+        #[cfg(xsave_soft)]
+        let env = Self::from_legacy(&model());
+
+        #[cfg(xsave_hw)]
+        let env = {
+            let mut env = Self::default();
+
+            #[cfg(not(doc))]
+            unsafe {
+                asm!(
+                    "fnstcw  [{}]",
+                    "fnstsw  [{}]",
+                    "stmxcsr [{}]",
+                    in(reg) &mut env.fcw,
+                    in(reg) &mut env.fsw,
+                    in(reg) &mut env.mxcsr,
+                    options(nostack, preserves_flags),
+                )
+            }
+
+            env.fsw &= Self::FLAGS;
+            env
+        };
+
+        env
+    }
+
+    /// Install this floating point environment
+    ///
+    /// If an installed x87 exception flag is unmasked by the installed
+    /// control word, the exception is left pending and is raised by the
+    /// next waiting x87 instruction.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn install(&self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut legacy = model();
+            self.apply(&mut legacy);
+            soft::fxrstor(&legacy);
+        }
+
+        #[cfg(xsave_hw)]
+        {
+            let mut x87 = X87Env::default();
+
+            #[cfg(not(doc))]
+            unsafe {
+                asm!(
+                    "fnstenv [{}]",
+                    in(reg) &mut x87,
+                    options(nostack, preserves_flags),
+                )
+            }
+
+            x87.fcw = self.fcw;
+            x87.fsw = self.status(x87.fsw);
+
+            #[cfg(not(doc))]
+            unsafe {
+                asm!(
+                    "fldenv  [{}]",
+                    "ldmxcsr [{}]",
+                    in(reg) &x87,
+                    in(reg) &self.mxcsr,
+                    options(nostack, preserves_flags),
+                )
+            }
+        }
+    }
+
+    /// Capture the floating point environment stored in a legacy area
+    #[inline]
+    pub fn from_legacy(legacy: &XSaveLegacy) -> Self {
+        Self {
+            fcw: legacy.fcw,
+            fsw: legacy.fsw & Self::FLAGS,
+            mxcsr: legacy.mxcsr,
+        }
+    }
+
+    /// Install this floating point environment into a legacy area
+    #[inline]
+    pub fn apply(&self, legacy: &mut XSaveLegacy) {
+        legacy.fcw = self.fcw;
+        legacy.fsw = self.status(legacy.fsw);
+        legacy.mxcsr = self.mxcsr;
+    }
+
+    /// Merge the exception flags into an existing status word
+    ///
+    /// The exception summary and busy bits are recomputed to reflect
+    /// whether any of the merged exceptions are unmasked.
+    fn status(&self, fsw: Fsw) -> Fsw {
+        let flags = self.fsw & Self::FLAGS;
+        let unmasked = flags.bits & !self.fcw.bits & Self::FLAGS.bits;
+
+        let mut fsw = (fsw - Self::FLAGS) | flags;
+        let pending = unmasked != 0 || fsw.contains(Fsw::STACK_FAULT);
+        fsw.set(Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY, pending);
+        fsw
+    }
+}
+
+impl From<&XSaveLegacy> for FpEnv {
+    #[inline]
+    fn from(legacy: &XSaveLegacy) -> Self {
+        Self::from_legacy(legacy)
+    }
+}
+
+/// The legacy area of the software model
+#[inline]
+#[cfg(xsave_soft)]
+fn model() -> XSaveLegacy {
+    let mut legacy = XSaveLegacy::DEFAULT;
+    soft::fxsave(&mut legacy);
+    legacy
+}
+
+/// Read the live x87 control word
+#[inline]
+#[cfg(xsave_asm)]
+fn fcw() -> Fcw {
+    #[cfg(xsave_soft)]
+    let fcw = model().fcw;
+
+    #[cfg(xsave_hw)]
+    let fcw = {
+        let mut fcw = Fcw::DEFAULT;
+
+        #[cfg(not(doc))]
+        unsafe {
+            asm!(
+                "fnstcw  [{}]",
+                in(reg) &mut fcw,
+                options(nostack, preserves_flags),
+            )
+        }
+
+        fcw
+    };
+
+    fcw
+}
+
+/// Write the live x87 control word
+#[inline]
+#[cfg(xsave_asm)]
+fn set_fcw(fcw: Fcw) {
+    #[cfg(xsave_soft)]
+    {
+        let mut legacy = model();
+        legacy.fcw = fcw;
+        soft::fxrstor(&legacy);
+    }
+
+    #[cfg(all(xsave_hw, not(doc)))]
+    unsafe {
+        asm!(
+            "fldcw   [{}]",
+            in(reg) &fcw,
+            options(nostack, preserves_flags),
+        )
+    }
+}
+
+/// Read the live MXCSR
+#[inline]
+#[cfg(xsave_asm)]
+fn mxcsr() -> MxCsr {
+    #[cfg(xsave_soft)]
+    let mxcsr = model().mxcsr;
+
+    #[cfg(xsave_hw)]
+    let mxcsr = {
+        let mut mxcsr = MxCsr::DEFAULT;
+
+        #[cfg(not(doc))]
+        unsafe {
+            asm!(
+                "stmxcsr [{}]",
+                in(reg) &mut mxcsr,
+                options(nostack, preserves_flags),
+            )
+        }
+
+        mxcsr
+    };
+
+    mxcsr
+}
+
+/// Write the live MXCSR
+#[inline]
+#[cfg(xsave_asm)]
+fn set_mxcsr(mxcsr: MxCsr) {
+    #[cfg(xsave_soft)]
+    {
+        let mut legacy = model();
+        legacy.mxcsr = mxcsr;
+        soft::fxrstor(&legacy);
+    }
+
+    #[cfg(all(xsave_hw, not(doc)))]
+    unsafe {
+        asm!(
+            "ldmxcsr [{}]",
+            in(reg) &mxcsr,
+            options(nostack, preserves_flags),
+        )
+    }
+}
+
+/// Get the current rounding mode
+///
+/// This is read from MXCSR, since that is what governs Rust's `f32` and
+/// `f64` arithmetic.
+#[inline]
+#[cfg(xsave_asm)]
+pub fn rounding_mode() -> RoundingMode {
+    mxcsr().rounding_mode()
+}
+
+/// Set the rounding mode
+#[inline]
+#[cfg(xsave_asm)]
+pub fn set_rounding_mode(mode: RoundingMode) {
+    let mut fcw = fcw();
+    fcw.set_rounding_mode(mode);
+    set_fcw(fcw);
+
+    let mut mxcsr = mxcsr();
+    mxcsr.set_rounding_mode(mode);
+    set_mxcsr(mxcsr);
+}
+
+/// Unmask the specified exceptions so that they trap
+///
+/// Any x87 flag which is already raised for a newly unmasked exception
+/// will trap on the next waiting x87 instruction. Call `clear_flags()`
+/// first to avoid this.
+#[inline]
+#[cfg(xsave_asm)]
+pub fn enable_traps(exceptions: impl IntoIterator<Item = FpException>) {
+    let mut fcw = fcw();
+    let mut mxcsr = mxcsr();
+
+    for exception in exceptions {
+        fcw.remove(exception.fcw());
+        mxcsr.remove(exception.mxcsr_mask());
+    }
+
+    set_fcw(fcw);
+    set_mxcsr(mxcsr);
+}
+
+/// Mask the specified exceptions so that they no longer trap
+#[inline]
+#[cfg(xsave_asm)]
+pub fn disable_traps(exceptions: impl IntoIterator<Item = FpException>) {
+    let mut fcw = fcw();
+    let mut mxcsr = mxcsr();
+
+    for exception in exceptions {
+        fcw.insert(exception.fcw());
+        mxcsr.insert(exception.mxcsr_mask());
+    }
+
+    set_fcw(fcw);
+    set_mxcsr(mxcsr);
+}
+
+/// Clear all raised exception flags
+#[inline]
+#[cfg(xsave_asm)]
+pub fn clear_flags() {
+    #[cfg(xsave_soft)]
+    {
+        let mut legacy = model();
+        let pending = Fsw::STACK_FAULT | Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY;
+        legacy.fsw.remove(FpEnv::FLAGS | pending);
+        soft::fxrstor(&legacy);
+    }
+
+    #[cfg(all(xsave_hw, not(doc)))]
+    unsafe {
+        asm!("fnclex", options(nomem, nostack, preserves_flags))
+    }
+
+    set_mxcsr(mxcsr() - MxCsr::from_bits_truncate(0x3F));
+}
+
+/// The invalid operation exception of `fenv.h`
+pub const FE_INVALID: i32 = 0x01;
+
+/// The x86 specific denormal operand exception (`__FE_DENORM` in glibc)
+pub const FE_DENORM: i32 = 0x02;
+
+/// The divide by zero exception of `fenv.h`
+pub const FE_DIVBYZERO: i32 = 0x04;
+
+/// The overflow exception of `fenv.h`
+pub const FE_OVERFLOW: i32 = 0x08;
+
+/// The underflow exception of `fenv.h`
+pub const FE_UNDERFLOW: i32 = 0x10;
+
+/// The inexact result exception of `fenv.h`
+pub const FE_INEXACT: i32 = 0x20;
+
+/// Every standard exception of `fenv.h`, which excludes `FE_DENORM`
+pub const FE_ALL_EXCEPT: i32 = FE_INVALID | FE_DIVBYZERO | FE_OVERFLOW | FE_UNDERFLOW | FE_INEXACT;
+
+/// The round to nearest mode of `fenv.h`
+pub const FE_TONEAREST: i32 = 0x000;
+
+/// The round down mode of `fenv.h`
+pub const FE_DOWNWARD: i32 = 0x400;
+
+/// The round up mode of `fenv.h`
+pub const FE_UPWARD: i32 = 0x800;
+
+/// The round toward zero mode of `fenv.h`
+pub const FE_TOWARDZERO: i32 = 0xC00;
+
+impl RoundingMode {
+    /// The `FE_*` rounding constant for this mode
+    #[inline]
+    pub const fn to_fe(self) -> i32 {
+        (self as i32) << 10
+    }
+
+    /// The mode for an `FE_*` rounding constant
+    #[inline]
+    pub const fn from_fe(round: i32) -> Option<Self> {
+        match round {
+            FE_TONEAREST => Some(Self::Nearest),
+            FE_DOWNWARD => Some(Self::Down),
+            FE_UPWARD => Some(Self::Up),
+            FE_TOWARDZERO => Some(Self::TowardZero),
+            _ => None,
+        }
+    }
+}
+
+impl FpException {
+    /// The `FE_*` constant for this exception
+    ///
+    /// The x87 and SSE flag bits share the `fenv.h` encoding on x86.
+    #[inline]
+    pub const fn to_fe(self) -> i32 {
+        self.fsw().bits as i32
+    }
+
+    /// The exception for a single `FE_*` exception constant
+    #[inline]
+    pub fn from_fe(except: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.to_fe() == except)
+    }
+}
+
+/// Convert exceptions to an `FE_*` exception set
+#[inline]
+pub fn to_fe_except(exceptions: impl IntoIterator<Item = FpException>) -> i32 {
+    exceptions.into_iter().fold(0, |set, e| set | e.to_fe())
+}
+
+/// Convert an `FE_*` exception set to the exceptions it contains
+///
+/// Bits which do not correspond to an exception are ignored.
+#[inline]
+pub fn from_fe_except(excepts: i32) -> impl Iterator<Item = FpException> {
+    FpException::ALL
+        .into_iter()
+        .filter(move |e| excepts & e.to_fe() != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix() {
+        for mode in [
+            RoundingMode::Nearest,
+            RoundingMode::Down,
+            RoundingMode::Up,
+            RoundingMode::TowardZero,
+        ] {
+            assert_eq!(RoundingMode::from_fe(mode.to_fe()), Some(mode));
+        }
+        assert_eq!(RoundingMode::from_fe(0x100), None);
+        assert_eq!(RoundingMode::Up.to_fe(), FE_UPWARD);
+
+        for exception in FpException::ALL {
+            assert_eq!(FpException::from_fe(exception.to_fe()), Some(exception));
+        }
+        assert_eq!(FpException::from_fe(FE_INVALID | FE_INEXACT), None);
+        assert_eq!(FpException::Denormal.to_fe(), FE_DENORM);
+
+        let standard = FpException::ALL
+            .into_iter()
+            .filter(|e| *e != FpException::Denormal);
+        assert_eq!(to_fe_except(standard), FE_ALL_EXCEPT);
+        assert!(from_fe_except(FE_DIVBYZERO | FE_OVERFLOW | 0x100)
+            .eq([FpException::DivideByZero, FpException::Overflow]));
+    }
+
+    #[test]
+    fn legacy() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fsw = Fsw::CONDITION0 | Fsw::PRECISION;
+
+        let mut env = FpEnv::from(&legacy);
+        assert_eq!(env.fsw, Fsw::PRECISION);
+
+        env.fcw.remove(Fcw::DIVIDE_BY_ZERO);
+        env.fsw = Fsw::DIVIDE_BY_ZERO;
+        env.apply(&mut legacy);
+
+        let pending = Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY;
+        assert_eq!(legacy.fsw, Fsw::CONDITION0 | Fsw::DIVIDE_BY_ZERO | pending);
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn asm() {
+        let original = FpEnv::capture();
+        assert_eq!(original.fcw, Fcw::DEFAULT);
+
+        let mut env = original;
+        env.fsw = Fsw::PRECISION;
+        env.mxcsr |= MxCsr::FLUSH_TO_ZERO | MxCsr::PRECISION;
+        env.install();
+        assert_eq!(FpEnv::capture(), env);
+
+        original.install();
+        assert_eq!(FpEnv::capture(), original);
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn control() {
+        let original = FpEnv::capture();
+
+        set_rounding_mode(RoundingMode::TowardZero);
+        assert_eq!(rounding_mode(), RoundingMode::TowardZero);
+        assert_eq!(fcw().rounding_mode(), RoundingMode::TowardZero);
+
+        enable_traps([FpException::DivideByZero]);
+        assert!(!fcw().contains(Fcw::DIVIDE_BY_ZERO));
+        assert!(!mxcsr().contains(MxCsr::DIVIDE_BY_ZERO_MASK));
+
+        disable_traps(FpException::ALL);
+        let mut env = FpEnv::capture();
+        assert!(env.mxcsr.contains(MxCsr::DEFAULT));
+
+        env.fsw = Fsw::PRECISION;
+        env.mxcsr |= MxCsr::PRECISION;
+        env.install();
+        clear_flags();
+        let env = FpEnv::capture();
+        assert_eq!(env.fsw, Fsw::empty());
+        assert!(!env.mxcsr.contains(MxCsr::PRECISION));
+
+        original.install();
+    }
+}
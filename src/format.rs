@@ -0,0 +1,55 @@
+//! Compact `defmt` formatting of the flag types and buffers
+//!
+//! On targets logging over RTT, `Display` would pull the `core::fmt`
+//! machinery into the image. These implementations send the raw register
+//! values instead and leave the decoding to the host, and summarize an
+//! `XSave` by its header and control registers rather than its contents.
+
+use defmt::{Format, Formatter};
+
+use super::*;
+
+impl Format for Fcw {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "Fcw({=u16:#06x})", self.bits)
+    }
+}
+
+impl Format for Fsw {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "Fsw({=u16:#06x})", self.bits)
+    }
+}
+
+impl Format for MxCsr {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "MxCsr({=u32:#06x})", self.bits)
+    }
+}
+
+impl Format for XCompBv {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "XCompBv({=u64:#x})", self.bits)
+    }
+}
+
+impl Format for XStateBv {
+    fn format(&self, f: Formatter) {
+        defmt::write!(f, "XStateBv({=u64:#x})", self.bits)
+    }
+}
+
+impl Format for XSave {
+    /// Formats the header and the x87 and SSE control and status registers
+    fn format(&self, f: Formatter) {
+        defmt::write!(
+            f,
+            "XSave {{ xstate_bv: {}, xcomp_bv: {}, fcw: {}, fsw: {}, mxcsr: {} }}",
+            self.header.xstate_bv,
+            self.header.xcomp_bv,
+            self.legacy.fcw,
+            self.legacy.fsw,
+            self.legacy.mxcsr,
+        )
+    }
+}
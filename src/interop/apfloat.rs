@@ -0,0 +1,61 @@
+//! Conversions to and from the software floats of `rustc_apfloat`
+//!
+//! The x87 registers hold 80-bit extended precision values, which have no
+//! native Rust type. `X87DoubleExtended` models them exactly, so emulators
+//! can do arithmetic on saved registers with full precision.
+
+use rustc_apfloat::ieee::X87DoubleExtended;
+use rustc_apfloat::Float;
+
+use super::*;
+
+impl From<Mm> for X87DoubleExtended {
+    #[inline]
+    fn from(mm: Mm) -> Self {
+        let mut bits = [0; 16];
+        bits[..10].copy_from_slice(&mm.0);
+        Self::from_bits(u128::from_le_bytes(bits))
+    }
+}
+
+impl From<X87DoubleExtended> for Mm {
+    #[inline]
+    fn from(value: X87DoubleExtended) -> Self {
+        let mut mm = Mm::DEFAULT;
+        mm.0.copy_from_slice(&value.to_bits().to_le_bytes()[..10]);
+        mm
+    }
+}
+
+impl From<FpReg> for X87DoubleExtended {
+    #[inline]
+    fn from(reg: FpReg) -> Self {
+        Mm::from(reg).into()
+    }
+}
+
+impl From<X87DoubleExtended> for FpReg {
+    #[inline]
+    fn from(value: X87DoubleExtended) -> Self {
+        Mm::from(value).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert() {
+        // -1.5: sign, biased exponent 0x3FFF, significand 0b11 << 62
+        let reg = FpReg {
+            significand: [0, 0, 0, 0xC000],
+            exponent: 0xBFFF,
+        };
+
+        let value = X87DoubleExtended::from(reg);
+        assert!(value.is_negative());
+        assert_eq!(value.to_bits(), 0xBFFF_C000_0000_0000_0000);
+        assert_eq!(FpReg::from(value), reg);
+    }
+}
@@ -0,0 +1,97 @@
+//! Conversions to and from the control register types of `x86_64`
+//!
+//! Kernels built on the `x86_64` crate manage CR0, CR4 and XCR0 through
+//! its flag types. These conversions let such kernels move between those
+//! types and the masks of this crate, and compute the control register
+//! bits which must be set before any XSAVE instruction is executed.
+
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::xcontrol::XCr0Flags;
+
+use super::*;
+
+impl From<XCr0Flags> for XStateBv {
+    #[inline]
+    fn from(flags: XCr0Flags) -> Self {
+        Self { bits: flags.bits() }
+    }
+}
+
+impl TryFrom<XStateBv> for XCr0Flags {
+    type Error = XSaveError;
+
+    /// Convert the mask, failing if `x86_64` has no flag for a component
+    fn try_from(bv: XStateBv) -> Result<Self, Self::Error> {
+        let unknown = bv.bits & !XCr0Flags::all().bits();
+
+        match unknown {
+            0 => Ok(Self::from_bits_truncate(bv.bits)),
+            bits => Err(XSaveError::UnsupportedComponents(XStateBv { bits })),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Update CR0 so that the x87 and SSE state is usable
+    ///
+    /// This clears emulation and the lazy-switching trap, and selects
+    /// native reporting of x87 exceptions.
+    pub fn enable_cr0(&self, cr0: Cr0Flags) -> Cr0Flags {
+        let clear = Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED;
+        let set = Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR;
+        (cr0 - clear) | set
+    }
+
+    /// Update CR4 so that the SSE state and, if supported, XSAVE are usable
+    ///
+    /// XCR0 may only be written once `OSXSAVE` is set.
+    pub fn enable_cr4(&self, cr4: Cr4Flags) -> Cr4Flags {
+        let mut cr4 = cr4 | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE;
+        cr4.set(Cr4Flags::OSXSAVE, self.xsave);
+        cr4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xcr0() {
+        let bv = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX | XStateBv::PKRU;
+        let flags = XCr0Flags::try_from(bv).unwrap();
+        assert_eq!(
+            flags,
+            XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX | XCr0Flags::MPK
+        );
+        assert_eq!(XStateBv::from(flags), bv);
+
+        let amx = XStateBv::AMX_TILECFG | XStateBv::AMX_TILEDATA;
+        assert_eq!(
+            XCr0Flags::try_from(bv | amx),
+            Err(XSaveError::UnsupportedComponents(amx))
+        );
+    }
+
+    #[test]
+    fn enable() {
+        let mut caps = Capabilities::NONE;
+        let cr0 = caps.enable_cr0(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::PROTECTED_MODE_ENABLE);
+        assert_eq!(
+            cr0,
+            Cr0Flags::PROTECTED_MODE_ENABLE
+                | Cr0Flags::MONITOR_COPROCESSOR
+                | Cr0Flags::NUMERIC_ERROR
+        );
+
+        caps.xsave = false;
+        let cr4 = caps.enable_cr4(Cr4Flags::OSXSAVE | Cr4Flags::PAGE_SIZE_EXTENSION);
+        assert_eq!(
+            cr4,
+            Cr4Flags::PAGE_SIZE_EXTENSION | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE
+        );
+
+        caps.xsave = true;
+        assert!(caps.enable_cr4(cr4).contains(Cr4Flags::OSXSAVE));
+    }
+}
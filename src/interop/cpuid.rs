@@ -0,0 +1,108 @@
+//! Conversions from the CPUID parsers of `raw-cpuid`
+//!
+//! Projects which already read CPUID leaf 0xD through `raw-cpuid` can
+//! build the component table and capabilities from its results, rather
+//! than parsing the same leaf a second time. Components which `raw-cpuid`
+//! does not know about, such as AMX, are not reported by its iterator and
+//! so are absent from the results.
+
+use raw_cpuid::{ExtendedStateInfo, FeatureInfo};
+
+use super::*;
+
+impl From<&ExtendedStateInfo> for ComponentTable {
+    fn from(info: &ExtendedStateInfo) -> Self {
+        let mut table = ComponentTable::empty();
+
+        for state in info.iter() {
+            table.set(
+                state.subleaf as usize,
+                ComponentInfo {
+                    size: state.size(),
+                    offset: state.offset(),
+                    supervisor: state.is_in_ia32_xss(),
+                    align64: state.is_compacted_format(),
+                },
+            );
+        }
+
+        table
+    }
+}
+
+impl From<&ExtendedStateInfo> for XStateMasks {
+    fn from(info: &ExtendedStateInfo) -> Self {
+        let mut masks = XStateMasks {
+            xcr0: XStateBv::empty(),
+            xss: XStateBv::empty(),
+        };
+
+        masks
+            .xcr0
+            .set(XStateBv::X87, info.xcr0_supports_legacy_x87());
+        masks.xcr0.set(XStateBv::SSE, info.xcr0_supports_sse_128());
+
+        for state in info.iter() {
+            let bit = XStateBv {
+                bits: 1 << state.subleaf,
+            };
+
+            match state.is_in_ia32_xss() {
+                true => masks.xss |= bit,
+                false => masks.xcr0 |= bit,
+            }
+        }
+
+        masks
+    }
+}
+
+impl Capabilities {
+    /// Build the capabilities from the results of `raw-cpuid`
+    ///
+    /// `features` supplies the XSAVE and OSXSAVE bits of leaf 1, and
+    /// `state` everything else.
+    pub fn from_raw_cpuid(features: &FeatureInfo, state: &ExtendedStateInfo) -> Self {
+        Self {
+            xsave: features.has_xsave(),
+            osxsave: features.has_oxsave(),
+            xsaveopt: state.has_xsaveopt(),
+            xsavec: state.has_xsavec(),
+            xgetbv1: state.has_xgetbv(),
+            xsaves: state.has_xsaves_xrstors(),
+            supported: state.into(),
+            enabled_size: state.xsave_area_size_enabled_features(),
+            max_size: state.xsave_area_size_supported_features(),
+            components: state.into(),
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use raw_cpuid::CpuId;
+
+    #[test]
+    fn detect() {
+        let cpuid = CpuId::new();
+        let (features, state) = match (cpuid.get_feature_info(), cpuid.get_extended_state_info()) {
+            (Some(features), Some(state)) if features.has_xsave() => (features, state),
+            _ => return,
+        };
+
+        let caps = Capabilities::from_raw_cpuid(&features, &state);
+        let detected = Capabilities::detect();
+        assert_eq!(caps.osxsave, detected.osxsave);
+        assert_eq!(caps.xsavec, detected.xsavec);
+        assert_eq!(caps.max_size, detected.max_size);
+
+        // Only the components known to `raw-cpuid` are reported.
+        assert!(detected.supported.xcr0.contains(caps.supported.xcr0));
+        for i in 2..64 {
+            if caps.supported.xcr0.bits & (1 << i) != 0 {
+                assert_eq!(caps.components.get(i), detected.components.get(i));
+            }
+        }
+    }
+}
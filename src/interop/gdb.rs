@@ -0,0 +1,535 @@
+//! Register access for the `gdbstub` crate
+//!
+//! `gdbstub_arch` describes the general purpose, x87 and SSE registers of
+//! a 64-bit target with `X86_64CoreRegs`, whose floating point and vector
+//! fields can be read from and written to an `XSave` with the functions
+//! here. For the AVX and AVX-512 registers, this module provides its own
+//! register files and `Arch` implementations, whose target descriptions
+//! follow the layout of the `org.gnu.gdb.i386` features shipped with GDB.
+
+use core::convert::TryInto;
+
+use gdbstub::arch::{Arch, Registers, SingleStepGdbBehavior};
+use gdbstub_arch::x86::reg::id::X86_64CoreRegId;
+use gdbstub_arch::x86::reg::X86_64CoreRegs;
+
+use super::*;
+
+/// The number of bytes of `X86_64CoreRegs` described by `org.gnu.gdb.i386.sse`
+///
+/// `gdbstub_arch` follows these with padding for registers outside both
+/// the core and SSE features, which the targets here leave out.
+const CORE_SIZE: usize = 0x218;
+
+macro_rules! numbered {
+    ($prefix:literal, $suffix:literal, $bits:literal, $ty:literal; $($n:literal)*) => {
+        concat!($(
+            "<reg name=\"", $prefix, $n, $suffix,
+            "\" bitsize=\"", $bits, "\" type=\"", $ty, "\"/>",
+        )*)
+    };
+}
+
+macro_rules! named {
+    ($bits:literal, $ty:literal; $($name:literal)*) => {
+        concat!($("<reg name=\"", $name, "\" bitsize=\"", $bits, "\" type=\"", $ty, "\"/>",)*)
+    };
+}
+
+macro_rules! vec128 {
+    () => {
+        concat!(
+            "<vector id=\"v4f\" type=\"ieee_single\" count=\"4\"/>",
+            "<vector id=\"v2d\" type=\"ieee_double\" count=\"2\"/>",
+            "<vector id=\"v16i8\" type=\"int8\" count=\"16\"/>",
+            "<vector id=\"v8i16\" type=\"int16\" count=\"8\"/>",
+            "<vector id=\"v4i32\" type=\"int32\" count=\"4\"/>",
+            "<vector id=\"v2i64\" type=\"int64\" count=\"2\"/>",
+            "<union id=\"vec128\">",
+            "<field name=\"v4_float\" type=\"v4f\"/>",
+            "<field name=\"v2_double\" type=\"v2d\"/>",
+            "<field name=\"v16_int8\" type=\"v16i8\"/>",
+            "<field name=\"v8_int16\" type=\"v8i16\"/>",
+            "<field name=\"v4_int32\" type=\"v4i32\"/>",
+            "<field name=\"v2_int64\" type=\"v2i64\"/>",
+            "<field name=\"uint128\" type=\"uint128\"/>",
+            "</union>",
+        )
+    };
+}
+
+macro_rules! core_sse_avx {
+    () => {
+        concat!(
+            "<architecture>i386:x86-64</architecture>",
+            "<feature name=\"org.gnu.gdb.i386.core\">",
+            named!(64, "int64"; "rax" "rbx" "rcx" "rdx" "rsi" "rdi"),
+            named!(64, "data_ptr"; "rbp" "rsp"),
+            numbered!("r", "", 64, "int64"; 8 9 10 11 12 13 14 15),
+            named!(64, "code_ptr"; "rip"),
+            named!(32, "int32"; "eflags" "cs" "ss" "ds" "es" "fs" "gs"),
+            numbered!("st", "", 80, "i387_ext"; 0 1 2 3 4 5 6 7),
+            named!(32, "int"; "fctrl" "fstat" "ftag" "fiseg" "fioff" "foseg" "fooff" "fop"),
+            "</feature>",
+            "<feature name=\"org.gnu.gdb.i386.sse\">",
+            vec128!(),
+            numbered!("xmm", "", 128, "vec128"; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15),
+            named!(32, "int"; "mxcsr"),
+            "</feature>",
+            "<feature name=\"org.gnu.gdb.i386.avx\">",
+            numbered!("ymm", "h", 128, "uint128"; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15),
+            "</feature>",
+        )
+    };
+}
+
+/// The target description of `X86_64Avx`
+pub const AVX_XML: &str = concat!("<target version=\"1.0\">", core_sse_avx!(), "</target>");
+
+/// The target description of `X86_64Avx512`
+pub const AVX512_XML: &str = concat!(
+    "<target version=\"1.0\">",
+    core_sse_avx!(),
+    "<feature name=\"org.gnu.gdb.i386.avx512\">",
+    vec128!(),
+    "<vector id=\"v2ui128\" type=\"uint128\" count=\"2\"/>",
+    numbered!("xmm", "", 128, "vec128"; 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31),
+    numbered!("ymm", "h", 128, "uint128"; 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31),
+    numbered!("k", "", 64, "uint64"; 0 1 2 3 4 5 6 7),
+    numbered!("zmm", "h", 256, "v2ui128";
+        0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31),
+    "</feature>",
+    "</target>",
+);
+
+/// Copy the x87 and SSE registers of an `XSave` into the core registers
+///
+/// The general purpose and segment registers are left untouched. The
+/// registers of an absent component are reported in their initial
+/// configuration.
+pub fn read_core_regs(xsave: &XSave, regs: &mut X86_64CoreRegs) {
+    let legacy = match xsave.header.xstate_bv.contains(XStateBv::X87) {
+        true => &xsave.legacy,
+        false => &XSaveLegacy::DEFAULT,
+    };
+
+    for (st, field) in regs.st.iter_mut().zip(legacy.mm.iter()) {
+        *st = field.mm.0;
+    }
+
+    regs.fpu.fctrl = u32::from(legacy.fcw.bits);
+    regs.fpu.fstat = u32::from(legacy.fsw.bits);
+    regs.fpu.ftag = u32::from(ftw_expand(legacy));
+    regs.fpu.fiseg = (legacy.fip >> 32) as u32;
+    regs.fpu.fioff = legacy.fip as u32;
+    regs.fpu.foseg = (legacy.fdp >> 32) as u32;
+    regs.fpu.fooff = legacy.fdp as u32;
+    regs.fpu.fop = u32::from(legacy.fop);
+
+    for (xmm, (_, value)) in regs.xmm.iter_mut().zip(xsave.xmm_regs()) {
+        *xmm = value;
+    }
+
+    regs.mxcsr = xsave.legacy.mxcsr.bits;
+}
+
+/// Copy the x87 and SSE registers of the core registers into an `XSave`
+///
+/// Both components are marked present. This fails with
+/// `XSaveError::MxCsrReserved`, leaving the buffer unchanged, if MXCSR has
+/// reserved bits set.
+pub fn write_core_regs(xsave: &mut XSave, regs: &X86_64CoreRegs) -> Result<(), XSaveError> {
+    xsave.legacy.set_mxcsr_checked(MxCsr { bits: regs.mxcsr })?;
+
+    let legacy = &mut xsave.legacy;
+    for (field, st) in legacy.mm.iter_mut().zip(regs.st.iter()) {
+        field.mm = Mm(*st);
+    }
+
+    legacy.fcw.bits = regs.fpu.fctrl as u16;
+    legacy.fsw.bits = regs.fpu.fstat as u16;
+    legacy.ftw = ftw_abridge(regs.fpu.ftag as u16);
+    legacy.fip = u64::from(regs.fpu.fioff) | u64::from(regs.fpu.fiseg) << 32;
+    legacy.fdp = u64::from(regs.fpu.fooff) | u64::from(regs.fpu.foseg) << 32;
+    legacy.fop = regs.fpu.fop as u16 & 0x7FF;
+
+    for (xmm, value) in legacy.xmm.iter_mut().zip(regs.xmm.iter()) {
+        *xmm = Xmm(value.to_le_bytes());
+    }
+
+    xsave.header.xstate_bv |= XStateBv::X87 | XStateBv::SSE;
+    Ok(())
+}
+
+/// Check that every component has room in the buffer
+fn check_room(xsave: &XSave, components: &[Component]) -> Result<(), XSaveError> {
+    let missing = components
+        .iter()
+        .filter(|c| xsave.component_range(**c).is_none())
+        .fold(XStateBv::empty(), |acc, c| acc | c.bit());
+
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(XSaveError::UnsupportedComponents(missing)),
+    }
+}
+
+/// The data of a component with room in the buffer, marking it present
+fn component_mut(xsave: &mut XSave, component: Component) -> &mut [u8] {
+    if !xsave.header.xstate_bv.contains(component.bit()) {
+        let _ = xsave.enable(component);
+    }
+
+    match xsave.component_range(component) {
+        Some((start, end)) => &mut xsave.as_bytes_mut()[start..end],
+        None => &mut [],
+    }
+}
+
+/// Write the bytes of a register
+fn write_bytes(write_byte: &mut impl FnMut(Option<u8>), bytes: &[u8]) {
+    bytes.iter().for_each(|b| write_byte(Some(*b)));
+}
+
+/// Serialize the core registers up to and including MXCSR
+fn serialize_core(core: &X86_64CoreRegs, write_byte: &mut impl FnMut(Option<u8>)) {
+    let mut len = 0;
+    core.gdb_serialize(|byte| {
+        if len < CORE_SIZE {
+            write_byte(byte);
+        }
+
+        len += 1;
+    });
+}
+
+/// Split a slice into chunks of `N` bytes
+fn chunks<const N: usize>(bytes: &[u8]) -> impl Iterator<Item = [u8; N]> + '_ {
+    bytes.chunks_exact(N).map(|c| c.try_into().unwrap())
+}
+
+/// The register file of `X86_64Avx`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AvxRegs {
+    /// The general purpose, x87 and SSE registers
+    pub core: X86_64CoreRegs,
+
+    /// The upper halves of YMM0 through YMM15
+    pub ymmh: [u128; 16],
+}
+
+impl AvxRegs {
+    /// Copy the x87, SSE and AVX registers from an `XSave`
+    pub fn read(&mut self, xsave: &XSave) {
+        read_core_regs(xsave, &mut self.core);
+
+        for (ymmh, (_, [_, high])) in self.ymmh.iter_mut().zip(xsave.ymm_regs()) {
+            *ymmh = high;
+        }
+    }
+
+    /// Copy the x87, SSE and AVX registers into an `XSave`
+    ///
+    /// This fails, leaving the buffer unchanged, if MXCSR is invalid or the
+    /// buffer has no room for the AVX state.
+    pub fn write(&self, xsave: &mut XSave) -> Result<(), XSaveError> {
+        check_room(xsave, &[Component::Avx])?;
+        write_core_regs(xsave, &self.core)?;
+
+        let avx = component_mut(xsave, Component::Avx);
+        for (dst, ymmh) in avx.chunks_exact_mut(16).zip(self.ymmh.iter()) {
+            dst.copy_from_slice(&ymmh.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+impl Registers for AvxRegs {
+    type ProgramCounter = u64;
+
+    fn pc(&self) -> Self::ProgramCounter {
+        self.core.rip
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        serialize_core(&self.core, &mut write_byte);
+
+        for ymmh in &self.ymmh {
+            write_bytes(&mut write_byte, &ymmh.to_le_bytes());
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let ymmh = bytes.get(CORE_SIZE..CORE_SIZE + 0x100).ok_or(())?;
+        self.core.gdb_deserialize(&bytes[..CORE_SIZE])?;
+
+        for (reg, chunk) in self.ymmh.iter_mut().zip(chunks(ymmh)) {
+            *reg = u128::from_le_bytes(chunk);
+        }
+
+        Ok(())
+    }
+}
+
+/// The register file of `X86_64Avx512`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Avx512Regs {
+    /// The general purpose, x87, SSE and AVX registers
+    pub avx: AvxRegs,
+
+    /// XMM16 through XMM31
+    pub xmm: [u128; 16],
+
+    /// The upper halves of YMM16 through YMM31
+    pub ymmh: [u128; 16],
+
+    /// The opmask registers K0 through K7
+    pub k: [u64; 8],
+
+    /// The upper halves of ZMM0 through ZMM31, as two 128-bit lanes
+    pub zmmh: [[u128; 2]; 32],
+}
+
+impl Avx512Regs {
+    /// Copy the x87, SSE, AVX and AVX-512 registers from an `XSave`
+    pub fn read(&mut self, xsave: &XSave) {
+        self.avx.read(xsave);
+
+        for (i, (_, [a, b, c, d])) in xsave.zmm_regs().enumerate() {
+            if i >= 16 {
+                self.xmm[i - 16] = a;
+                self.ymmh[i - 16] = b;
+            }
+
+            self.zmmh[i] = [c, d];
+        }
+
+        for (k, (_, value)) in self.k.iter_mut().zip(xsave.k_regs()) {
+            *k = value;
+        }
+    }
+
+    /// Copy the x87, SSE, AVX and AVX-512 registers into an `XSave`
+    ///
+    /// This fails, leaving the buffer unchanged, if MXCSR is invalid or the
+    /// buffer has no room for the AVX or AVX-512 state.
+    pub fn write(&self, xsave: &mut XSave) -> Result<(), XSaveError> {
+        check_room(
+            xsave,
+            &[
+                Component::Avx512Opmask,
+                Component::Avx512ZmmHi256,
+                Component::Avx512Hi16Zmm,
+            ],
+        )?;
+        self.avx.write(xsave)?;
+
+        let opmask = component_mut(xsave, Component::Avx512Opmask);
+        for (dst, k) in opmask.chunks_exact_mut(8).zip(self.k.iter()) {
+            dst.copy_from_slice(&k.to_le_bytes());
+        }
+
+        let hi256 = component_mut(xsave, Component::Avx512ZmmHi256);
+        for (dst, [c, d]) in hi256.chunks_exact_mut(32).zip(self.zmmh.iter()) {
+            dst[..16].copy_from_slice(&c.to_le_bytes());
+            dst[16..].copy_from_slice(&d.to_le_bytes());
+        }
+
+        let hi16 = component_mut(xsave, Component::Avx512Hi16Zmm);
+        for (i, dst) in hi16.chunks_exact_mut(64).enumerate() {
+            let [c, d] = self.zmmh[i + 16];
+            for (lane, value) in dst
+                .chunks_exact_mut(16)
+                .zip([self.xmm[i], self.ymmh[i], c, d])
+            {
+                lane.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Registers for Avx512Regs {
+    type ProgramCounter = u64;
+
+    fn pc(&self) -> Self::ProgramCounter {
+        self.avx.core.rip
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        self.avx.gdb_serialize(&mut write_byte);
+
+        for reg in self.xmm.iter().chain(self.ymmh.iter()) {
+            write_bytes(&mut write_byte, &reg.to_le_bytes());
+        }
+
+        for k in &self.k {
+            write_bytes(&mut write_byte, &k.to_le_bytes());
+        }
+
+        for lane in self.zmmh.iter().flatten() {
+            write_bytes(&mut write_byte, &lane.to_le_bytes());
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let start = CORE_SIZE + 0x100;
+        let rest = bytes.get(start..start + 0x200 + 0x40 + 0x400).ok_or(())?;
+        self.avx.gdb_deserialize(&bytes[..start])?;
+
+        let (vec, rest) = rest.split_at(0x200);
+        let (k, zmmh) = rest.split_at(0x40);
+
+        let regs = self.xmm.iter_mut().chain(self.ymmh.iter_mut());
+        for (reg, chunk) in regs.zip(chunks(vec)) {
+            *reg = u128::from_le_bytes(chunk);
+        }
+
+        for (reg, chunk) in self.k.iter_mut().zip(chunks(k)) {
+            *reg = u64::from_le_bytes(chunk);
+        }
+
+        for (reg, chunk) in self.zmmh.iter_mut().flatten().zip(chunks(zmmh)) {
+            *reg = u128::from_le_bytes(chunk);
+        }
+
+        Ok(())
+    }
+}
+
+/// Implements `Arch` for 64-bit x86 with AVX
+///
+/// Single registers are accessed by the numbering of `X86_64CoreRegId`,
+/// so the AVX registers are only transferred with the whole register file.
+pub enum X86_64Avx {}
+
+impl Arch for X86_64Avx {
+    type Usize = u64;
+    type Registers = AvxRegs;
+    type RegId = X86_64CoreRegId;
+    type BreakpointKind = usize;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(AVX_XML)
+    }
+
+    #[inline(always)]
+    fn single_step_gdb_behavior() -> SingleStepGdbBehavior {
+        SingleStepGdbBehavior::Required
+    }
+}
+
+/// Implements `Arch` for 64-bit x86 with AVX-512
+///
+/// Single registers are accessed by the numbering of `X86_64CoreRegId`,
+/// so the AVX and AVX-512 registers are only transferred with the whole
+/// register file.
+pub enum X86_64Avx512 {}
+
+impl Arch for X86_64Avx512 {
+    type Usize = u64;
+    type Registers = Avx512Regs;
+    type RegId = X86_64CoreRegId;
+    type BreakpointKind = usize;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(AVX512_XML)
+    }
+
+    #[inline(always)]
+    fn single_step_gdb_behavior() -> SingleStepGdbBehavior {
+        SingleStepGdbBehavior::Required
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.fip = 0x1234_5678_9ABC;
+        xsave.legacy.xmm[3] = Xmm(9u128.to_le_bytes());
+
+        let mut regs = X86_64CoreRegs::default();
+        read_core_regs(&xsave, &mut regs);
+        assert_eq!(regs.fpu.fctrl, 0x37F);
+        assert_eq!(regs.fpu.ftag, 0xFFFF);
+        assert_eq!((regs.fpu.fiseg, regs.fpu.fioff), (0x1234, 0x5678_9ABC));
+        assert_eq!(regs.xmm[3], 9);
+
+        let mut copy = XSave::DEFAULT;
+        write_core_regs(&mut copy, &regs).unwrap();
+        assert_eq!(copy.legacy.fip, xsave.legacy.fip);
+        assert_eq!(copy.legacy.xmm[3].0, xsave.legacy.xmm[3].0);
+        assert!(copy
+            .header
+            .xstate_bv
+            .contains(XStateBv::X87 | XStateBv::SSE));
+
+        regs.mxcsr = !0;
+        assert!(matches!(
+            write_core_regs(&mut copy, &regs),
+            Err(XSaveError::MxCsrReserved(_))
+        ));
+    }
+
+    #[test]
+    fn avx512() {
+        let mut regs = Avx512Regs::default();
+        regs.avx.core.mxcsr = MxCsr::DEFAULT.bits;
+        regs.avx.core.fpu.fctrl = 0x37F;
+        regs.avx.core.fpu.ftag = 0xFFFF;
+        regs.avx.ymmh[1] = 1;
+        regs.xmm[2] = 2;
+        regs.ymmh[3] = 3;
+        regs.k[4] = 4;
+        regs.zmmh[5] = [5, 6];
+        regs.zmmh[31] = [7, 8];
+
+        let mut xsave = XSave::DEFAULT;
+        regs.write(&mut xsave).unwrap();
+        assert_eq!(xsave.ymm_regs().nth(1).unwrap().1, [0, 1]);
+        assert_eq!(xsave.zmm_regs().nth(18).unwrap().1, [2, 0, 0, 0]);
+        assert_eq!(xsave.zmm_regs().nth(19).unwrap().1, [0, 3, 0, 0]);
+        assert_eq!(xsave.zmm_regs().nth(5).unwrap().1, [0, 0, 5, 6]);
+        assert_eq!(xsave.zmm_regs().nth(31).unwrap().1, [0, 0, 7, 8]);
+        assert_eq!(xsave.k_regs().nth(4).unwrap().1, 4);
+
+        let mut copy = Avx512Regs::default();
+        copy.read(&xsave);
+        assert_eq!(copy, regs);
+    }
+
+    #[test]
+    fn serialize() {
+        let mut regs = Avx512Regs::default();
+        regs.avx.core.rip = 0x1000;
+        regs.avx.ymmh[15] = 1;
+        regs.k[7] = 2;
+        regs.zmmh[31] = [3, 4];
+
+        let mut bytes = [0; 0x218 + 0x100 + 0x200 + 0x40 + 0x400];
+        let mut len = 0;
+        regs.gdb_serialize(|byte| {
+            bytes[len] = byte.unwrap();
+            len += 1;
+        });
+        assert_eq!(len, bytes.len());
+
+        let mut copy = Avx512Regs::default();
+        copy.gdb_deserialize(&bytes).unwrap();
+        assert_eq!(copy, regs);
+        assert_eq!(copy.gdb_deserialize(&bytes[..len - 1]), Err(()));
+
+        // Every register of the description is serialized, in order.
+        let count = AVX512_XML.matches("<reg ").count();
+        assert_eq!(count, 57 + 16 + 16 + 16 + 8 + 32);
+        assert!(AVX_XML.ends_with(
+            "<reg name=\"ymm15h\" bitsize=\"128\" type=\"uint128\"/></feature></target>"
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! SEV-ES Guest-Hypervisor Communication Block (GHCB)
+//!
+//! Under SEV-ES, the hypervisor cannot read the guest's registers, so the
+//! `#VC` handler passes the ones needed to emulate an exit through the
+//! GHCB, marking each as valid in a bitmap. An exit involving extended
+//! state, such as CPUID leaf 0xD, needs XCR0 and IA32_XSS. Where the
+//! hypervisor must see register data, such as for emulated SSE accesses,
+//! the selected components can be carried in the shared buffer in the
+//! packed format of `XSave::pack_into()`.
+//!
+//! See the GHCB specification (AMD publication 56421).
+
+use super::*;
+
+/// The size of the GHCB page
+pub const GHCB_SIZE: usize = 4096;
+
+/// The offset of `xss` in the save area
+pub const XSS: usize = 0x140;
+
+/// The offset of `xcr0` in the save area
+pub const XCR0: usize = 0x3E8;
+
+/// The offset of the valid bitmap in the save area
+pub const VALID_BITMAP: usize = 0x3F0;
+
+/// The offset of the shared buffer
+pub const SHARED_BUFFER: usize = 0x800;
+
+/// The size of the shared buffer
+pub const SHARED_BUFFER_SIZE: usize = 2032;
+
+/// A GHCB page
+#[repr(C, align(4096))]
+#[derive(Copy, Clone, Debug)]
+pub struct Ghcb(pub [u8; GHCB_SIZE]);
+
+impl Default for Ghcb {
+    #[inline]
+    fn default() -> Self {
+        Self([0; GHCB_SIZE])
+    }
+}
+
+impl Ghcb {
+    /// Read a save area field, or `None` if it is not marked valid
+    #[inline]
+    fn field(&self, offset: usize) -> Option<u64> {
+        let qword = offset / 8;
+        if self.0[VALID_BITMAP + qword / 8] & (1 << (qword % 8)) == 0 {
+            return None;
+        }
+
+        let mut value = [0; 8];
+        value.copy_from_slice(&self.0[offset..offset + 8]);
+        Some(u64::from_le_bytes(value))
+    }
+
+    /// Write a save area field and mark it valid
+    #[inline]
+    fn set_field(&mut self, offset: usize, value: u64) {
+        let qword = offset / 8;
+        self.0[VALID_BITMAP + qword / 8] |= 1 << (qword % 8);
+        self.0[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The guest's XCR0, if marked valid
+    #[inline]
+    pub fn xcr0(&self) -> Option<XStateBv> {
+        self.field(XCR0).map(|bits| XStateBv { bits })
+    }
+
+    /// Set the guest's XCR0 and mark it valid
+    #[inline]
+    pub fn set_xcr0(&mut self, xcr0: XStateBv) {
+        self.set_field(XCR0, xcr0.bits)
+    }
+
+    /// The guest's IA32_XSS, if marked valid
+    #[inline]
+    pub fn xss(&self) -> Option<XStateBv> {
+        self.field(XSS).map(|bits| XStateBv { bits })
+    }
+
+    /// Set the guest's IA32_XSS and mark it valid
+    #[inline]
+    pub fn set_xss(&mut self, xss: XStateBv) {
+        self.set_field(XSS, xss.bits)
+    }
+
+    /// Copy the selected components into the shared buffer
+    ///
+    /// The buffer's guest physical address, `SHARED_BUFFER` bytes into the
+    /// GHCB, is to be passed in `sw_scratch`. This fails as for
+    /// `XSave::pack_into()`, including when the components do not fit.
+    #[inline]
+    pub fn set_extended_state(
+        &mut self,
+        xsave: &XSave,
+        mask: XStateBv,
+    ) -> Result<usize, XSaveError> {
+        let shared = &mut self.0[SHARED_BUFFER..][..SHARED_BUFFER_SIZE];
+        xsave.pack_into(mask, shared)
+    }
+
+    /// Read the components in the shared buffer, returned by the hypervisor
+    ///
+    /// The components must be enabled in `xcr0`, as for `XSave::unpack()`.
+    #[inline]
+    pub fn extended_state(&self, xcr0: XStateBv) -> Result<XSave, XSaveError> {
+        let shared = &self.0[SHARED_BUFFER..][..SHARED_BUFFER_SIZE];
+        XSave::unpack(pack::prefix(shared)?, xcr0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let mut ghcb = Ghcb::default();
+        assert_eq!(ghcb.xcr0(), None);
+
+        ghcb.set_xcr0(XStateBv::X87 | XStateBv::SSE | XStateBv::AVX);
+        ghcb.set_xss(XStateBv::PT);
+        assert_eq!(
+            ghcb.xcr0(),
+            Some(XStateBv::X87 | XStateBv::SSE | XStateBv::AVX)
+        );
+        assert_eq!(ghcb.xss(), Some(XStateBv::PT));
+
+        // Bit 125 for xcr0 and bit 40 for xss
+        assert_eq!(ghcb.0[VALID_BITMAP + 15], 1 << 5);
+        assert_eq!(ghcb.0[VALID_BITMAP + 5], 1 << 0);
+    }
+
+    #[test]
+    fn extended() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.legacy.xmm[1].0[0] = 0xAA;
+        xsave.as_bytes_mut()[576 + 16] = 0x55;
+
+        let mut ghcb = Ghcb::default();
+        let mask = XStateBv::SSE | XStateBv::AVX;
+        ghcb.set_extended_state(&xsave, mask).unwrap();
+
+        let back = ghcb.extended_state(XStateBv::all()).unwrap();
+        assert_eq!(back.header.xstate_bv, mask);
+        assert_eq!(back.legacy.xmm[1].0[0], 0xAA);
+        assert_eq!(back.as_bytes()[576 + 16], 0x55);
+
+        xsave.enable_avx512().unwrap();
+        assert_eq!(
+            ghcb.set_extended_state(
+                &xsave,
+                XStateBv::all() - XStateBv::AMX_TILEDATA - XStateBv::PT
+            ),
+            Err(XSaveError::InvalidLength(9 + 152 + 264 + 256 + 1600))
+        );
+    }
+}
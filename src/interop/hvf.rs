@@ -0,0 +1,82 @@
+//! Apple Hypervisor.framework floating point state
+//!
+//! On x86, `hv_vcpu_read_fpstate` and `hv_vcpu_write_fpstate` transfer the
+//! floating point and SIMD state of a virtual CPU through a caller-supplied
+//! buffer. A buffer of `HV_FXSAVE_SIZE` bytes holds an FXSAVE image; a
+//! larger one holds an XSAVE area sized for the enabled components.
+
+use super::*;
+
+/// The size of an FXSAVE image
+pub const HV_FXSAVE_SIZE: usize = size_of::<XSaveLegacy>();
+
+/// Parse the buffer filled by `hv_vcpu_read_fpstate`
+///
+/// An FXSAVE image is checked as by `XSave::from_bytes_strict()` and has
+/// X87 and SSE marked present. An XSAVE area is checked as by
+/// `interop::whp::from_whp_xsave_state()`.
+pub fn from_hvf_fpstate(bytes: &[u8]) -> Result<XSave, XSaveError> {
+    if bytes.len() != HV_FXSAVE_SIZE {
+        return read_sized(bytes);
+    }
+
+    let mut xsave = XSave::fxsave_default();
+    xsave.legacy.as_bytes_mut().copy_from_slice(bytes);
+    XSave::from_bytes_strict(xsave.as_bytes())
+}
+
+/// Fill a buffer for `hv_vcpu_write_fpstate`, returning its length
+///
+/// For a buffer of `HV_FXSAVE_SIZE` bytes only the legacy area is written,
+/// which fails with `XSaveError::UnsupportedComponents` if any extended
+/// component is present. Otherwise the area is written up to the end of
+/// its last component, and the buffer must be at least that long.
+pub fn to_hvf_fpstate(xsave: &XSave, buf: &mut [u8]) -> Result<usize, XSaveError> {
+    if buf.len() != HV_FXSAVE_SIZE {
+        return write_sized(xsave, buf);
+    }
+
+    let extended = xsave.header.xstate_bv - (XStateBv::X87 | XStateBv::SSE);
+    if !extended.is_empty() {
+        return Err(XSaveError::UnsupportedComponents(extended));
+    }
+
+    buf.copy_from_slice(xsave.legacy.as_bytes());
+    Ok(HV_FXSAVE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fxsave() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[2].0[3] = 0xAA;
+
+        let mut buf = [0; HV_FXSAVE_SIZE];
+        assert_eq!(to_hvf_fpstate(&xsave, &mut buf), Ok(HV_FXSAVE_SIZE));
+        assert_eq!(buf[160 + 2 * 16 + 3], 0xAA);
+
+        let back = from_hvf_fpstate(&buf).unwrap();
+        assert_eq!(back.as_bytes()[..], xsave.as_bytes()[..]);
+
+        xsave.enable(Component::Avx).unwrap();
+        assert_eq!(
+            to_hvf_fpstate(&xsave, &mut buf),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX))
+        );
+    }
+
+    #[test]
+    fn xsave() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[600] = 0x55;
+
+        let mut buf = [0; 1024];
+        assert_eq!(to_hvf_fpstate(&xsave, &mut buf), Ok(832));
+        let back = from_hvf_fpstate(&buf[..832]).unwrap();
+        assert_eq!(back.as_bytes()[..], xsave.as_bytes()[..]);
+    }
+}
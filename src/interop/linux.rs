@@ -0,0 +1,448 @@
+//! Linux signal frame and ptrace structures
+//!
+//! See `arch/x86/include/uapi/asm/sigcontext.h` and
+//! `arch/x86/include/asm/user_32.h` in the Linux kernel.
+
+use super::*;
+
+/// The `magic` value of an `FpState32` carrying an FXSAVE image
+pub const X86_FXSR_MAGIC: u16 = 0x0000;
+
+/// The `magic` value of an `FpState32` carrying only FSAVE data
+pub const X86_FSAVE_MAGIC: u16 = 0xFFFF;
+
+/// The offset of the XCR0 value in a ptrace `NT_X86_XSTATE` buffer
+///
+/// The kernel stores the user features it supports in the first eight of
+/// the software-available bytes of the legacy area, and zeroes the rest.
+pub const USER_XSTATE_XCR0_OFFSET: usize = 464;
+
+/// The initial FPU state Linux gives new tasks, as read with ptrace
+///
+/// The kernel builds `init_fpstate` by restoring every component to its
+/// initial configuration and reading the legacy area back with `fxsave`.
+/// A task which has not touched the FPU reports that state through
+/// `NT_X86_XSTATE` as follows:
+///
+///  * `xstate_bv` is empty, with X87 and SSE absent.
+///  * FCW and MXCSR hold their defaults regardless, since the kernel copies
+///    them from `init_fpstate` rather than zeroing absent components.
+///  * MXCSR_MASK is the value `fxsave` stored, which is zero on CPUs
+///    predating it. `mxcsr_mask` gives it.
+///  * `xfeatures`, the user features the kernel enabled in XCR0, is stored
+///    at `USER_XSTATE_XCR0_OFFSET`.
+///  * Everything else, including `xcomp_bv`, is zero.
+pub fn init_fpstate(xfeatures: XStateBv, mxcsr_mask: MxCsr) -> XSave {
+    let mut xsave = XSave::DEFAULT;
+    xsave.legacy.mxcsr_mask = mxcsr_mask;
+    xsave.as_bytes_mut()[USER_XSTATE_XCR0_OFFSET..][..8]
+        .copy_from_slice(&xfeatures.bits.to_le_bytes());
+
+    xsave
+}
+
+/// The i386 signal frame FPU state (`struct _fpstate_32`)
+///
+/// This is the 112-byte FSAVE format environment followed by an FXSAVE
+/// image. When `magic` is `X86_FXSR_MAGIC`, the kernel fills the FSAVE
+/// prefix from the FXSAVE image and ignores the prefix on `sigreturn`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FpState32 {
+    pub cw: u32,
+    pub sw: u32,
+    pub tag: u32,
+    pub ipoff: u32,
+    pub cssel: u32,
+    pub dataoff: u32,
+    pub datasel: u32,
+    pub st: [FpReg; 8],
+    pub status: u16,
+    pub magic: u16,
+    pub fxsave: XSaveLegacy,
+}
+
+impl Default for FpState32 {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSaveLegacy::DEFAULT)
+    }
+}
+
+impl From<&XSaveLegacy> for FpState32 {
+    /// Build the signal frame the way the kernel does for FXSR CPUs
+    fn from(legacy: &XSaveLegacy) -> Self {
+        let mut st = [FpReg::default(); 8];
+        for (reg, mm) in st.iter_mut().zip(legacy.mm.iter()) {
+            *reg = mm.mm.into();
+        }
+
+        Self {
+            cw: u32::from(legacy.fcw.bits) | 0xFFFF_0000,
+            sw: u32::from(legacy.fsw.bits) | 0xFFFF_0000,
+            tag: u32::from(ftw_expand(legacy)) | 0xFFFF_0000,
+            ipoff: legacy.fip as u32,
+            cssel: u32::from((legacy.fip >> 32) as u16) | u32::from(legacy.fop) << 16,
+            dataoff: legacy.fdp as u32,
+            datasel: u32::from((legacy.fdp >> 32) as u16) | 0xFFFF_0000,
+            st,
+            status: legacy.fsw.bits,
+            magic: X86_FXSR_MAGIC,
+            fxsave: *legacy,
+        }
+    }
+}
+
+impl From<&FpState32> for XSaveLegacy {
+    /// Extract the state the way the kernel does on `sigreturn`
+    ///
+    /// If the frame carries an FXSAVE image, it is used as is. Otherwise,
+    /// the FSAVE prefix is converted and the SSE state is left at its
+    /// defaults.
+    fn from(fpstate: &FpState32) -> Self {
+        if fpstate.magic == X86_FXSR_MAGIC {
+            return fpstate.fxsave;
+        }
+
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fcw.bits = fpstate.cw as u16;
+        legacy.fsw.bits = fpstate.sw as u16;
+        legacy.ftw = ftw_abridge(fpstate.tag as u16);
+        legacy.fop = (fpstate.cssel >> 16) as u16;
+        legacy.fip = u64::from(fpstate.ipoff) | u64::from(fpstate.cssel as u16) << 32;
+        legacy.fdp = u64::from(fpstate.dataoff) | u64::from(fpstate.datasel as u16) << 32;
+        for (mm, reg) in legacy.mm.iter_mut().zip(fpstate.st.iter()) {
+            mm.mm = (*reg).into();
+        }
+
+        legacy
+    }
+}
+
+/// The i386 signal frame extended state (`struct _xstate` on i386)
+///
+/// This is an `FpState32` followed by the XSAVE header and the upper
+/// halves of the YMM registers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct XState32 {
+    pub fpstate: FpState32,
+    pub xstate_hdr: XSaveHeader,
+    pub ymmh: [Xmm; 16],
+}
+
+impl XState32 {
+    /// The offset of the YMM upper halves within an `XSave`
+    const YMMH: usize = 576;
+}
+
+impl Default for XState32 {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSave::DEFAULT)
+    }
+}
+
+impl From<&XSave> for XState32 {
+    fn from(xsave: &XSave) -> Self {
+        let mut ymmh = [Xmm::DEFAULT; 16];
+        let bytes = &xsave.as_bytes()[Self::YMMH..];
+        for (xmm, chunk) in ymmh.iter_mut().zip(bytes.chunks_exact(16)) {
+            xmm.0.copy_from_slice(chunk);
+        }
+
+        Self {
+            fpstate: FpState32::from(&xsave.legacy),
+            xstate_hdr: xsave.header,
+            ymmh,
+        }
+    }
+}
+
+impl From<&XState32> for XSave {
+    fn from(xstate: &XState32) -> Self {
+        let mut xsave = XSave::DEFAULT;
+        xsave.legacy = XSaveLegacy::from(&xstate.fpstate);
+        xsave.header = xstate.xstate_hdr;
+
+        let bytes = &mut xsave.as_bytes_mut()[XState32::YMMH..];
+        for (chunk, xmm) in bytes.chunks_exact_mut(16).zip(xstate.ymmh.iter()) {
+            chunk.copy_from_slice(&xmm.0);
+        }
+
+        xsave
+    }
+}
+
+/// The i386 ptrace FSAVE image (`struct user_i387_struct`)
+///
+/// This is returned by `PTRACE_GETFPREGS` on i386 and is the FSAVE prefix
+/// of an `FpState32`: the environment in 32-bit fields followed by the
+/// registers in the packed 80-bit format, in stack order.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct UserFpRegs {
+    pub cwd: u32,
+    pub swd: u32,
+    pub twd: u32,
+    pub fip: u32,
+    pub fcs: u32,
+    pub foo: u32,
+    pub fos: u32,
+    pub st_space: [FpReg; 8],
+}
+
+impl Default for UserFpRegs {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSaveLegacy::DEFAULT)
+    }
+}
+
+impl From<&XSaveLegacy> for UserFpRegs {
+    /// Convert the x87 state the way the kernel does for FXSR CPUs
+    fn from(legacy: &XSaveLegacy) -> Self {
+        let fpstate = FpState32::from(legacy);
+
+        Self {
+            cwd: fpstate.cw,
+            swd: fpstate.sw,
+            twd: fpstate.tag,
+            fip: fpstate.ipoff,
+            fcs: fpstate.cssel,
+            foo: fpstate.dataoff,
+            fos: fpstate.datasel,
+            st_space: fpstate.st,
+        }
+    }
+}
+
+impl From<&UserFpRegs> for XSaveLegacy {
+    /// Convert the x87 state, leaving the SSE state at its defaults
+    fn from(regs: &UserFpRegs) -> Self {
+        XSaveLegacy::from(&FpState32 {
+            cw: regs.cwd,
+            sw: regs.swd,
+            tag: regs.twd,
+            ipoff: regs.fip,
+            cssel: regs.fcs,
+            dataoff: regs.foo,
+            datasel: regs.fos,
+            st: regs.st_space,
+            status: regs.swd as u16,
+            magic: X86_FSAVE_MAGIC,
+            fxsave: XSaveLegacy::DEFAULT,
+        })
+    }
+}
+
+/// The i386 ptrace FXSAVE image (`struct user_fpxregs_struct`)
+///
+/// This is returned by `PTRACE_GETFPXREGS` and is the same 512 bytes as an
+/// FXSAVE image, split into 32-bit fields. Unlike the 64-bit image, the
+/// upper halves of the instruction and data pointers hold the FPU CS and
+/// DS selectors in `fcs` and `fos`, the upper 16 bits of which are
+/// reserved. Only eight XMM registers exist in 32-bit mode.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct UserFpxRegs {
+    pub cwd: u16,
+    pub swd: u16,
+    pub twd: u16,
+    pub fop: u16,
+    pub fip: u32,
+    pub fcs: u32,
+    pub foo: u32,
+    pub fos: u32,
+    pub mxcsr: u32,
+    pub mxcsr_mask: u32,
+    pub st_space: [MmField; 8],
+    pub xmm_space: [Xmm; 8],
+    pub padding: [u32; 56],
+}
+
+impl Default for UserFpxRegs {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSaveLegacy::DEFAULT)
+    }
+}
+
+impl From<&XSaveLegacy> for UserFpxRegs {
+    /// Split an FXSAVE image into the ptrace fields
+    ///
+    /// XMM8-15 are dropped, as are the upper 16 bits of each selector.
+    fn from(legacy: &XSaveLegacy) -> Self {
+        let mut xmm_space = [Xmm::DEFAULT; 8];
+        xmm_space.copy_from_slice(&legacy.xmm[..8]);
+
+        Self {
+            cwd: legacy.fcw.bits,
+            swd: legacy.fsw.bits,
+            twd: legacy.ftw.into(),
+            fop: legacy.fop,
+            fip: legacy.fip as u32,
+            fcs: u32::from((legacy.fip >> 32) as u16),
+            foo: legacy.fdp as u32,
+            fos: u32::from((legacy.fdp >> 32) as u16),
+            mxcsr: legacy.mxcsr.bits,
+            mxcsr_mask: legacy.mxcsr_mask.bits,
+            st_space: legacy.mm,
+            xmm_space,
+            padding: [0; 56],
+        }
+    }
+}
+
+impl From<&UserFpxRegs> for XSaveLegacy {
+    /// Reassemble an FXSAVE image from the ptrace fields
+    ///
+    /// XMM8-15 are zeroed. As in `PTRACE_SETFPXREGS`, reserved MXCSR bits
+    /// are cleared using the image's own MXCSR_MASK, where a mask of zero
+    /// stands for the architectural default of 0xFFBF.
+    fn from(regs: &UserFpxRegs) -> Self {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fcw.bits = regs.cwd;
+        legacy.fsw.bits = regs.swd;
+        legacy.ftw = regs.twd as u8;
+        legacy.fop = regs.fop;
+        legacy.fip = u64::from(regs.fip) | u64::from(regs.fcs as u16) << 32;
+        legacy.fdp = u64::from(regs.foo) | u64::from(regs.fos as u16) << 32;
+        legacy.mxcsr_mask = MxCsr::from_bits_truncate(regs.mxcsr_mask);
+        legacy.mxcsr = MxCsr::from_bits_truncate(regs.mxcsr & legacy.mxcsr_writable());
+        legacy.mm = regs.st_space;
+        legacy.xmm[..8].copy_from_slice(&regs.xmm_space);
+
+        legacy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    #[test]
+    fn size() {
+        assert_eq!(size_of::<FpState32>(), 112 + 512);
+        assert_eq!(size_of::<XState32>(), 112 + 512 + 64 + 256);
+        assert_eq!(size_of::<UserFpRegs>(), 108);
+        assert_eq!(size_of::<UserFpxRegs>(), size_of::<XSaveLegacy>());
+    }
+
+    #[test]
+    fn init() {
+        let xfeatures = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        let xsave = init_fpstate(xfeatures, MxCsr::from_bits_truncate(0xFFFF));
+
+        let bytes = xsave.as_bytes();
+        assert_eq!(&bytes[..2], &[0x7F, 0x03]);
+        assert_eq!(&bytes[24..32], &[0x80, 0x1F, 0, 0, 0xFF, 0xFF, 0, 0]);
+        assert_eq!(&bytes[464..472], &[7, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(bytes[472..].iter().all(|b| *b == 0));
+        assert!(bytes[2..24].iter().all(|b| *b == 0));
+        assert!(bytes[32..464].iter().all(|b| *b == 0));
+        assert!(xsave.header.xstate_bv.is_empty());
+        assert!(XSave::from_bytes_strict(bytes).is_ok());
+    }
+
+    #[test]
+    fn fpregs() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fsw.bits = 7 << 11;
+        legacy.ftw = 0b1000_0000;
+        legacy.fop = 0x5D9;
+        legacy.fip = 0x0023_0804_8000;
+        legacy.mm[0].mm = Mm::from(FpReg {
+            significand: [0, 0, 0, 0xC000],
+            exponent: 0x4000,
+        });
+
+        let regs = UserFpRegs::from(&legacy);
+        assert_eq!(regs.twd, 0xFFFF_3FFF);
+        assert_eq!(regs.fcs, 0x05D9_0023);
+        assert_eq!(regs.st_space[0].exponent, 0x4000);
+
+        let back = XSaveLegacy::from(&regs);
+        assert_eq!(back.fsw, legacy.fsw);
+        assert_eq!(back.ftw, legacy.ftw);
+        assert_eq!(back.fop, legacy.fop);
+        assert_eq!(back.fip, legacy.fip);
+        assert_eq!(back.mm[0].mm.0, legacy.mm[0].mm.0);
+    }
+
+    #[test]
+    fn fpxregs() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.ftw = 0x81;
+        legacy.fip = 0xFFFF_0023_DEAD_BEEF;
+        legacy.fdp = 0x002B_0000_1000;
+        legacy.xmm[7].0[0] = 0xAA;
+        legacy.xmm[8].0[0] = 0xBB;
+
+        let regs = UserFpxRegs::from(&legacy);
+        assert_eq!(regs.twd, 0x81);
+        assert_eq!(regs.fcs, 0x23);
+        assert_eq!(regs.fos, 0x2B);
+        assert_eq!(regs.mxcsr, 0x1F80);
+
+        let back = XSaveLegacy::from(&regs);
+        assert_eq!(back.fip, 0x0023_DEAD_BEEF);
+        assert_eq!(back.fdp, legacy.fdp);
+        assert_eq!(back.mxcsr, legacy.mxcsr);
+        assert_eq!(back.xmm[7].0, legacy.xmm[7].0);
+        assert_eq!(back.xmm[8].0, [0; 16]);
+
+        let mut regs = regs;
+        regs.mxcsr = 0x9F80;
+        regs.mxcsr_mask = 0;
+        let back = XSaveLegacy::from(&regs);
+        assert_eq!(back.mxcsr.bits, 0x9F80);
+        assert_eq!(back.mxcsr_mask.bits, 0);
+
+        regs.mxcsr |= 1 << 6;
+        assert_eq!(XSaveLegacy::from(&regs).mxcsr.bits, 0x9F80);
+    }
+
+    #[test]
+    fn fxsr() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fop = 0x123;
+        legacy.fip = 0x0023_DEAD_BEEF;
+        legacy.fdp = 0x002B_0000_1000;
+
+        let fpstate = FpState32::from(&legacy);
+        assert_eq!(fpstate.cw, 0xFFFF_037F);
+        assert_eq!(fpstate.tag, 0xFFFF_FFFF);
+        assert_eq!(fpstate.ipoff, 0xDEAD_BEEF);
+        assert_eq!(fpstate.cssel, 0x0123_0023);
+        assert_eq!(fpstate.datasel, 0xFFFF_002B);
+
+        let mut fsave = fpstate;
+        fsave.magic = X86_FSAVE_MAGIC;
+        fsave.fxsave = XSaveLegacy::DEFAULT;
+
+        for fpstate in [fpstate, fsave] {
+            let back = XSaveLegacy::from(&fpstate);
+            assert_eq!(back.fcw, legacy.fcw);
+            assert_eq!(back.ftw, legacy.ftw);
+            assert_eq!(back.fop, legacy.fop);
+            assert_eq!(back.fip, legacy.fip);
+            assert_eq!(back.fdp, legacy.fdp);
+        }
+    }
+
+    #[test]
+    fn xstate() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        xsave.as_bytes_mut()[XState32::YMMH + 16] = 0xAA;
+
+        let xstate = XState32::from(&xsave);
+        assert_eq!(xstate.ymmh[1].0[0], 0xAA);
+
+        let back = XSave::from(&xstate);
+        assert_eq!(back.header.xstate_bv, xsave.header.xstate_bv);
+        assert_eq!(back.as_bytes()[..], xsave.as_bytes()[..]);
+    }
+}
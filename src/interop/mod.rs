@@ -0,0 +1,188 @@
+//! Conversions to and from the extended state layouts of other systems
+//!
+//! Operating systems and hypervisors frequently expose the extended CPU
+//! state in formats which differ from the raw XSAVE layout. Each submodule
+//! provides the structures for one such system, along with conversions to
+//! and from the types in this crate.
+
+#[cfg(feature = "gdb")]
+pub mod gdb;
+pub mod ghcb;
+pub mod hvf;
+pub mod linux;
+pub mod whp;
+pub mod windows;
+pub mod xen;
+pub mod zircon;
+
+#[cfg(feature = "rustc_apfloat")]
+mod apfloat;
+#[cfg(feature = "x86_64")]
+mod control;
+#[cfg(feature = "raw-cpuid")]
+mod cpuid;
+
+use super::*;
+
+/// An x87 register in the packed 80-bit FSAVE format
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FpReg {
+    pub significand: [u16; 4],
+    pub exponent: u16,
+}
+
+impl From<Mm> for FpReg {
+    #[inline]
+    fn from(mm: Mm) -> Self {
+        let b = mm.0;
+        Self {
+            significand: [
+                u16::from_le_bytes([b[0], b[1]]),
+                u16::from_le_bytes([b[2], b[3]]),
+                u16::from_le_bytes([b[4], b[5]]),
+                u16::from_le_bytes([b[6], b[7]]),
+            ],
+            exponent: u16::from_le_bytes([b[8], b[9]]),
+        }
+    }
+}
+
+impl From<FpReg> for Mm {
+    #[inline]
+    fn from(reg: FpReg) -> Self {
+        let mut mm = Mm::DEFAULT;
+        for (i, word) in reg.significand.iter().enumerate() {
+            mm.0[i * 2..][..2].copy_from_slice(&word.to_le_bytes());
+        }
+        mm.0[8..].copy_from_slice(&reg.exponent.to_le_bytes());
+        mm
+    }
+}
+
+/// Parse an XSAVE area from a buffer sized for its components
+///
+/// Missing bytes read as zero. Present components which are cut off by the
+/// end of the buffer, or which do not fit in an `XSave`, are unsupported.
+fn read_sized(bytes: &[u8]) -> Result<XSave, XSaveError> {
+    if bytes.len() < component::EXTENDED {
+        return Err(XSaveError::InvalidLength(bytes.len()));
+    }
+
+    let mut area = [0; size_of::<XSave>()];
+    let len = bytes.len().min(area.len());
+    area[..len].copy_from_slice(&bytes[..len]);
+
+    let xsave = XSave::from_bytes_strict(&area)?;
+    let cut = Component::ALL
+        .iter()
+        .filter(|c| xsave.header.xstate_bv.contains(c.bit()))
+        .filter(|c| !matches!(c, Component::X87 | Component::Sse))
+        .filter(|c| {
+            xsave
+                .component_range(**c)
+                .map_or(true, |(_, end)| end > len)
+        })
+        .fold(XStateBv::empty(), |acc, c| acc | c.bit());
+
+    match cut.is_empty() {
+        true => Ok(xsave),
+        false => Err(XSaveError::UnsupportedComponents(cut)),
+    }
+}
+
+/// Write an XSAVE area to a buffer, up to the end of its last component
+fn write_sized(xsave: &XSave, buf: &mut [u8]) -> Result<usize, XSaveError> {
+    let compacted = xsave.header.xcomp_bv.contains(XCompBv::COMPACT);
+    let mask = match compacted {
+        true => XStateBv {
+            bits: xsave.header.xcomp_bv.bits & !XCompBv::COMPACT.bits,
+        },
+        false => xsave.header.xstate_bv,
+    };
+
+    let len = size_for(mask, compacted, &ComponentTable::ARCHITECTURAL).min(size_of::<XSave>());
+    let dst = buf.get_mut(..len).ok_or(XSaveError::InvalidLength(len))?;
+    dst.copy_from_slice(&xsave.as_bytes()[..len]);
+    Ok(len)
+}
+
+/// Tag values for an x87 register in the full FSAVE tag word
+const TAG_VALID: u16 = 0;
+const TAG_ZERO: u16 = 1;
+const TAG_SPECIAL: u16 = 2;
+const TAG_EMPTY: u16 = 3;
+
+/// Expand the abridged FXSAVE tag byte into the full FSAVE tag word
+///
+/// The full tag word classifies each physical register by examining the
+/// contents of the registers marked as valid in the abridged form.
+fn ftw_expand(legacy: &XSaveLegacy) -> u16 {
+    let top = (legacy.fsw.bits >> 11) & 7;
+    let mut ftw = 0;
+
+    for i in 0..8 {
+        let tag = if legacy.ftw & (1 << i) == 0 {
+            TAG_EMPTY
+        } else {
+            let reg = FpReg::from(legacy.mm[usize::from((i + 8 - top) & 7)].mm);
+            match reg.exponent & 0x7FFF {
+                0x7FFF => TAG_SPECIAL,
+                0x0000 if reg.significand == [0; 4] => TAG_ZERO,
+                0x0000 => TAG_SPECIAL,
+                _ if reg.significand[3] & 0x8000 != 0 => TAG_VALID,
+                _ => TAG_SPECIAL,
+            }
+        };
+
+        ftw |= tag << (i * 2);
+    }
+
+    ftw
+}
+
+/// Compress the full FSAVE tag word into the abridged FXSAVE tag byte
+fn ftw_abridge(ftw: u16) -> u8 {
+    let mut abridged = 0;
+
+    for i in 0..8 {
+        if (ftw >> (i * 2)) & 0b11 != TAG_EMPTY {
+            abridged |= 1 << i;
+        }
+    }
+
+    abridged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ftw() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        assert_eq!(ftw_expand(&legacy), 0xFFFF);
+
+        // Push 1.0 and 0.0, leaving TOP at 6.
+        legacy.fsw.bits = 6 << 11;
+        legacy.ftw = 0b1100_0000;
+        legacy.mm[1].mm = Mm::from(FpReg {
+            significand: [0, 0, 0, 0x8000],
+            exponent: 0x3FFF,
+        });
+
+        let ftw = ftw_expand(&legacy);
+        assert_eq!(ftw, 0x1FFF);
+        assert_eq!(ftw_abridge(ftw), legacy.ftw);
+    }
+
+    #[test]
+    fn fpreg() {
+        let reg = FpReg {
+            significand: [1, 2, 3, 0x8004],
+            exponent: 0x4005,
+        };
+
+        assert_eq!(FpReg::from(Mm::from(reg)), reg);
+    }
+}
@@ -0,0 +1,67 @@
+//! Windows Hypervisor Platform (WHP) XSAVE state
+//!
+//! `WHvGetVirtualProcessorXsaveState` writes the extended state of a
+//! virtual processor to a caller-supplied buffer as an XSAVE area, and
+//! `WHvSetVirtualProcessorXsaveState` reads one back. The buffer is sized
+//! for the components enabled in the partition, so it may be shorter or
+//! longer than an `XSave`. These conversions run the buffers through the
+//! same validation as any other area.
+
+use super::*;
+
+/// Parse the buffer written by `WHvGetVirtualProcessorXsaveState`
+///
+/// `bytes` is the part of the buffer which the hypervisor wrote. Missing
+/// bytes read as zero and the area is then checked as by
+/// `XSave::from_bytes_strict()`. Present components whose data is cut off
+/// by the end of the buffer, or which do not fit in an `XSave`, are
+/// reported as `XSaveError::UnsupportedComponents`.
+#[inline]
+pub fn from_whp_xsave_state(bytes: &[u8]) -> Result<XSave, XSaveError> {
+    read_sized(bytes)
+}
+
+/// Write an `XSave` for `WHvSetVirtualProcessorXsaveState`
+///
+/// Only the bytes up to the end of the last present component are
+/// written, and their number returned; pass it as the buffer size. This
+/// fails with `XSaveError::InvalidLength`, holding the length required, if
+/// the buffer is too short.
+#[inline]
+pub fn to_whp_xsave_state(xsave: &XSave, buf: &mut [u8]) -> Result<usize, XSaveError> {
+    write_sized(xsave, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whp() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576 + 255] = 0xAA;
+
+        let mut buf = [0xFF; 4096];
+        assert_eq!(to_whp_xsave_state(&xsave, &mut buf), Ok(832));
+        assert_eq!(
+            to_whp_xsave_state(&xsave, &mut buf[..831]),
+            Err(XSaveError::InvalidLength(832))
+        );
+
+        let back = from_whp_xsave_state(&buf[..832]).unwrap();
+        assert_eq!(back.as_bytes()[..], xsave.as_bytes()[..]);
+
+        assert_eq!(
+            from_whp_xsave_state(&buf[..800]).err(),
+            Some(XSaveError::UnsupportedComponents(XStateBv::AVX))
+        );
+        assert_eq!(
+            from_whp_xsave_state(&buf[..512]).err(),
+            Some(XSaveError::InvalidLength(512))
+        );
+
+        // The bytes of absent components are carried along unchecked
+        assert!(from_whp_xsave_state(&buf).is_ok());
+    }
+}
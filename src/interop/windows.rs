@@ -0,0 +1,58 @@
+//! Windows context structures
+
+use super::*;
+
+/// The size of the `ExtendedRegisters` area of a `WOW64_CONTEXT`
+pub const WOW64_MAXIMUM_SUPPORTED_EXTENSION: usize = 512;
+
+/// The `ExtendedRegisters` area of a `WOW64_CONTEXT`
+///
+/// This holds a 32-bit format FXSAVE image, which has the same layout as
+/// `XSaveLegacy`. Since it is embedded at an unaligned offset within the
+/// context, it is stored as plain bytes.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Wow64ExtendedRegisters(pub [u8; WOW64_MAXIMUM_SUPPORTED_EXTENSION]);
+
+impl Default for Wow64ExtendedRegisters {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSaveLegacy::DEFAULT)
+    }
+}
+
+impl From<&XSaveLegacy> for Wow64ExtendedRegisters {
+    #[inline]
+    fn from(legacy: &XSaveLegacy) -> Self {
+        Self(*legacy.as_bytes())
+    }
+}
+
+impl From<&Wow64ExtendedRegisters> for XSaveLegacy {
+    #[inline]
+    fn from(regs: &Wow64ExtendedRegisters) -> Self {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.as_bytes_mut().copy_from_slice(&regs.0);
+        legacy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wow64() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fip = 0x0023_1234_5678;
+        legacy.xmm[7].0[15] = 0xAA;
+
+        let regs = Wow64ExtendedRegisters::from(&legacy);
+        assert_eq!(regs.0[..2], 0x037Fu16.to_le_bytes());
+        assert_eq!(regs.0[8..14], [0x78, 0x56, 0x34, 0x12, 0x23, 0x00]);
+        assert_eq!(regs.0[160 + 7 * 16 + 15], 0xAA);
+
+        let back = XSaveLegacy::from(&regs);
+        assert_eq!(back.as_bytes()[..], legacy.as_bytes()[..]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Xen HVM save records
+//!
+//! Xen domain save images are a sequence of records, each a descriptor
+//! followed by a body. The extended state of each virtual CPU is held in a
+//! `CPU_XSAVE` record (`struct hvm_hw_cpu_xsave`), whose body is three
+//! component masks followed by a standard format XSAVE area, truncated
+//! after the last enabled component.
+//!
+//! See `xen/include/public/arch-x86/hvm/save.h` in Xen.
+
+use super::*;
+
+/// The typecode of a `CPU_XSAVE` record
+pub const CPU_XSAVE_CODE: u16 = 16;
+
+/// The size of a record descriptor (`struct hvm_save_descriptor`)
+pub const DESCRIPTOR_SIZE: usize = 8;
+
+/// The size of the masks before the XSAVE area in a `CPU_XSAVE` body
+const MASKS_SIZE: usize = 24;
+
+/// The contents of a `CPU_XSAVE` record
+#[derive(Copy, Clone, Debug)]
+pub struct CpuXsave {
+    /// The components supported for the domain
+    pub xfeature_mask: XStateBv,
+
+    /// The guest's XCR0
+    pub xcr0: XStateBv,
+
+    /// Every component the guest has ever enabled in XCR0
+    pub xcr0_accum: XStateBv,
+
+    /// The saved area
+    pub xsave: XSave,
+}
+
+/// Read a little-endian field of a record
+#[inline]
+fn field<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    let mut value = [0; N];
+    value.copy_from_slice(bytes.get(offset..offset + N)?);
+    Some(value)
+}
+
+impl CpuXsave {
+    /// Parse a `CPU_XSAVE` record, returning its instance (the vCPU index)
+    ///
+    /// `bytes` begins with the record's descriptor and may extend past the
+    /// record. A descriptor of another type is reported as
+    /// `XSaveError::InvalidMagic`, and the area is checked as by
+    /// `interop::whp::from_whp_xsave_state()`.
+    pub fn from_record(bytes: &[u8]) -> Result<(u16, Self), XSaveError> {
+        let short = XSaveError::InvalidLength(bytes.len());
+        let typecode = u16::from_le_bytes(field(bytes, 0).ok_or(short)?);
+        let instance = u16::from_le_bytes(field(bytes, 2).ok_or(short)?);
+        let length = u32::from_le_bytes(field(bytes, 4).ok_or(short)?) as usize;
+
+        if typecode != CPU_XSAVE_CODE {
+            return Err(XSaveError::InvalidMagic);
+        }
+
+        let body = bytes.get(DESCRIPTOR_SIZE..).and_then(|b| b.get(..length));
+        let body = body.filter(|b| b.len() >= MASKS_SIZE).ok_or(short)?;
+        let mask = |offset| XStateBv {
+            bits: u64::from_le_bytes(field(body, offset).unwrap_or_default()),
+        };
+
+        let record = Self {
+            xfeature_mask: mask(0),
+            xcr0: mask(8),
+            xcr0_accum: mask(16),
+            xsave: read_sized(&body[MASKS_SIZE..])?,
+        };
+
+        Ok((instance, record))
+    }
+
+    /// Emit a `CPU_XSAVE` record for a vCPU, returning its length
+    ///
+    /// The area is truncated after the last component in `xcr0_accum`, as
+    /// Xen does, and must be in the standard format. This fails with
+    /// `XSaveError::InvalidLength`, holding the length required, if the
+    /// buffer is too short.
+    pub fn to_record(&self, instance: u16, buf: &mut [u8]) -> Result<usize, XSaveError> {
+        if self.xsave.header.xcomp_bv.contains(XCompBv::COMPACT) {
+            return Err(XSaveError::InvalidMask(XStateBv::empty()));
+        }
+
+        let area = size_for(self.xcr0_accum, false, &ComponentTable::ARCHITECTURAL)
+            .min(size_of::<XSave>());
+        let length = MASKS_SIZE + area;
+        let total = DESCRIPTOR_SIZE + length;
+        let buf = buf
+            .get_mut(..total)
+            .ok_or(XSaveError::InvalidLength(total))?;
+
+        buf[0..2].copy_from_slice(&CPU_XSAVE_CODE.to_le_bytes());
+        buf[2..4].copy_from_slice(&instance.to_le_bytes());
+        buf[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        buf[8..16].copy_from_slice(&self.xfeature_mask.bits.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.xcr0.bits.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.xcr0_accum.bits.to_le_bytes());
+        buf[32..].copy_from_slice(&self.xsave.as_bytes()[..area]);
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576 + 17] = 0xAA;
+
+        let avx = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        let record = CpuXsave {
+            xfeature_mask: avx | XStateBv::AVX512,
+            xcr0: avx,
+            xcr0_accum: avx,
+            xsave,
+        };
+
+        let mut buf = [0; 1024];
+        let len = record.to_record(3, &mut buf).unwrap();
+        assert_eq!(len, DESCRIPTOR_SIZE + MASKS_SIZE + 832);
+        assert_eq!(buf[..8], [16, 0, 3, 0, 0x58, 0x03, 0, 0]);
+        assert_eq!(
+            record.to_record(3, &mut buf[..len - 1]).err(),
+            Some(XSaveError::InvalidLength(len))
+        );
+
+        let (instance, back) = CpuXsave::from_record(&buf).unwrap();
+        assert_eq!(instance, 3);
+        assert_eq!(back.xfeature_mask, record.xfeature_mask);
+        assert_eq!(back.xcr0_accum, avx);
+        assert_eq!(back.xsave.as_bytes()[..], xsave.as_bytes()[..]);
+
+        buf[0] = 15;
+        assert_eq!(
+            CpuXsave::from_record(&buf).err(),
+            Some(XSaveError::InvalidMagic)
+        );
+        buf[0] = 16;
+        assert_eq!(
+            CpuXsave::from_record(&buf[..len - 1]).err(),
+            Some(XSaveError::InvalidLength(len - 1))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Fuchsia (Zircon) thread state
+//!
+//! `zx_thread_read_state` and `zx_thread_write_state` transfer the x87
+//! state as `ZX_THREAD_STATE_FP_REGS` and the SSE, AVX and AVX-512 state as
+//! `ZX_THREAD_STATE_VECTOR_REGS`. The former is the FXSAVE image without
+//! MXCSR and the XMM registers; the latter holds every ZMM register whole,
+//! with the XMM and YMM registers in their low lanes, followed by the
+//! opmask registers and MXCSR. Registers beyond what the CPU supports read
+//! as zero.
+
+use super::*;
+
+/// The x87 state (`zx_thread_state_fp_regs_t`)
+///
+/// Only the low 80 bits of each register in `st` are used, in stack
+/// order. `ftw` is the abridged tag word, as in an FXSAVE image.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug)]
+pub struct ZxThreadStateFpRegs {
+    pub fcw: u16,
+    pub fsw: u16,
+    pub ftw: u8,
+    pub reserved: u8,
+    pub fop: u16,
+    pub fip: u64,
+    pub fdp: u64,
+    pub padding1: [u8; 8],
+    pub st: [MmField; 8],
+}
+
+impl Default for ZxThreadStateFpRegs {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSaveLegacy::DEFAULT)
+    }
+}
+
+impl From<&XSaveLegacy> for ZxThreadStateFpRegs {
+    fn from(legacy: &XSaveLegacy) -> Self {
+        Self {
+            fcw: legacy.fcw.bits,
+            fsw: legacy.fsw.bits,
+            ftw: legacy.ftw,
+            reserved: 0,
+            fop: legacy.fop,
+            fip: legacy.fip,
+            fdp: legacy.fdp,
+            padding1: [0; 8],
+            st: legacy.mm,
+        }
+    }
+}
+
+impl From<&ZxThreadStateFpRegs> for XSaveLegacy {
+    /// Convert the x87 state, leaving the SSE state at its defaults
+    fn from(regs: &ZxThreadStateFpRegs) -> Self {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fcw.bits = regs.fcw;
+        legacy.fsw.bits = regs.fsw;
+        legacy.ftw = regs.ftw;
+        legacy.fop = regs.fop;
+        legacy.fip = regs.fip;
+        legacy.fdp = regs.fdp;
+        legacy.mm = regs.st;
+
+        legacy
+    }
+}
+
+/// The SIMD state (`zx_thread_state_vector_regs_t`)
+///
+/// Each register in `zmm` is given as eight `u64` values from the lowest.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ZxThreadStateVectorRegs {
+    pub zmm: [[u64; 8]; 32],
+    pub opmask: [u64; 8],
+    pub mxcsr: u32,
+    pub padding1: [u8; 4],
+}
+
+impl Default for ZxThreadStateVectorRegs {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSave::DEFAULT)
+    }
+}
+
+impl From<&XSave> for ZxThreadStateVectorRegs {
+    /// Gather the registers, reading those of absent components as zero
+    fn from(xsave: &XSave) -> Self {
+        let mut regs = Self {
+            zmm: [[0; 8]; 32],
+            opmask: [0; 8],
+            mxcsr: xsave.legacy.mxcsr.bits,
+            padding1: [0; 4],
+        };
+
+        for (dst, (_, lanes)) in regs.zmm.iter_mut().zip(xsave.zmm_regs()) {
+            for (words, lane) in dst.chunks_exact_mut(2).zip(lanes) {
+                words[0] = lane as u64;
+                words[1] = (lane >> 64) as u64;
+            }
+        }
+
+        for (dst, (_, k)) in regs.opmask.iter_mut().zip(xsave.k_regs()) {
+            *dst = k;
+        }
+
+        regs
+    }
+}
+
+/// Whether writing `words` would take a component out of its initial configuration
+fn needed(xsave: &XSave, component: Component, words: &[u64]) -> bool {
+    xsave.header.xstate_bv.contains(component.bit()) || words.iter().any(|w| *w != 0)
+}
+
+/// Write `words` into a component, marking it present
+fn store(xsave: &mut XSave, component: Component, words: &[u64]) -> Result<(), XSaveError> {
+    xsave.enable(component)?;
+    if let Some((start, end)) = xsave.component_range(component) {
+        let bytes = &mut xsave.as_bytes_mut()[start..end];
+        for (dst, word) in bytes.chunks_exact_mut(8).zip(words) {
+            dst.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+impl ZxThreadStateVectorRegs {
+    /// Write the registers into an `XSave`, as `zx_thread_write_state` does
+    ///
+    /// MXCSR and the XMM registers are always written, marking SSE present.
+    /// An extended component is marked present if it already was or if
+    /// the registers give it nonzero contents; otherwise it is left in its
+    /// initial configuration, which holds zeros. The three AVX-512
+    /// components are only ever marked present together. Reserved MXCSR
+    /// bits are rejected with `XSaveError::MxCsrReserved`, leaving the
+    /// buffer unchanged.
+    pub fn write(&self, xsave: &mut XSave) -> Result<(), XSaveError> {
+        xsave.legacy.set_mxcsr_checked(MxCsr { bits: self.mxcsr })?;
+
+        for (xmm, zmm) in xsave.legacy.xmm.iter_mut().zip(self.zmm.iter()) {
+            xmm.0[..8].copy_from_slice(&zmm[0].to_le_bytes());
+            xmm.0[8..].copy_from_slice(&zmm[1].to_le_bytes());
+        }
+        xsave.header.xstate_bv |= XStateBv::SSE;
+
+        let mut avx = [0; 32];
+        let mut hi256 = [0; 64];
+        for (i, zmm) in self.zmm[..16].iter().enumerate() {
+            avx[i * 2..][..2].copy_from_slice(&zmm[2..4]);
+            hi256[i * 4..][..4].copy_from_slice(&zmm[4..]);
+        }
+
+        let mut hi16 = [0; 128];
+        for (dst, zmm) in hi16.chunks_exact_mut(8).zip(self.zmm[16..].iter()) {
+            dst.copy_from_slice(zmm);
+        }
+
+        if needed(xsave, Component::Avx, &avx) {
+            store(xsave, Component::Avx, &avx)?;
+        }
+
+        let avx512 = [
+            (Component::Avx512Opmask, &self.opmask[..]),
+            (Component::Avx512ZmmHi256, &hi256[..]),
+            (Component::Avx512Hi16Zmm, &hi16[..]),
+        ];
+
+        if avx512.iter().any(|(c, words)| needed(xsave, *c, words)) {
+            for (component, words) in avx512 {
+                store(xsave, component, words)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{align_of, size_of};
+
+    #[test]
+    fn size() {
+        assert_eq!(size_of::<ZxThreadStateFpRegs>(), 160);
+        assert_eq!(align_of::<ZxThreadStateFpRegs>(), 16);
+        assert_eq!(size_of::<ZxThreadStateVectorRegs>(), 2120);
+    }
+
+    #[test]
+    fn fp() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fsw.set_top(5);
+        legacy.ftw = 0xE0;
+        legacy.fip = 0x4000_1000;
+        legacy.mm[7].mm = Mm([0x11; 10]);
+
+        let regs = ZxThreadStateFpRegs::from(&legacy);
+        assert_eq!(regs.fsw, 5 << 11);
+        assert_eq!(regs.ftw, 0xE0);
+
+        let back = XSaveLegacy::from(&regs);
+        assert_eq!(back.as_bytes()[..], legacy.as_bytes()[..]);
+    }
+
+    #[test]
+    fn vector() {
+        let mut xsave = XSave::fxsave_default();
+        let mut regs = ZxThreadStateVectorRegs::from(&xsave);
+        assert_eq!(regs, ZxThreadStateVectorRegs::default());
+
+        regs.zmm[1] = [1, 2, 0, 0, 0, 0, 0, 0];
+        regs.zmm[3][3] = 4;
+        regs.write(&mut xsave).unwrap();
+        assert_eq!(xsave.legacy.xmm[1].0[0], 1);
+        assert_eq!(xsave.legacy.xmm[1].0[8], 2);
+        assert_eq!(
+            xsave.header.xstate_bv,
+            XStateBv::X87 | XStateBv::SSE | XStateBv::AVX
+        );
+        assert_eq!(ZxThreadStateVectorRegs::from(&xsave), regs);
+
+        regs.opmask[2] = 6;
+        regs.write(&mut xsave).unwrap();
+        assert!(xsave.header.xstate_bv.contains(XStateBv::AVX512));
+        assert_eq!(xsave.header.xstate_bv.check_avx512(), Ok(()));
+
+        regs.zmm[31][7] = 5;
+        regs.write(&mut xsave).unwrap();
+        assert_eq!(ZxThreadStateVectorRegs::from(&xsave), regs);
+
+        regs.mxcsr = 1 << 31;
+        assert_eq!(
+            regs.write(&mut xsave),
+            Err(XSaveError::MxCsrReserved(1 << 31))
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! The state components used by each instruction set extension
+
+use super::*;
+
+/// An instruction set extension which uses extended state
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Isa {
+    X87,
+    Mmx,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Aes,
+    Pclmulqdq,
+    Sha,
+    Avx,
+    Avx2,
+    Fma,
+    F16c,
+    AvxVnni,
+    Avx512F,
+    Avx512Cd,
+    Avx512Bw,
+    Avx512Dq,
+    Avx512Vl,
+    Avx512Ifma,
+    Avx512Vbmi,
+    Avx512Vnni,
+    Avx512Bf16,
+    Avx512Fp16,
+    Mpx,
+    Pku,
+    AmxTile,
+    AmxInt8,
+    AmxBf16,
+}
+
+impl XStateBv {
+    /// The user components which must be enabled in XCR0 to use an extension
+    ///
+    /// Only the components used by the extension itself are returned, so
+    /// the result must still be combined with `XStateBv::X87`, which is
+    /// always required.
+    pub const fn required_for(isa: Isa) -> Self {
+        const SSE: u64 = XStateBv::SSE.bits;
+        const AVX: u64 = SSE | XStateBv::AVX.bits;
+        const AVX512: u64 = AVX
+            | XStateBv::AVX512_OPMASK.bits
+            | XStateBv::AVX512_ZMM_HI256.bits
+            | XStateBv::AVX512_HI16_ZMM.bits;
+
+        let bits = match isa {
+            Isa::X87 | Isa::Mmx => XStateBv::X87.bits,
+
+            Isa::Sse
+            | Isa::Sse2
+            | Isa::Sse3
+            | Isa::Ssse3
+            | Isa::Sse41
+            | Isa::Sse42
+            | Isa::Aes
+            | Isa::Pclmulqdq
+            | Isa::Sha => SSE,
+
+            Isa::Avx | Isa::Avx2 | Isa::Fma | Isa::F16c | Isa::AvxVnni => AVX,
+
+            Isa::Avx512F
+            | Isa::Avx512Cd
+            | Isa::Avx512Bw
+            | Isa::Avx512Dq
+            | Isa::Avx512Vl
+            | Isa::Avx512Ifma
+            | Isa::Avx512Vbmi
+            | Isa::Avx512Vnni
+            | Isa::Avx512Bf16
+            | Isa::Avx512Fp16 => AVX512,
+
+            Isa::Mpx => XStateBv::BNDREGS.bits | XStateBv::BNDCSR.bits,
+            Isa::Pku => XStateBv::PKRU.bits,
+
+            Isa::AmxTile | Isa::AmxInt8 | Isa::AmxBf16 => {
+                XStateBv::AMX_TILECFG.bits | XStateBv::AMX_TILEDATA.bits
+            }
+        };
+
+        Self { bits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required() {
+        assert_eq!(XStateBv::required_for(Isa::Sse42), XStateBv::SSE);
+        assert_eq!(
+            XStateBv::required_for(Isa::Avx2),
+            XStateBv::SSE | XStateBv::AVX
+        );
+        assert_eq!(XStateBv::required_for(Isa::Avx512F).bits, 0b1110_0110);
+        assert_eq!(XStateBv::required_for(Isa::AmxInt8).bits, 0b11 << 17);
+
+        for isa in [Isa::Avx512Vl, Isa::AmxTile, Isa::Mpx, Isa::Pku] {
+            let xcr0 = XStateBv::X87 | XStateBv::required_for(isa);
+            let masks = XStateMasks {
+                xcr0,
+                xss: XStateBv::empty(),
+            };
+            assert_eq!(masks.validate(), Ok(()));
+        }
+    }
+}
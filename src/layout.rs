@@ -0,0 +1,71 @@
+//! The fixed layouts of the buffer types
+
+use core::mem::align_of;
+
+use super::interop::{ghcb, linux, windows, FpReg};
+use super::*;
+
+/// A type with an architecturally fixed size and alignment
+///
+/// Every type implementing this trait is `#[repr(C)]`, and its size
+/// matches the hardware or ABI structure it models. The alignment is that
+/// of the Rust type, which for `XSaveLegacy` and `XSaveHeader` is smaller
+/// than the instructions require of a whole area. Generic embedding code,
+/// such as arena allocators or guest ABI builders, can rely on these
+/// constants in const contexts.
+pub trait FixedLayout: Sized {
+    /// The size of the type in bytes
+    const SIZE: usize;
+
+    /// The alignment of the type in bytes
+    const ALIGN: usize;
+}
+
+macro_rules! fixed_layout {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl FixedLayout for $t {
+                const SIZE: usize = size_of::<Self>();
+                const ALIGN: usize = align_of::<Self>();
+            }
+        )*
+    };
+}
+
+fixed_layout! {
+    Mm,
+    MmField,
+    Xmm,
+    XSaveLegacy,
+    XSaveHeader,
+    XSave,
+    XSave4K,
+    XSaveAvx,
+    XSaveUnaligned,
+    SseState,
+    TileCfg,
+    FpReg,
+    ghcb::Ghcb,
+    linux::FpState32,
+    linux::XState32,
+    linux::UserFpRegs,
+    linux::UserFpxRegs,
+    windows::Wow64ExtendedRegisters,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!((Mm::SIZE, Mm::ALIGN), (10, 1));
+        assert_eq!((MmField::SIZE, Xmm::SIZE), (16, 16));
+        assert_eq!((XSaveLegacy::SIZE, XSaveLegacy::ALIGN), (512, 8));
+        assert_eq!((XSaveHeader::SIZE, XSaveHeader::ALIGN), (64, 8));
+        assert_eq!(XSave::ALIGN, 64);
+        assert_eq!((XSave4K::SIZE, XSave4K::ALIGN), (4096, 4096));
+        assert_eq!((FpReg::SIZE, FpReg::ALIGN), (10, 2));
+        assert_eq!(ghcb::Ghcb::SIZE, ghcb::GHCB_SIZE);
+    }
+}
@@ -0,0 +1,198 @@
+//! Explicitly little-endian integers for wire formats
+//!
+//! The XSAVE area is little-endian, but the `repr(C)` structures of this
+//! crate use native endianness. These wrappers have an alignment of one
+//! and always store their value in little-endian byte order, so that
+//! serialized state decodes correctly on any host.
+//!
+//! The wire formats carry an `XSave` in the layout of the XSAVE area,
+//! with every integer field little-endian. Only the legacy control fields
+//! and the header masks are integers; the registers and the extended area
+//! are already held as bytes in that order.
+
+use core::fmt;
+use core::mem::size_of;
+
+use super::*;
+
+macro_rules! le {
+    ($(#[$attr:meta])* $name:ident: $int:ty) => {
+        $(#[$attr])*
+        #[repr(transparent)]
+        #[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+        pub struct $name([u8; core::mem::size_of::<$int>()]);
+
+        impl $name {
+            /// Create a new value
+            #[inline]
+            pub const fn new(value: $int) -> Self {
+                Self(value.to_le_bytes())
+            }
+
+            /// Get the value in native byte order
+            #[inline]
+            pub const fn get(self) -> $int {
+                <$int>::from_le_bytes(self.0)
+            }
+
+            /// Set the value
+            #[inline]
+            pub fn set(&mut self, value: $int) {
+                *self = Self::new(value);
+            }
+
+            /// The little-endian bytes of the value
+            #[inline]
+            pub const fn to_bytes(self) -> [u8; core::mem::size_of::<$int>()] {
+                self.0
+            }
+
+            /// Create a value from its little-endian bytes
+            #[inline]
+            pub const fn from_bytes(bytes: [u8; core::mem::size_of::<$int>()]) -> Self {
+                Self(bytes)
+            }
+        }
+
+        impl From<$int> for $name {
+            #[inline]
+            fn from(value: $int) -> Self {
+                Self::new(value)
+            }
+        }
+
+        impl From<$name> for $int {
+            #[inline]
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+    };
+}
+
+le! {
+    /// A little-endian `u16`
+    U16: u16
+}
+
+le! {
+    /// A little-endian `u32`
+    U32: u32
+}
+
+le! {
+    /// A little-endian `u64`
+    U64: u64
+}
+
+/// Read the bytes of a field from the XSAVE area
+#[inline]
+fn field<const N: usize>(bytes: &[u8; size_of::<XSave>()], offset: usize) -> [u8; N] {
+    let mut field = [0; N];
+    field.copy_from_slice(&bytes[offset..][..N]);
+    field
+}
+
+impl XSave {
+    /// Serialize the buffer in the little-endian layout of the XSAVE area
+    pub(crate) fn le_bytes(&self) -> [u8; size_of::<XSave>()] {
+        let mut bytes = *self.as_bytes();
+        let legacy = &self.legacy;
+
+        bytes[0..2].copy_from_slice(&U16::new(legacy.fcw.bits).to_bytes());
+        bytes[2..4].copy_from_slice(&U16::new(legacy.fsw.bits).to_bytes());
+        bytes[6..8].copy_from_slice(&U16::new(legacy.fop).to_bytes());
+        bytes[8..16].copy_from_slice(&U64::new(legacy.fip).to_bytes());
+        bytes[16..24].copy_from_slice(&U64::new(legacy.fdp).to_bytes());
+        bytes[24..28].copy_from_slice(&U32::new(legacy.mxcsr.bits).to_bytes());
+        bytes[28..32].copy_from_slice(&U32::new(legacy.mxcsr_mask.bits).to_bytes());
+        bytes[512..520].copy_from_slice(&U64::new(self.header.xstate_bv.bits).to_bytes());
+        bytes[520..528].copy_from_slice(&U64::new(self.header.xcomp_bv.bits).to_bytes());
+        bytes
+    }
+
+    /// Parse a buffer serialized by `XSave::le_bytes()`
+    pub(crate) fn from_le_bytes(bytes: &[u8; size_of::<XSave>()]) -> Self {
+        let mut xsave = Self::DEFAULT;
+        *xsave.as_bytes_mut() = *bytes;
+
+        let legacy = &mut xsave.legacy;
+        legacy.fcw.bits = U16::from_bytes(field(bytes, 0)).get();
+        legacy.fsw.bits = U16::from_bytes(field(bytes, 2)).get();
+        legacy.fop = U16::from_bytes(field(bytes, 6)).get();
+        legacy.fip = U64::from_bytes(field(bytes, 8)).get();
+        legacy.fdp = U64::from_bytes(field(bytes, 16)).get();
+        legacy.mxcsr.bits = U32::from_bytes(field(bytes, 24)).get();
+        legacy.mxcsr_mask.bits = U32::from_bytes(field(bytes, 28)).get();
+        xsave.header.xstate_bv.bits = U64::from_bytes(field(bytes, 512)).get();
+        xsave.header.xcomp_bv.bits = U64::from_bytes(field(bytes, 520)).get();
+        xsave
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::align_of;
+
+    #[test]
+    fn order() {
+        assert_eq!(U16::new(0x037F).to_bytes(), [0x7F, 0x03]);
+        assert_eq!(U32::new(0x1F80).to_bytes(), [0x80, 0x1F, 0, 0]);
+        assert_eq!(
+            U64::from_bytes([7, 0, 0, 0, 0, 0, 0, 0x80]).get(),
+            0x8000_0000_0000_0007
+        );
+
+        let mut value = U32::default();
+        value.set(0xFFBF);
+        assert_eq!(u32::from(value), 0xFFBF);
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<U64>(), 8);
+        assert_eq!(align_of::<U64>(), 1);
+    }
+
+    #[test]
+    fn fixture() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.legacy.fsw = Fsw::from_bits_truncate(0x4101);
+        xsave.legacy.fop = 0x07DD;
+        xsave.legacy.fip = 0x0102_0304_0506_0708;
+        xsave.legacy.fdp = 0x1112_1314_1516_1718;
+        xsave.legacy.mxcsr_mask = MxCsr::from_bits_truncate(0xFFFF);
+        xsave.legacy.xmm[0] = Xmm([0xAA; 16]);
+        xsave.header.xstate_bv = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        xsave.header.xcomp_bv = XCompBv::COMPACT;
+
+        #[rustfmt::skip]
+        const LEGACY: [u8; 32] = [
+            0x7F, 0x03, 0x01, 0x41, 0x00, 0x00, 0xDD, 0x07,
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+            0x18, 0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11,
+            0x80, 0x1F, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00,
+        ];
+        const HEADER: [u8; 16] = [7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80];
+
+        let bytes = xsave.le_bytes();
+        assert_eq!(bytes[..32], LEGACY);
+        assert_eq!(bytes[160..176], [0xAA; 16]);
+        assert_eq!(bytes[512..528], HEADER);
+
+        let copy = XSave::from_le_bytes(&bytes);
+        assert_eq!(copy.legacy.fcw, Fcw::DEFAULT);
+        assert_eq!(copy.legacy.fip, 0x0102_0304_0506_0708);
+        assert_eq!(copy.legacy.mxcsr, MxCsr::DEFAULT);
+        assert_eq!(copy.header.xstate_bv, xsave.header.xstate_bv);
+        assert_eq!(copy.header.xcomp_bv, XCompBv::COMPACT);
+        assert_eq!(copy.as_bytes()[..], xsave.as_bytes()[..]);
+    }
+}
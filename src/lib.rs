@@ -27,12 +27,146 @@
 #![deny(clippy::all)]
 #![no_std]
 
-#[cfg(feature = "asm")]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(any(xsave_soft, feature = "differential"))]
+extern crate std;
+
+#[cfg(xsave_hw)]
 use core::arch::asm;
+#[cfg(xsave_asm)]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub mod container;
+#[cfg(all(feature = "differential", xsave_hw))]
+pub mod differential;
+pub mod fenv;
+pub mod interop;
+pub mod le;
+pub mod msr;
+#[cfg(all(feature = "uefi", xsave_hw))]
+pub mod uefi;
+
+mod alias;
+mod amx;
+mod avx;
+mod backend;
+#[cfg(xsave_asm)]
+mod batch;
+mod caps;
+mod component;
+mod display;
+#[cfg(feature = "alloc")]
+mod dynamic;
+mod error;
+mod exception;
+#[cfg(feature = "defmt")]
+mod format;
+mod isa;
+mod layout;
+mod mmx;
+mod mpx;
+mod nested;
+mod pack;
+mod padded;
+mod parse;
+mod pinned;
+#[cfg(all(feature = "debug-poison", xsave_asm))]
+mod poison;
+mod regs;
+#[cfg(xsave_asm)]
+mod scope;
+mod scratch;
+#[cfg(xsave_asm)]
+mod scrub;
+#[cfg(xsave_asm)]
+mod selftest;
+mod seq;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd;
+#[cfg(any(xsave_soft, feature = "differential"))]
+#[cfg_attr(not(xsave_soft), allow(dead_code))]
+mod soft;
+mod sse;
+mod unaligned;
+mod vendor;
+mod view;
+mod x87;
+
+pub use alias::{Encoding, VectorReg, WriteEffect};
+pub use amx::TileCfg;
+pub use avx::XSaveAvx;
+#[cfg(xsave_asm)]
+pub use backend::DefaultBackend;
+pub use backend::{Backend, SoftBackend};
+pub use caps::Capabilities;
+#[cfg(target_arch = "x86_64")]
+pub use component::xsaves_size;
+pub use component::{size_for, Component, ComponentInfo, ComponentTable};
+#[cfg(feature = "alloc")]
+pub use dynamic::XSaveDyn;
+pub use error::XSaveError;
+pub use exception::{FpException, FpExceptions};
+pub use isa::Isa;
+pub use layout::FixedLayout;
+pub use nested::{merge_nested, XStateMasks};
+pub use padded::XSave4K;
+#[cfg(feature = "alloc")]
+pub use parse::ParseIssue;
+pub use pinned::{PinnedAlloc, PinnedXSave};
+#[cfg(all(feature = "debug-poison", xsave_asm))]
+pub use poison::Lineage;
+#[cfg(xsave_asm)]
+pub use scope::{Isolated, XSaveGuard};
+pub use scratch::{AlignedStatic, Claim, PerCpu, StaticXSave};
+#[cfg(xsave_asm)]
+pub use selftest::SelfTest;
+pub use seq::SeqXSave;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub use simd::Ymm;
+pub use sse::SseState;
+pub use unaligned::XSaveUnaligned;
+pub use vendor::Vendor;
+pub use view::{XSaveMut, XSaveRef, XSaveView};
+pub use x87::FpPointerUpdate;
+
+use core::mem::size_of;
 
 use bitflags::bitflags;
 use const_default::ConstDefault;
 
+/// A floating point rounding mode
+///
+/// The x87 FCW and the MXCSR share this encoding for their rounding
+/// control fields.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even
+    Nearest = 0,
+
+    /// Round toward negative infinity
+    Down = 1,
+
+    /// Round toward positive infinity
+    Up = 2,
+
+    /// Round toward zero (truncate)
+    TowardZero = 3,
+}
+
+impl RoundingMode {
+    #[inline]
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Nearest,
+            1 => Self::Down,
+            2 => Self::Up,
+            _ => Self::TowardZero,
+        }
+    }
+}
+
 /// An MMX register
 #[repr(C)]
 #[derive(Copy, Clone, Default, Debug, ConstDefault)]
@@ -94,6 +228,20 @@ impl Default for Fcw {
     }
 }
 
+impl Fcw {
+    /// Get the rounding control field
+    #[inline]
+    pub const fn rounding_mode(self) -> RoundingMode {
+        RoundingMode::from_bits((self.bits >> 10) as u8)
+    }
+
+    /// Set the rounding control field
+    #[inline]
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.bits = (self.bits & !(0b11 << 10)) | (mode as u16) << 10;
+    }
+}
+
 bitflags! {
     /// The x87 Floating Point Unit (FPU) Status Word
     ///
@@ -160,6 +308,54 @@ impl Default for MxCsr {
     }
 }
 
+impl MxCsr {
+    /// Get the rounding control field
+    #[inline]
+    pub const fn rounding_mode(self) -> RoundingMode {
+        RoundingMode::from_bits((self.bits >> 13) as u8)
+    }
+
+    /// Set the rounding control field
+    #[inline]
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.bits = (self.bits & !(0b11 << 13)) | (mode as u32) << 13;
+    }
+
+    /// Detect the MXCSR bits supported by the current CPU
+    ///
+    /// This executes `fxsave` into a scratch area to read the real
+    /// MXCSR_MASK. The result is cached after the first call.
+    #[cfg(xsave_asm)]
+    pub fn detect_mask() -> Self {
+        static MASK: AtomicU32 = AtomicU32::new(0);
+
+        let mut mask = MASK.load(Ordering::Relaxed);
+        if mask == 0 {
+            #[repr(C, align(16))]
+            struct Scratch(XSaveLegacy);
+
+            let mut scratch = Scratch(XSaveLegacy::DEFAULT);
+
+            #[cfg(xsave_soft)]
+            soft::fxsave(&mut scratch.0);
+
+            #[cfg(all(xsave_hw, not(doc)))]
+            unsafe {
+                asm!(
+                    "fxsave  [{}]",
+                    in(reg) &mut scratch,
+                    options(nostack),
+                )
+            }
+
+            mask = scratch.0.mxcsr_writable();
+            MASK.store(mask, Ordering::Relaxed);
+        }
+
+        Self { bits: mask }
+    }
+}
+
 bitflags! {
     /// XCOMP_BV flags
     #[repr(transparent)]
@@ -184,12 +380,45 @@ bitflags! {
         const AVX512_HI16_ZMM = 1 << 7;
         const PT = 1 << 8;
         const PKRU = 1 << 9;
+        const AMX_TILECFG = 1 << 17;
+        const AMX_TILEDATA = 1 << 18;
+    }
+}
+
+impl XStateBv {
+    /// The three AVX-512 components, which must be enabled together
+    pub const AVX512: Self = Self {
+        bits: Self::AVX512_OPMASK.bits | Self::AVX512_ZMM_HI256.bits | Self::AVX512_HI16_ZMM.bits,
+    };
+
+    /// Check that the AVX-512 components are either all set or all clear
+    ///
+    /// Enabling only part of the trio in XCR0 faults, and is a common cause
+    /// of unexpected general protection faults. The partial set is returned
+    /// as `XSaveError::InvalidMask` on failure.
+    #[inline]
+    pub fn check_avx512(self) -> Result<(), XSaveError> {
+        match self & Self::AVX512 {
+            trio if trio.is_empty() || trio == Self::AVX512 => Ok(()),
+            partial => Err(XSaveError::InvalidMask(partial)),
+        }
+    }
+
+    /// Complete a partial set of AVX-512 components
+    ///
+    /// If any of the three components is set, all of them are set.
+    #[inline]
+    pub fn fix_avx512(self) -> Self {
+        match self.intersects(Self::AVX512) {
+            true => self | Self::AVX512,
+            false => self,
+        }
     }
 }
 
 /// The XSave Legacy Area
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug)]
 pub struct XSaveLegacy {
     pub fcw: Fcw,
     pub fsw: Fsw,
@@ -203,7 +432,7 @@ pub struct XSaveLegacy {
     pub mm: [MmField; 8],
     pub xmm: [Xmm; 16],
     reserved1: [u64; 11],
-    reserved2: [u8; 7],
+    reserved2: [u8; 8],
 }
 
 impl ConstDefault for XSaveLegacy {
@@ -220,10 +449,85 @@ impl ConstDefault for XSaveLegacy {
         mm: [MmField::DEFAULT; 8],
         xmm: [Xmm::DEFAULT; 16],
         reserved1: [0; 11],
-        reserved2: [0; 7],
+        reserved2: [0; 8],
     };
 }
 
+impl Default for XSaveLegacy {
+    /// The default legacy area
+    ///
+    /// Unlike `XSaveLegacy::DEFAULT`, this uses the MXCSR_MASK of the
+    /// current CPU when the `asm` feature is enabled.
+    #[inline]
+    fn default() -> Self {
+        Self {
+            #[cfg(xsave_asm)]
+            mxcsr_mask: MxCsr::detect_mask(),
+            ..Self::DEFAULT
+        }
+    }
+}
+
+impl XSaveLegacy {
+    #[inline]
+    fn as_bytes(&self) -> &[u8; size_of::<XSaveLegacy>()] {
+        // SAFETY: `XSaveLegacy` has no padding and every bit pattern is valid.
+        unsafe { &*(self as *const Self as *const [u8; size_of::<XSaveLegacy>()]) }
+    }
+
+    #[inline]
+    fn as_bytes_mut(&mut self) -> &mut [u8; size_of::<XSaveLegacy>()] {
+        // SAFETY: `XSaveLegacy` has no padding and every bit pattern is valid.
+        unsafe { &mut *(self as *mut Self as *mut [u8; size_of::<XSaveLegacy>()]) }
+    }
+
+    /// The MXCSR bits which may be set without faulting
+    ///
+    /// A zero `mxcsr_mask` means the CPU did not report one, in which case
+    /// the architectural default of `0xFFBF` applies (Section 11.6.6).
+    #[inline]
+    fn mxcsr_writable(&self) -> u32 {
+        match self.mxcsr_mask.bits {
+            0 => 0xFFBF,
+            mask => mask,
+        }
+    }
+
+    /// Set MXCSR, rejecting any bits excluded by `mxcsr_mask`
+    ///
+    /// Loading an MXCSR value with reserved bits set causes a general
+    /// protection fault, so this should be preferred over setting `mxcsr`
+    /// directly whenever the value is not known to be valid.
+    #[inline]
+    pub fn set_mxcsr_checked(&mut self, mxcsr: MxCsr) -> Result<(), XSaveError> {
+        let reserved = mxcsr.bits & !self.mxcsr_writable();
+        if reserved != 0 {
+            return Err(XSaveError::MxCsrReserved(reserved));
+        }
+
+        self.mxcsr = mxcsr;
+        Ok(())
+    }
+}
+
+/// Timestamp counter readings taken around a save
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timestamps {
+    /// The counter immediately before the save
+    pub before: u64,
+
+    /// The counter immediately after the save completed
+    pub after: u64,
+}
+
+impl Timestamps {
+    /// The number of cycles taken by the save
+    #[inline]
+    pub const fn cycles(&self) -> u64 {
+        self.after.wrapping_sub(self.before)
+    }
+}
+
 /// The XSave Header Area
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, ConstDefault)]
@@ -233,6 +537,37 @@ pub struct XSaveHeader {
     reserved: [u64; 6],
 }
 
+impl XSaveHeader {
+    /// The XCOMP_BV bits above the highest architectural component
+    const XCOMP_RESERVED: u64 = !XCompBv::COMPACT.bits & !((1 << 19) - 1);
+
+    /// Set XCOMP_BV, rejecting values which would fault on restore
+    ///
+    /// In the compacted format every component in `xstate_bv` must also be
+    /// present in `xcomp_bv`, and bits 62:19 are reserved. In the standard
+    /// format `xcomp_bv` must be zero. Violations are reported as
+    /// `XSaveError::InvalidMask` carrying the offending component bits.
+    #[inline]
+    pub fn set_xcomp_bv_checked(&mut self, xcomp_bv: XCompBv) -> Result<(), XSaveError> {
+        let components = xcomp_bv.bits & !XCompBv::COMPACT.bits;
+
+        let invalid = if !xcomp_bv.contains(XCompBv::COMPACT) {
+            components
+        } else if components & Self::XCOMP_RESERVED != 0 {
+            components & Self::XCOMP_RESERVED
+        } else {
+            self.xstate_bv.bits & !components
+        };
+
+        if invalid != 0 {
+            return Err(XSaveError::InvalidMask(XStateBv { bits: invalid }));
+        }
+
+        self.xcomp_bv = xcomp_bv;
+        Ok(())
+    }
+}
+
 /// The XSave Extended Area
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, ConstDefault)]
@@ -259,11 +594,62 @@ impl ConstDefault for XSave {
 }
 
 impl XSave {
+    #[inline]
+    fn as_bytes(&self) -> &[u8; size_of::<XSave>()] {
+        // SAFETY: `XSave` has no padding and every bit pattern is valid.
+        unsafe { &*(self as *const Self as *const [u8; size_of::<XSave>()]) }
+    }
+
+    #[inline]
+    fn as_bytes_mut(&mut self) -> &mut [u8; size_of::<XSave>()] {
+        // SAFETY: `XSave` has no padding and every bit pattern is valid.
+        unsafe { &mut *(self as *mut Self as *mut [u8; size_of::<XSave>()]) }
+    }
+
+    /// A buffer which restores every component to its initial configuration
+    ///
+    /// With `xstate_bv` clear, `xrstor` initializes every component instead
+    /// of reading its data, which makes this the cheapest way to restore a
+    /// clean state. Only MXCSR is still loaded from memory. The legacy area
+    /// holds its defaults, so the buffer stays valid for consumers which do
+    /// read it, and restoring it is equivalent to restoring `XSave::DEFAULT`.
+    /// Unlike `XSave::default()`, this does not depend on the current CPU.
+    #[inline]
+    pub const fn init_state() -> Self {
+        Self {
+            legacy: XSaveLegacy::DEFAULT,
+            header: XSaveHeader {
+                xstate_bv: XStateBv::empty(),
+                ..XSaveHeader::DEFAULT
+            },
+            ..Self::DEFAULT
+        }
+    }
+
+    /// A default buffer with the legacy components marked present
+    ///
+    /// This holds the same data as `XSave::DEFAULT`, but with X87 and SSE
+    /// set in `xstate_bv`, as in an `fxsave` image. Some consumers, such as
+    /// kernels and KVM, reject buffers whose `xstate_bv` is fully clear.
+    #[inline]
+    pub const fn fxsave_default() -> Self {
+        Self {
+            header: XSaveHeader {
+                xstate_bv: XStateBv::from_bits_truncate(XStateBv::X87.bits | XStateBv::SSE.bits),
+                ..XSaveHeader::DEFAULT
+            },
+            ..Self::DEFAULT
+        }
+    }
+
     /// Save the extended CPU state
     #[inline]
-    #[cfg(feature = "asm")]
+    #[cfg(xsave_asm)]
     pub extern "C" fn save(&mut self) {
-        #[cfg(all(feature = "asm", not(doc)))]
+        #[cfg(xsave_soft)]
+        soft::save(self);
+
+        #[cfg(all(xsave_hw, not(doc)))]
         unsafe {
             asm!(
                 "xsave   [{}]",
@@ -274,17 +660,129 @@ impl XSave {
         }
     }
 
+    /// Save the extended CPU state, checking first that XSAVE is usable
+    ///
+    /// This returns `XSaveError::UnsupportedCpu` if CPUID does not report
+    /// XSAVE and `XSaveError::NotEnabled` if CR4.OSXSAVE is clear, or, when
+    /// running at CPL 0, if CR0.EM or CR0.TS is set, instead of faulting.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn try_save(&mut self) -> Result<(), XSaveError> {
+        component::preflight()?;
+        self.save();
+        Ok(())
+    }
+
+    /// Save the extended CPU state, reading the timestamp counter around it
+    ///
+    /// The counter is read with `rdtsc` immediately before the save and
+    /// with `rdtscp`, which waits for the save to complete, immediately
+    /// after. This attributes the cost of the save itself, apart from the
+    /// surrounding code. With the software backend, both readings are zero.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn save_timestamped(&mut self) -> Timestamps {
+        #[cfg_attr(xsave_soft, allow(unused_mut))]
+        let mut timestamps = Timestamps::default();
+
+        #[cfg(xsave_soft)]
+        soft::save(self);
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "rdtsc",
+                "shl     rdx, 32",
+                "or      rax, rdx",
+                "mov     {before}, rax",
+                "mov     rax, {mask}",
+                "mov     rdx, {mask}",
+                "xsave   [{xsave}]",
+                "rdtscp",
+                "shl     rdx, 32",
+                "or      rax, rdx",
+                xsave = in(reg) self,
+                mask = in(reg) !0usize,
+                before = out(reg) timestamps.before,
+                out("rax") timestamps.after,
+                out("rcx") _,
+                out("rdx") _,
+                options(nostack),
+            )
+        }
+
+        timestamps
+    }
+
+    /// Load the extended CPU state, refusing components the OS has not enabled
+    ///
+    /// Restoring a component which is present in `xstate_bv` but not
+    /// enabled in XCR0 raises a general protection fault. This reads XCR0,
+    /// when it is available, and returns `XSaveError::UnsupportedComponents`
+    /// with the offending bits instead of executing the restore. It first
+    /// checks that the instruction can execute at all; see `try_save()`.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn try_load(&self) -> Result<(), XSaveError> {
+        component::preflight()?;
+
+        if let Some(xcr0) = component::xcr0() {
+            let unsupported = self.header.xstate_bv - xcr0;
+            if !unsupported.is_empty() {
+                return Err(XSaveError::UnsupportedComponents(unsupported));
+            }
+        }
+
+        self.load();
+        Ok(())
+    }
+
     /// Load the extended CPU state
+    ///
+    /// This does not check `xstate_bv` against XCR0; see `try_load()`.
     #[inline]
-    #[cfg(feature = "asm")]
+    #[cfg(xsave_asm)]
     pub extern "C" fn load(&self) {
-        #[cfg(all(feature = "asm", not(doc)))]
+        #[cfg(xsave_soft)]
+        soft::load(self);
+
+        #[cfg(all(xsave_hw, not(doc)))]
         unsafe {
             asm!(
                 "xrstor  [{}]",
                 in(reg) self,
                 in("rax") !0usize,
                 in("rdx") !0usize,
+                clobber_abi("C"),
+            )
+        }
+    }
+
+    /// Save the extended CPU state, then load `next`
+    ///
+    /// This is the context switch: the outgoing state is saved into
+    /// `self` and the incoming one restored immediately afterwards, in a
+    /// single block. Like `load()`, this does not check `next` against
+    /// XCR0.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn swap(&mut self, next: &Self) {
+        #[cfg(xsave_soft)]
+        {
+            soft::save(self);
+            soft::load(next);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "xsave   [{current}]",
+                "xrstor  [{next}]",
+                current = in(reg) self,
+                next = in(reg) next,
+                in("rax") !0usize,
+                in("rdx") !0usize,
+                clobber_abi("C"),
             )
         }
     }
@@ -293,7 +791,7 @@ impl XSave {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use core::mem::{align_of, size_of};
+    use core::mem::align_of;
 
     #[test]
     fn default() {
@@ -302,6 +800,237 @@ mod tests {
         assert_eq!(XSave::DEFAULT.legacy.fsw.bits, 0);
     }
 
+    #[test]
+    fn mxcsr_checked() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.mxcsr_mask = MxCsr::empty();
+
+        let daz = MxCsr::DEFAULT | MxCsr::DENORMALS_ARE_ZEROS;
+        assert_eq!(
+            legacy.set_mxcsr_checked(daz).unwrap_err(),
+            XSaveError::MxCsrReserved(MxCsr::DENORMALS_ARE_ZEROS.bits)
+        );
+        assert_eq!(legacy.mxcsr, MxCsr::DEFAULT);
+
+        legacy.mxcsr_mask = MxCsr::all();
+        legacy.set_mxcsr_checked(daz).unwrap();
+        assert_eq!(legacy.mxcsr, daz);
+    }
+
+    #[test]
+    fn avx512() {
+        let avx = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        assert_eq!(avx.check_avx512(), Ok(()));
+        assert_eq!((avx | XStateBv::AVX512).check_avx512(), Ok(()));
+
+        let partial = avx | XStateBv::AVX512_OPMASK | XStateBv::AVX512_HI16_ZMM;
+        assert_eq!(
+            partial.check_avx512(),
+            Err(XSaveError::InvalidMask(
+                XStateBv::AVX512_OPMASK | XStateBv::AVX512_HI16_ZMM
+            ))
+        );
+        assert_eq!(partial.fix_avx512(), avx | XStateBv::AVX512);
+        assert_eq!(avx.fix_avx512(), avx);
+    }
+
+    #[test]
+    fn xcomp_bv_checked() {
+        let mut header = XSaveHeader::DEFAULT;
+        header.xstate_bv = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+
+        let sse = XCompBv::COMPACT | XCompBv { bits: 0b11 };
+        assert_eq!(
+            header.set_xcomp_bv_checked(sse),
+            Err(XSaveError::InvalidMask(XStateBv::AVX))
+        );
+
+        let reserved = XCompBv::COMPACT
+            | XCompBv {
+                bits: 0b111 | 1 << 40,
+            };
+        assert_eq!(
+            header.set_xcomp_bv_checked(reserved),
+            Err(XSaveError::InvalidMask(XStateBv { bits: 1 << 40 }))
+        );
+
+        let standard = XCompBv { bits: 0b111 };
+        assert_eq!(
+            header.set_xcomp_bv_checked(standard),
+            Err(XSaveError::InvalidMask(XStateBv { bits: 0b111 }))
+        );
+        assert_eq!(header.xcomp_bv, XCompBv::empty());
+
+        let avx = XCompBv::COMPACT | XCompBv { bits: 0b111 };
+        header.set_xcomp_bv_checked(avx).unwrap();
+        assert_eq!(header.xcomp_bv, avx);
+
+        header.set_xcomp_bv_checked(XCompBv::empty()).unwrap();
+        assert_eq!(header.xcomp_bv, XCompBv::empty());
+    }
+
+    #[test]
+    #[cfg(all(xsave_asm, target_arch = "x86_64"))]
+    fn try_load() {
+        let reserved = XStateBv { bits: 1 << 62 };
+
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::X87 | reserved;
+        assert_eq!(
+            xsave.try_load(),
+            Err(XSaveError::UnsupportedComponents(reserved))
+        );
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn preflight() {
+        // Every host running the tests can execute XSAVE at user level.
+        assert_eq!(component::preflight(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn swap() {
+        let mut backup = XSave::DEFAULT;
+        backup.save();
+
+        let mut down = XSave::fxsave_default();
+        down.legacy.mxcsr.set_rounding_mode(RoundingMode::Down);
+        let mut up = XSave::fxsave_default();
+        up.legacy.mxcsr.set_rounding_mode(RoundingMode::Up);
+
+        let mut outgoing = XSave::DEFAULT;
+        let mut incoming = XSave::DEFAULT;
+        down.load();
+        outgoing.swap(&up);
+        incoming.save();
+
+        backup.load();
+
+        assert_eq!(outgoing.legacy.mxcsr, down.legacy.mxcsr);
+        assert_eq!(incoming.legacy.mxcsr, up.legacy.mxcsr);
+    }
+
+    #[test]
+    fn fxsave_default() {
+        let xsave = XSave::fxsave_default();
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert!(xsave.header.xcomp_bv.is_empty());
+        assert_eq!(xsave.legacy.as_bytes(), XSave::DEFAULT.legacy.as_bytes());
+    }
+
+    #[test]
+    fn init_state() {
+        let init = XSave::init_state();
+        assert!(init.header.xstate_bv.is_empty());
+        assert!(init.header.xcomp_bv.is_empty());
+        assert_eq!(init.legacy.as_bytes(), XSaveLegacy::DEFAULT.as_bytes());
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn init_state_restore() {
+        let mut host = XSave::DEFAULT;
+        host.save();
+
+        let mut dirty = XSave::fxsave_default();
+        dirty.legacy.fcw = Fcw::empty();
+        dirty.legacy.mxcsr.set_rounding_mode(RoundingMode::Up);
+        dirty.legacy.xmm[0] = Xmm([0xAA; 16]);
+
+        let mut init = XSave::DEFAULT;
+        dirty.load();
+        XSave::init_state().load();
+        init.save();
+
+        let mut default = XSave::DEFAULT;
+        dirty.load();
+        XSave::DEFAULT.load();
+        default.save();
+
+        host.load();
+
+        assert_eq!(init.header.xstate_bv, default.header.xstate_bv);
+        assert_eq!(init.legacy.as_bytes(), default.legacy.as_bytes());
+        assert_eq!(init.legacy.fcw, Fcw::DEFAULT);
+        assert_eq!(init.legacy.mxcsr, MxCsr::DEFAULT);
+        assert_eq!(init.legacy.xmm[0].0, [0; 16]);
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn mxcsr_mask() {
+        let mask = MxCsr::detect_mask();
+        assert!(mask.contains(MxCsr::DEFAULT));
+        assert_eq!(XSave::default().legacy.mxcsr_mask, mask);
+    }
+
+    /// Fails to link unless the optimizer proves that `f` cannot panic
+    #[cfg(not(debug_assertions))]
+    fn assert_no_panic<R>(f: impl FnOnce() -> R) -> R {
+        struct Guard;
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                extern "C" {
+                    fn xsave_core_path_may_panic() -> !;
+                }
+
+                unsafe { xsave_core_path_may_panic() }
+            }
+        }
+
+        let guard = Guard;
+        let result = f();
+        core::mem::forget(guard);
+        result
+    }
+
+    /// The core paths run in exception handlers, where unwinding is fatal
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn no_panic() {
+        use core::ptr::read_volatile;
+
+        let bytes = [0u8; size_of::<XSave>()];
+        let bytes = unsafe { read_volatile(&&bytes[..]) };
+        let component = unsafe { read_volatile(&Component::Avx) };
+        let mxcsr = unsafe { read_volatile(&MxCsr::DEFAULT) };
+        let xcomp_bv = unsafe { read_volatile(&XCompBv::COMPACT) };
+        let masks = unsafe { read_volatile(&XStateMasks::default()) };
+
+        let mut xsave = assert_no_panic(|| XSave::from_bytes_strict(bytes)).unwrap();
+        assert_no_panic(|| xsave.legacy.set_mxcsr_checked(mxcsr)).unwrap();
+        assert_no_panic(|| xsave.header.set_xcomp_bv_checked(xcomp_bv)).unwrap();
+        assert_no_panic(|| xsave.enable(component)).unwrap_err();
+        assert_no_panic(|| xsave.disable(component)).unwrap_err();
+        assert_no_panic(|| masks.validate()).unwrap_err();
+
+        // Only link, rather than run, the paths which touch the CPU state.
+        #[cfg(xsave_hw)]
+        if unsafe { read_volatile(&false) } {
+            assert_no_panic(|| xsave.save());
+            assert_no_panic(|| xsave.try_load()).unwrap();
+            assert_no_panic(|| xsave.load());
+        }
+    }
+
+    #[test]
+    fn rounding_mode() {
+        assert_eq!(Fcw::DEFAULT.rounding_mode(), RoundingMode::Nearest);
+        assert_eq!(MxCsr::DEFAULT.rounding_mode(), RoundingMode::Nearest);
+
+        let mut mxcsr = MxCsr::DEFAULT;
+        mxcsr.set_rounding_mode(RoundingMode::Up);
+        assert_eq!(mxcsr.bits, 0x5F80);
+        assert_eq!(mxcsr.rounding_mode(), RoundingMode::Up);
+
+        let mut fcw = Fcw::DEFAULT;
+        fcw.set_rounding_mode(RoundingMode::Down);
+        assert_eq!(fcw.bits, 0x077F);
+    }
+
     #[test]
     fn size() {
         assert_eq!(size_of::<XSaveLegacy>(), 512);
@@ -316,7 +1045,16 @@ mod tests {
     }
 
     #[test]
-    #[cfg(feature = "asm")]
+    #[cfg(xsave_hw)]
+    fn save_timestamped() {
+        let mut xsave = XSave::default();
+        let timestamps = xsave.save_timestamped();
+        assert!(timestamps.after > timestamps.before);
+        assert_eq!(timestamps.cycles(), timestamps.after - timestamps.before);
+    }
+
+    #[test]
+    #[cfg(xsave_hw)]
     #[cfg(target_feature = "sse")]
     fn asm() {
         let mut xsave = XSave::default();
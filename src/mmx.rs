@@ -0,0 +1,94 @@
+//! Typed access to the packed integers of the MMX registers
+//!
+//! An MMX register aliases the low 64 bits of an x87 register. Writing it
+//! through an MMX instruction also sets the x87 exponent bits to all ones,
+//! which the setters here reproduce.
+
+use super::*;
+
+macro_rules! packed {
+    ($($(#[$attr:meta])* $get:ident, $set:ident: [$t:ty; $n:literal];)*) => {
+        $(
+            $(#[$attr])*
+            #[inline]
+            pub fn $get(&self) -> [$t; $n] {
+                let mut lanes = [0; $n];
+                let size = size_of::<$t>();
+                for (lane, bytes) in lanes.iter_mut().zip(self.0[..8].chunks_exact(size)) {
+                    let mut raw = [0; size_of::<$t>()];
+                    raw.copy_from_slice(bytes);
+                    *lane = <$t>::from_le_bytes(raw);
+                }
+
+                lanes
+            }
+
+            $(#[$attr])*
+            #[inline]
+            pub fn $set(&mut self, lanes: [$t; $n]) {
+                let size = size_of::<$t>();
+                for (lane, bytes) in lanes.iter().zip(self.0[..8].chunks_exact_mut(size)) {
+                    bytes.copy_from_slice(&lane.to_le_bytes());
+                }
+
+                self.0[8..].fill(0xFF);
+            }
+        )*
+    };
+}
+
+impl Mm {
+    /// Read the register as a 64-bit integer
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        u64::from_le_bytes(self.as_u8x8())
+    }
+
+    /// Write the register as a 64-bit integer
+    #[inline]
+    pub fn set_u64(&mut self, value: u64) {
+        self.set_u8x8(value.to_le_bytes())
+    }
+
+    packed! {
+        /// The register as eight signed bytes, lowest first
+        as_i8x8, set_i8x8: [i8; 8];
+
+        /// The register as eight unsigned bytes, lowest first
+        as_u8x8, set_u8x8: [u8; 8];
+
+        /// The register as four signed words, lowest first
+        as_i16x4, set_i16x4: [i16; 4];
+
+        /// The register as four unsigned words, lowest first
+        as_u16x4, set_u16x4: [u16; 4];
+
+        /// The register as two signed doublewords, lowest first
+        as_i32x2, set_i32x2: [i32; 2];
+
+        /// The register as two unsigned doublewords, lowest first
+        as_u32x2, set_u32x2: [u32; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed() {
+        let mut mm = Mm::DEFAULT;
+        mm.set_i16x4([-1, 2, -3, 4]);
+        assert_eq!(mm.0[8..], [0xFF, 0xFF]);
+        assert_eq!(mm.as_i16x4(), [-1, 2, -3, 4]);
+        assert_eq!(mm.as_u16x4(), [0xFFFF, 2, 0xFFFD, 4]);
+        assert_eq!(mm.as_u64(), 0x0004_FFFD_0002_FFFF);
+        assert_eq!(mm.as_i8x8(), [-1, -1, 2, 0, -3, -1, 4, 0]);
+
+        mm.set_i32x2([i32::MIN, 7]);
+        assert_eq!(mm.as_u32x2(), [0x8000_0000, 7]);
+
+        mm.set_u64(0x0102_0304_0506_0708);
+        assert_eq!(mm.as_u8x8(), [8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Validation of the MPX bounds components
+//!
+//! MPX is deprecated, but guests which used it may still hand over state
+//! holding its components. Loading corrupt bounds state faults, so tools
+//! which accept such state can check it beforehand.
+
+use super::*;
+
+/// The number of linear address bits checked for canonical form
+const LINEAR_BITS: u32 = 48;
+
+/// The `BNDCFGU` bits which are not part of the bound directory base
+const BNDCFGU_FLAGS: u64 = 0b11;
+
+/// The alignment of the bound directory base
+const BNDCFGU_ALIGN: u64 = 1 << 12;
+
+/// Whether an address is in canonical form
+#[inline]
+const fn canonical(addr: u64) -> bool {
+    let shift = 64 - LINEAR_BITS;
+    ((addr << shift) as i64 >> shift) as u64 == addr
+}
+
+impl XSave {
+    /// Check that every `BND0`-`BND3` bound is a canonical address
+    ///
+    /// Upper bounds are stored in one's complement, and are checked after
+    /// inverting. A bound found out of form is reported as
+    /// `XSaveError::NonCanonical` with its offset in the buffer.
+    pub fn check_bndregs(&self) -> Result<(), XSaveError> {
+        let (start, _) = match self.component_range(Component::BndRegs) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        let bytes = self.component_bytes(Component::BndRegs);
+        for reg in 0..4 {
+            let lower = reg * 16;
+            let upper = lower + 8;
+
+            if !canonical(regs::u64_at(bytes, lower)) {
+                return Err(XSaveError::NonCanonical(start + lower));
+            }
+
+            if !canonical(!regs::u64_at(bytes, upper)) {
+                return Err(XSaveError::NonCanonical(start + upper));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the bound directory base in `BNDCFGU` is valid
+    ///
+    /// The base must be canonical and 4 KiB aligned, which means the
+    /// reserved bits 11:2 are clear. A misaligned base is reported as
+    /// `XSaveError::Misaligned` with the base address.
+    pub fn check_bndcsr(&self) -> Result<(), XSaveError> {
+        let (start, _) = match self.component_range(Component::BndCsr) {
+            Some(range) => range,
+            None => return Ok(()),
+        };
+
+        let base = regs::u64_at(self.component_bytes(Component::BndCsr), 0) & !BNDCFGU_FLAGS;
+        if base % BNDCFGU_ALIGN != 0 {
+            return Err(XSaveError::Misaligned(base as usize));
+        }
+
+        if !canonical(base) {
+            return Err(XSaveError::NonCanonical(start));
+        }
+
+        Ok(())
+    }
+
+    /// Check both MPX components, in the manner of the individual checks
+    pub fn check_mpx(&self) -> Result<(), XSaveError> {
+        self.check_bndregs()?;
+        self.check_bndcsr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical() {
+        assert!(super::canonical(0));
+        assert!(super::canonical(!0));
+        assert!(super::canonical(0x0000_7FFF_FFFF_F000));
+        assert!(super::canonical(0xFFFF_8000_0000_0000));
+        assert!(!super::canonical(0x0000_8000_0000_0000));
+        assert!(!super::canonical(0x1000_0000_0000_0000));
+    }
+
+    #[test]
+    fn bounds() {
+        let mut xsave = XSave::DEFAULT;
+        assert_eq!(xsave.check_mpx(), Ok(()));
+
+        xsave.enable_mpx().unwrap();
+        assert_eq!(xsave.check_mpx(), Ok(()));
+
+        // BND1 upper bound, stored inverted
+        xsave.as_bytes_mut()[960 + 24..960 + 32].copy_from_slice(&0x8000u64.to_le_bytes());
+        assert_eq!(xsave.check_mpx(), Ok(()));
+        xsave.as_bytes_mut()[960 + 31] = 0x40;
+        assert_eq!(xsave.check_bndregs(), Err(XSaveError::NonCanonical(984)));
+
+        // Absent components are in their initial configuration
+        xsave.header.xstate_bv -= XStateBv::BNDREGS;
+        assert_eq!(xsave.check_bndregs(), Ok(()));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn config() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.enable_mpx().unwrap();
+
+        let bndcfgu = &mut xsave.as_bytes_mut()[1024..1032];
+        bndcfgu.copy_from_slice(&0x7FFF_1234_5003u64.to_le_bytes());
+        assert_eq!(xsave.check_bndcsr(), Ok(()));
+
+        xsave.as_bytes_mut()[1024] = 0x07;
+        assert_eq!(
+            xsave.check_bndcsr(),
+            Err(XSaveError::Misaligned(0x7FFF_1234_5004))
+        );
+
+        xsave.as_bytes_mut()[1024] = 0;
+        xsave.as_bytes_mut()[1029] = 0x80;
+        assert_eq!(xsave.check_mpx(), Err(XSaveError::NonCanonical(1024)));
+    }
+}
@@ -0,0 +1,351 @@
+//! Model specific register access
+//!
+//! The helpers in this module are generic over `MsrAccess`, so the same
+//! code paths can be driven by a kernel, a hypervisor emulating MSRs for
+//! its guests, or a mock in unit tests.
+
+#[cfg(all(xsave_asm, target_arch = "x86_64"))]
+use core::arch::asm;
+
+#[cfg(target_arch = "x86_64")]
+use super::component::{cpuid, has_xsave};
+use super::*;
+
+/// The MSR holding the enabled supervisor state components
+pub const IA32_XSS: u32 = 0xDA0;
+
+/// The MSR holding the user state components whose first use faults
+pub const IA32_XFD: u32 = 0x1C4;
+
+/// The MSR recording the components whose use raised an XFD fault
+pub const IA32_XFD_ERR: u32 = 0x1C5;
+
+/// Access to model specific registers
+pub trait MsrAccess {
+    /// Read an MSR
+    fn rdmsr(&mut self, msr: u32) -> u64;
+
+    /// Write an MSR
+    fn wrmsr(&mut self, msr: u32, value: u64);
+}
+
+/// Access to the MSRs of the current CPU using `rdmsr` and `wrmsr`
+#[cfg(all(xsave_asm, target_arch = "x86_64"))]
+#[derive(Debug)]
+pub struct HardwareMsr(());
+
+#[cfg(all(xsave_asm, target_arch = "x86_64"))]
+impl HardwareMsr {
+    /// Create a new instance
+    ///
+    /// # Safety
+    ///
+    /// The caller must be running at CPL 0 and must ensure that every MSR
+    /// read or written through this instance exists and that the values
+    /// written are valid, since otherwise the instructions fault.
+    #[inline]
+    pub const unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+#[cfg(all(xsave_asm, target_arch = "x86_64"))]
+impl MsrAccess for HardwareMsr {
+    #[inline]
+    fn rdmsr(&mut self, msr: u32) -> u64 {
+        let mut lo = 0u32;
+        let mut hi = 0u32;
+
+        #[cfg(all(xsave_asm, not(doc)))]
+        unsafe {
+            asm!(
+                "rdmsr",
+                in("ecx") msr,
+                inout("eax") lo,
+                inout("edx") hi,
+                options(nomem, nostack, preserves_flags),
+            )
+        }
+
+        u64::from(lo) | u64::from(hi) << 32
+    }
+
+    #[inline]
+    fn wrmsr(&mut self, msr: u32, value: u64) {
+        #[cfg(all(xsave_asm, not(doc)))]
+        unsafe {
+            asm!(
+                "wrmsr",
+                in("ecx") msr,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+}
+
+/// Read the enabled supervisor state components from IA32_XSS
+#[inline]
+pub fn read_xss<M: MsrAccess>(msr: &mut M) -> XStateBv {
+    XStateBv {
+        bits: msr.rdmsr(IA32_XSS),
+    }
+}
+
+/// Write the enabled supervisor state components to IA32_XSS
+///
+/// User components may not be enabled in IA32_XSS; attempting to do so
+/// returns `XSaveError::InvalidMask` without writing the MSR.
+#[inline]
+pub fn write_xss<M: MsrAccess>(msr: &mut M, xss: XStateBv) -> Result<(), XSaveError> {
+    let masks = XStateMasks {
+        xcr0: XStateBv::X87,
+        xss,
+    };
+
+    masks.validate()?;
+    msr.wrmsr(IA32_XSS, xss.bits);
+    Ok(())
+}
+
+/// Read the user components which support extended feature disable
+///
+/// These are the components with `CPUID.(EAX=0DH,ECX=i):ECX[2]` set, of
+/// which only AMX tile data exists today. The set is empty if the CPU does
+/// not support XFD.
+#[cfg(target_arch = "x86_64")]
+pub fn xfd_supported() -> XStateBv {
+    let mut xfd = XStateBv::empty();
+    if !has_xsave() || cpuid(0xD, 1).eax & (1 << 4) == 0 {
+        return xfd;
+    }
+
+    for i in 2..64 {
+        if cpuid(0xD, i).ecx & (1 << 2) != 0 {
+            xfd.bits |= 1 << i;
+        }
+    }
+
+    xfd
+}
+
+/// Read the components whose first use is disabled from IA32_XFD
+#[inline]
+pub fn read_xfd<M: MsrAccess>(msr: &mut M) -> XStateBv {
+    XStateBv {
+        bits: msr.rdmsr(IA32_XFD),
+    }
+}
+
+/// Write the components whose first use is disabled to IA32_XFD
+///
+/// The x87 and SSE state and supervisor components can never be disabled;
+/// attempting to do so returns `XSaveError::InvalidMask` without writing
+/// the MSR. Other components the CPU does not enumerate in
+/// `xfd_supported()` make `wrmsr` fault.
+#[inline]
+pub fn write_xfd<M: MsrAccess>(msr: &mut M, xfd: XStateBv) -> Result<(), XSaveError> {
+    check_xfd(xfd)?;
+    msr.wrmsr(IA32_XFD, xfd.bits);
+    Ok(())
+}
+
+/// Check that components may be disabled in IA32_XFD
+#[inline]
+fn check_xfd(xfd: XStateBv) -> Result<(), XSaveError> {
+    let invalid =
+        xfd & (XStateBv::X87 | XStateBv::SSE | ComponentTable::ARCHITECTURAL.supervisor());
+    match invalid.is_empty() {
+        true => Ok(()),
+        false => Err(XSaveError::InvalidMask(invalid)),
+    }
+}
+
+/// Read the components whose use faulted from IA32_XFD_ERR
+#[inline]
+pub fn read_xfd_err<M: MsrAccess>(msr: &mut M) -> XStateBv {
+    XStateBv {
+        bits: msr.rdmsr(IA32_XFD_ERR),
+    }
+}
+
+/// Clear IA32_XFD_ERR, which the CPU never clears itself
+#[inline]
+pub fn clear_xfd_err<M: MsrAccess>(msr: &mut M) {
+    msr.wrmsr(IA32_XFD_ERR, 0);
+}
+
+/// Handle a device-not-available fault raised by extended feature disable
+///
+/// Call this from the #NM handler. If IA32_XFD_ERR is empty, the fault
+/// has another cause and the empty set is returned without changing
+/// anything. Otherwise IA32_XFD_ERR is cleared, the faulting components
+/// are removed from IA32_XFD, and `area` is enlarged to hold them at their
+/// standard format offsets, so that the faulting instruction can be
+/// resumed. The components are returned, for the caller to record that
+/// the context now uses them.
+///
+/// Nothing is changed on failure. Faulting components which the area
+/// cannot locate are reported as `XSaveError::UnsupportedComponents`, and
+/// an IA32_XFD which `write_xfd()` would reject as
+/// `XSaveError::InvalidMask`.
+#[cfg(feature = "alloc")]
+pub fn handle_xfd_fault<M: MsrAccess>(
+    msr: &mut M,
+    area: &mut XSaveDyn,
+) -> Result<XStateBv, XSaveError> {
+    let faulted = read_xfd_err(msr);
+    if faulted.is_empty() {
+        return Ok(faulted);
+    }
+
+    let table = area.table();
+    let mut unknown = XStateBv::empty();
+    for i in 0..64 {
+        let sized = table.get(i).map_or(false, |c| c.size != 0 && !c.supervisor);
+        if faulted.bits & (1 << i) != 0 && !sized {
+            unknown.bits |= 1 << i;
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(XSaveError::UnsupportedComponents(unknown));
+    }
+
+    let xfd = read_xfd(msr) - faulted;
+    check_xfd(xfd)?;
+
+    let size = size_for(area.header().xstate_bv | faulted, false, table);
+
+    clear_xfd_err(msr);
+    msr.wrmsr(IA32_XFD, xfd.bits);
+    area.grow(size);
+    Ok(faulted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Mock {
+        xss: u64,
+        xfd: u64,
+        xfd_err: u64,
+    }
+
+    impl Mock {
+        fn msr(&mut self, msr: u32) -> &mut u64 {
+            match msr {
+                IA32_XSS => &mut self.xss,
+                IA32_XFD => &mut self.xfd,
+                IA32_XFD_ERR => &mut self.xfd_err,
+                _ => panic!("unexpected MSR {:#x}", msr),
+            }
+        }
+    }
+
+    impl MsrAccess for Mock {
+        fn rdmsr(&mut self, msr: u32) -> u64 {
+            *self.msr(msr)
+        }
+
+        fn wrmsr(&mut self, msr: u32, value: u64) {
+            *self.msr(msr) = value;
+        }
+    }
+
+    #[test]
+    fn xss() {
+        let mut mock = Mock::default();
+        assert_eq!(read_xss(&mut mock), XStateBv::empty());
+
+        write_xss(&mut mock, XStateBv::PT).unwrap();
+        assert_eq!(read_xss(&mut mock), XStateBv::PT);
+
+        assert_eq!(
+            write_xss(&mut mock, XStateBv::PT | XStateBv::AVX),
+            Err(XSaveError::InvalidMask(XStateBv::AVX))
+        );
+        assert_eq!(mock.xss, XStateBv::PT.bits);
+    }
+
+    #[test]
+    fn xfd() {
+        let mut mock = Mock::default();
+        write_xfd(&mut mock, XStateBv::AMX_TILEDATA).unwrap();
+        assert_eq!(read_xfd(&mut mock), XStateBv::AMX_TILEDATA);
+
+        assert_eq!(
+            write_xfd(&mut mock, XStateBv::SSE | XStateBv::PT),
+            Err(XSaveError::InvalidMask(XStateBv::SSE | XStateBv::PT))
+        );
+        assert_eq!(mock.xfd, XStateBv::AMX_TILEDATA.bits);
+
+        mock.xfd_err = XStateBv::AMX_TILEDATA.bits;
+        assert_eq!(read_xfd_err(&mut mock), XStateBv::AMX_TILEDATA);
+        clear_xfd_err(&mut mock);
+        assert_eq!(mock.xfd_err, 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn supported() {
+        let xfd = xfd_supported();
+        assert!(!xfd.intersects(XStateBv::X87 | XStateBv::SSE | XStateBv::PT));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn fault() {
+        let mut mock = Mock::default();
+        let mut area = XSaveDyn::with_size(2816).unwrap();
+
+        assert_eq!(
+            handle_xfd_fault(&mut mock, &mut area),
+            Ok(XStateBv::empty())
+        );
+        assert_eq!(area.size(), 2816);
+
+        mock.xfd = (XStateBv::AMX_TILEDATA | XStateBv::AMX_TILECFG).bits;
+        mock.xfd_err = XStateBv::AMX_TILEDATA.bits;
+        assert_eq!(
+            handle_xfd_fault(&mut mock, &mut area),
+            Ok(XStateBv::AMX_TILEDATA)
+        );
+        assert_eq!(mock.xfd_err, 0);
+        assert_eq!(mock.xfd, XStateBv::AMX_TILECFG.bits);
+        assert_eq!(area.size(), 2816 + 8192);
+        assert!(area.rfbm().contains(XStateBv::AMX_TILEDATA));
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn fault_unchanged() {
+        let mut mock = Mock::default();
+        let mut area = XSaveDyn::with_size(2816).unwrap();
+
+        mock.xfd = XStateBv::AMX_TILEDATA.bits | 1 << 19;
+        mock.xfd_err = XStateBv::AMX_TILEDATA.bits | 1 << 19;
+        assert_eq!(
+            handle_xfd_fault(&mut mock, &mut area),
+            Err(XSaveError::UnsupportedComponents(XStateBv {
+                bits: 1 << 19
+            }))
+        );
+        assert_eq!(mock.xfd_err, XStateBv::AMX_TILEDATA.bits | 1 << 19);
+        assert_eq!(mock.xfd, XStateBv::AMX_TILEDATA.bits | 1 << 19);
+        assert_eq!(area.size(), 2816);
+
+        mock.xfd = (XStateBv::AMX_TILEDATA | XStateBv::SSE).bits;
+        mock.xfd_err = XStateBv::AMX_TILEDATA.bits;
+        assert_eq!(
+            handle_xfd_fault(&mut mock, &mut area),
+            Err(XSaveError::InvalidMask(XStateBv::SSE))
+        );
+        assert_eq!(mock.xfd_err, XStateBv::AMX_TILEDATA.bits);
+        assert_eq!(mock.xfd, (XStateBv::AMX_TILEDATA | XStateBv::SSE).bits);
+        assert_eq!(area.size(), 2816);
+    }
+}
@@ -0,0 +1,162 @@
+//! Component mask policy for nested virtualization
+
+use super::*;
+
+/// A pair of user (XCR0) and supervisor (IA32_XSS) component masks
+///
+/// This describes the components supported or enabled at one level of a
+/// virtualization stack.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct XStateMasks {
+    pub xcr0: XStateBv,
+    pub xss: XStateBv,
+}
+
+impl XStateMasks {
+    /// Check that the masks obey the architectural rules
+    ///
+    /// These are the rules enforced by `xsetbv` and `wrmsr` (Section
+    /// 13.3). The offending bits are returned on failure.
+    #[inline]
+    pub fn validate(&self) -> Result<(), XSaveError> {
+        let xcr0 = self.xcr0;
+
+        let supervisor = ComponentTable::ARCHITECTURAL.supervisor();
+        let misplaced = (xcr0 & supervisor) | (self.xss - supervisor);
+        if !misplaced.is_empty() {
+            return Err(XSaveError::InvalidMask(misplaced));
+        }
+
+        if !xcr0.contains(XStateBv::X87) {
+            return Err(XSaveError::InvalidMask(XStateBv::X87));
+        }
+
+        if xcr0.contains(XStateBv::AVX) && !xcr0.contains(XStateBv::SSE) {
+            return Err(XSaveError::InvalidMask(XStateBv::AVX));
+        }
+
+        let mpx = XStateBv::BNDREGS | XStateBv::BNDCSR;
+        if xcr0.intersects(mpx) && !xcr0.contains(mpx) {
+            return Err(XSaveError::InvalidMask(xcr0 & mpx));
+        }
+
+        if xcr0.intersects(XStateBv::AVX512) {
+            xcr0.check_avx512()?;
+
+            if !xcr0.contains(XStateBv::SSE | XStateBv::AVX) {
+                return Err(XSaveError::InvalidMask(XStateBv::AVX512));
+            }
+        }
+
+        let amx = XStateBv::AMX_TILECFG | XStateBv::AMX_TILEDATA;
+        if xcr0.intersects(amx) && !xcr0.contains(amx) {
+            return Err(XSaveError::InvalidMask(xcr0 & amx));
+        }
+
+        Ok(())
+    }
+
+    /// Compute the masks for a guest running on top of these masks
+    ///
+    /// The guest's masks must be valid and must be a subset of these. On
+    /// success, the guest's masks are the effective save/restore masks
+    /// while the guest runs.
+    pub fn nest(&self, guest: Self) -> Result<Self, XSaveError> {
+        guest.validate()?;
+
+        let unsupported = (guest.xcr0 - self.xcr0) | (guest.xss - self.xss);
+        if !unsupported.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(unsupported));
+        }
+
+        Ok(guest)
+    }
+}
+
+/// Compute the effective masks for a nested (L2) guest
+///
+/// `l0` describes the host capabilities, `l1` the masks the L1 hypervisor
+/// advertises to its guests and `l2` the masks requested by the L2 guest.
+/// Every level is validated and each must be a subset of the level below.
+pub fn merge_nested(
+    l0: XStateMasks,
+    l1: XStateMasks,
+    l2: XStateMasks,
+) -> Result<XStateMasks, XSaveError> {
+    l0.validate()?;
+    l0.nest(l1)?.nest(l2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSE: XStateBv = XStateBv::from_bits_truncate(0b11);
+    const AVX: XStateBv = XStateBv::from_bits_truncate(0b111);
+    const AVX512: XStateBv = XStateBv::from_bits_truncate(0b1110_0111);
+
+    fn masks(xcr0: XStateBv) -> XStateMasks {
+        XStateMasks {
+            xcr0,
+            xss: XStateBv::empty(),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(masks(AVX512).validate(), Ok(()));
+        assert_eq!(
+            masks(XStateBv::SSE).validate(),
+            Err(XSaveError::InvalidMask(XStateBv::X87))
+        );
+        assert_eq!(
+            masks(AVX | XStateBv::AVX512_OPMASK).validate(),
+            Err(XSaveError::InvalidMask(XStateBv::AVX512_OPMASK))
+        );
+        assert_eq!(
+            masks(AVX | XStateBv::PT).validate(),
+            Err(XSaveError::InvalidMask(XStateBv::PT))
+        );
+    }
+
+    #[test]
+    fn supervisor() {
+        let cet = XStateBv { bits: 0b11 << 11 };
+        let host = XStateMasks {
+            xcr0: AVX,
+            xss: XStateBv::PT | cet,
+        };
+        assert_eq!(host.validate(), Ok(()));
+
+        assert_eq!(
+            masks(AVX | cet).validate(),
+            Err(XSaveError::InvalidMask(cet))
+        );
+        assert_eq!(
+            XStateMasks {
+                xcr0: AVX,
+                xss: XStateBv::PKRU,
+            }
+            .validate(),
+            Err(XSaveError::InvalidMask(XStateBv::PKRU))
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let l0 = XStateMasks {
+            xcr0: AVX512 | XStateBv::PKRU,
+            xss: XStateBv::PT,
+        };
+
+        assert_eq!(merge_nested(l0, masks(AVX), masks(SSE)), Ok(masks(SSE)));
+        assert_eq!(
+            merge_nested(l0, masks(AVX), masks(AVX512)),
+            Err(XSaveError::UnsupportedComponents(AVX512 - AVX))
+        );
+        assert_eq!(
+            merge_nested(masks(SSE), masks(AVX), masks(SSE)),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AVX))
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! A compact wire format for selected components
+//!
+//! For sending state over a narrow channel, such as vsock or a serial line
+//! to a debug or attestation agent, the full area is wasteful. The packed
+//! format is a version byte and the little-endian `u64` mask of the
+//! components which follow, then the data of each component in index
+//! order, laid out as in the XSAVE area with every field little-endian,
+//! without any padding. The X87 data is the x87 control fields and
+//! registers; the SSE data is MXCSR, MXCSR_MASK and the XMM registers.
+//! Unpacking always produces the standard format.
+
+use core::ops::Range;
+
+use super::*;
+
+/// The version of the packed format
+const VERSION: u8 = 1;
+
+/// The size of the packed header
+const HEADER: usize = 1 + size_of::<u64>();
+
+/// The components which have room in the buffer and can be packed
+///
+/// The extended components use their standard format offsets, so this is
+/// the same whatever the format of the buffer.
+fn packable() -> XStateBv {
+    Component::ALL
+        .iter()
+        .filter(|c| segments(&XSave::DEFAULT, **c).is_some())
+        .fold(XStateBv::empty(), |acc, c| acc | c.bit())
+}
+
+/// The byte ranges in the buffer holding the data of a component
+fn segments(xsave: &XSave, component: Component) -> Option<[Range<usize>; 2]> {
+    match component {
+        Component::X87 => Some([0..24, 32..160]),
+        Component::Sse => Some([24..32, 160..416]),
+        _ => xsave
+            .component_range(component)
+            .map(|(start, end)| [start..end, end..end]),
+    }
+}
+
+impl XSave {
+    /// The size of the packed form of the given components
+    ///
+    /// Only the components which are requested and present are packed.
+    pub fn packed_len(&self, mask: XStateBv) -> Result<usize, XSaveError> {
+        let mut len = HEADER;
+        for component in self.packed_components(mask)? {
+            if let Some(segs) = segments(self, component) {
+                len += segs.iter().map(|r| r.len()).sum::<usize>();
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// The components which would be packed for a mask
+    fn packed_components(
+        &self,
+        mask: XStateBv,
+    ) -> Result<impl Iterator<Item = Component> + '_, XSaveError> {
+        let unsupported = mask - packable();
+        if !unsupported.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(unsupported));
+        }
+
+        let mask = mask & self.header.xstate_bv;
+        Ok(Component::ALL
+            .into_iter()
+            .filter(move |c| mask.contains(c.bit()))
+            .filter(move |c| segments(self, *c).is_some()))
+    }
+
+    /// Pack the requested components into a buffer, returning its length
+    ///
+    /// Requested components which are absent from `xstate_bv` are left out
+    /// and are initialized when unpacked. This fails with
+    /// `XSaveError::UnsupportedComponents` for components which cannot be
+    /// packed, and with `XSaveError::InvalidLength`, holding the length
+    /// required, if the buffer is too short.
+    pub fn pack_into(&self, mask: XStateBv, buf: &mut [u8]) -> Result<usize, XSaveError> {
+        let len = self.packed_len(mask)?;
+        if buf.len() < len {
+            return Err(XSaveError::InvalidLength(len));
+        }
+
+        let mut packed = XStateBv::empty();
+        let mut offset = HEADER;
+        let bytes = self.le_bytes();
+
+        for component in self.packed_components(mask)? {
+            let segs = segments(self, component).unwrap_or([0..0, 0..0]);
+            for range in segs {
+                let end = offset + range.len();
+                buf[offset..end].copy_from_slice(&bytes[range]);
+                offset = end;
+            }
+
+            packed |= component.bit();
+        }
+
+        buf[0] = VERSION;
+        buf[1..HEADER].copy_from_slice(&packed.bits.to_le_bytes());
+        Ok(offset)
+    }
+}
+
+/// The packed data at the start of a buffer which may hold more
+pub(crate) fn prefix(bytes: &[u8]) -> Result<&[u8], XSaveError> {
+    let mut bits = [0; 8];
+    match bytes.get(1..HEADER) {
+        Some(mask) => bits.copy_from_slice(mask),
+        None => return Err(XSaveError::InvalidLength(bytes.len())),
+    }
+
+    let mask = XStateBv {
+        bits: u64::from_le_bytes(bits),
+    };
+
+    let mut xsave = XSave::DEFAULT;
+    xsave.header.xstate_bv = mask;
+    let len = xsave.packed_len(mask)?;
+    bytes
+        .get(..len)
+        .ok_or(XSaveError::InvalidLength(bytes.len()))
+}
+
+impl XSave {
+    /// Unpack components packed by `XSave::pack_into()`
+    ///
+    /// The packed components must all be enabled in `xcr0`, which is
+    /// usually that of the local CPU, or this fails with
+    /// `XSaveError::UnsupportedComponents`. The result is in the standard
+    /// format, with every component which was not packed absent.
+    pub fn unpack(bytes: &[u8], xcr0: XStateBv) -> Result<Self, XSaveError> {
+        let (version, rest) = match bytes {
+            [version, rest @ ..] if rest.len() >= HEADER - 1 => (*version, rest),
+            _ => return Err(XSaveError::InvalidLength(bytes.len())),
+        };
+
+        if version != VERSION {
+            return Err(XSaveError::UnsupportedVersion(version.into()));
+        }
+
+        let mut bits = [0; 8];
+        bits.copy_from_slice(&rest[..8]);
+        let mask = XStateBv {
+            bits: u64::from_le_bytes(bits),
+        };
+
+        let unsupported = mask - (packable() & xcr0);
+        if !unsupported.is_empty() {
+            return Err(XSaveError::UnsupportedComponents(unsupported));
+        }
+
+        let mut xsave = Self::DEFAULT;
+        xsave.header.xstate_bv = mask;
+        if xsave.packed_len(mask)? != bytes.len() {
+            return Err(XSaveError::InvalidLength(bytes.len()));
+        }
+
+        let mut image = xsave.le_bytes();
+        let mut offset = HEADER;
+        for component in Component::ALL.iter().filter(|c| mask.contains(c.bit())) {
+            let segs = segments(&xsave, *component).unwrap_or([0..0, 0..0]);
+            for range in segs {
+                let end = offset + range.len();
+                image[range].copy_from_slice(&bytes[offset..end]);
+                offset = end;
+            }
+        }
+
+        let mut xsave = Self::from_le_bytes(&image);
+
+        xsave.legacy.set_mxcsr_checked(xsave.legacy.mxcsr)?;
+        Ok(xsave)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Pkru).unwrap();
+        xsave.legacy.fcw = Fcw::empty();
+        xsave.legacy.xmm[0].0[0] = 0xAA;
+        xsave.as_bytes_mut()[2688] = 0x55;
+
+        let mask = XStateBv::SSE | XStateBv::AVX | XStateBv::PKRU;
+        assert_eq!(xsave.packed_len(mask), Ok(HEADER + 264 + 8));
+
+        let mut buf = [0xFF; 512];
+        let len = xsave.pack_into(mask, &mut buf).unwrap();
+        assert_eq!(len, HEADER + 264 + 8);
+        assert_eq!(buf[0], VERSION);
+        assert_eq!(
+            buf[1..HEADER],
+            (XStateBv::SSE | XStateBv::PKRU).bits.to_le_bytes()
+        );
+        assert_eq!(buf[HEADER..HEADER + 4], 0x1F80u32.to_le_bytes());
+        assert_eq!(buf[HEADER + 8], 0xAA);
+        assert_eq!(buf[HEADER + 264], 0x55);
+
+        assert_eq!(
+            xsave.pack_into(mask, &mut buf[..100]),
+            Err(XSaveError::InvalidLength(len))
+        );
+        assert_eq!(
+            xsave.pack_into(XStateBv::AMX_TILEDATA | XStateBv::PT, &mut buf),
+            Err(XSaveError::UnsupportedComponents(
+                XStateBv::AMX_TILEDATA | XStateBv::PT
+            ))
+        );
+    }
+
+    #[test]
+    fn unpack() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.enable(Component::Pkru).unwrap();
+        xsave.legacy.fip = 0x1234;
+        xsave.legacy.xmm[15].0[15] = 0xAA;
+        xsave.as_bytes_mut()[2689] = 0x55;
+
+        let mut buf = [0; 512];
+        let mask = XStateBv::X87 | XStateBv::SSE | XStateBv::PKRU;
+        let len = xsave.pack_into(mask, &mut buf).unwrap();
+        let packed = &buf[..len];
+
+        let copy = XSave::unpack(packed, XStateBv::all()).unwrap();
+        assert_eq!(copy.header.xstate_bv, mask);
+        assert_eq!(copy.as_bytes()[..], xsave.as_bytes()[..]);
+
+        assert_eq!(
+            XSave::unpack(packed, XStateBv::X87 | XStateBv::SSE).err(),
+            Some(XSaveError::UnsupportedComponents(XStateBv::PKRU))
+        );
+        assert_eq!(
+            XSave::unpack(&packed[..len - 1], XStateBv::all()).err(),
+            Some(XSaveError::InvalidLength(len - 1))
+        );
+        assert_eq!(
+            XSave::unpack(&packed[..4], XStateBv::all()).err(),
+            Some(XSaveError::InvalidLength(4))
+        );
+
+        buf[0] = 2;
+        assert_eq!(
+            XSave::unpack(&buf[..len], XStateBv::all()).err(),
+            Some(XSaveError::UnsupportedVersion(2))
+        );
+
+        // MXCSR, after the X87 data, with a bit outside MXCSR_MASK
+        buf[0] = VERSION;
+        buf[HEADER + 152 + 2] = 0x01;
+        assert_eq!(
+            XSave::unpack(&buf[..len], XStateBv::all()).err(),
+            Some(XSaveError::MxCsrReserved(0x10000))
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! XSave buffers padded to the sizes used by other ABIs
+
+use core::ops::{Deref, DerefMut};
+
+use super::*;
+
+/// The size of the region in `struct kvm_xsave`
+const KVM_REGION: usize = 4096;
+
+/// An `XSave` padded to the 4KB region of `struct kvm_xsave`
+///
+/// The type is layout compatible with `struct kvm_xsave`, so the
+/// `KVM_GET_XSAVE` and `KVM_SET_XSAVE` ioctls can be pointed straight at
+/// it. The padding is exposed through `view()`.
+#[repr(C, align(4096))]
+#[derive(Clone, Copy, Debug)]
+pub struct XSave4K {
+    xsave: XSave,
+    padding: [u8; KVM_REGION - size_of::<XSave>()],
+}
+
+impl ConstDefault for XSave4K {
+    const DEFAULT: Self = Self {
+        xsave: XSave::DEFAULT,
+        padding: [0; KVM_REGION - size_of::<XSave>()],
+    };
+}
+
+impl Default for XSave4K {
+    #[inline]
+    fn default() -> Self {
+        XSave::default().into()
+    }
+}
+
+impl From<XSave> for XSave4K {
+    #[inline]
+    fn from(xsave: XSave) -> Self {
+        Self {
+            xsave,
+            ..Self::DEFAULT
+        }
+    }
+}
+
+impl From<XSave4K> for XSave {
+    #[inline]
+    fn from(xsave: XSave4K) -> Self {
+        xsave.xsave
+    }
+}
+
+impl XSave4K {
+    /// View the whole region, including the padding
+    #[inline]
+    pub fn view(&mut self) -> XSaveView<'_> {
+        // SAFETY: `XSave4K` has no implicit padding and is 4096 bytes.
+        let region = unsafe { &mut *(self as *mut Self as *mut [u8; KVM_REGION]) };
+
+        match XSaveView::new(region) {
+            Ok(view) => view,
+            Err(..) => unreachable!("XSave4K is aligned"),
+        }
+    }
+}
+
+impl Deref for XSave4K {
+    type Target = XSave;
+
+    #[inline]
+    fn deref(&self) -> &XSave {
+        &self.xsave
+    }
+}
+
+impl DerefMut for XSave4K {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut XSave {
+        &mut self.xsave
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::align_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<XSave4K>(), 4096);
+        assert_eq!(align_of::<XSave4K>(), 4096);
+    }
+
+    #[test]
+    fn view() {
+        let mut xsave = XSave4K::from(XSave::fxsave_default());
+        xsave.padding[0] = 0xAA;
+
+        let mut view = xsave.view();
+        assert_eq!(view.header().xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(view.extended()[size_of::<XSave>() - 576], 0xAA);
+
+        view.header_mut().xstate_bv = XStateBv::X87;
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87);
+    }
+}
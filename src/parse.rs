@@ -0,0 +1,376 @@
+//! Conversion of raw byte buffers into `XSave` structures
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::*;
+
+/// The byte ranges of the reserved fields in the legacy area and header
+pub(crate) const RESERVED: [(usize, usize); 11] = [
+    (5, 6),
+    (42, 48),
+    (58, 64),
+    (74, 80),
+    (90, 96),
+    (106, 112),
+    (122, 128),
+    (138, 144),
+    (154, 160),
+    (416, 464),
+    (528, 576),
+];
+
+/// The standard format offset and size of each extended component
+#[cfg(feature = "alloc")]
+const EXTENDED: [(XStateBv, usize, usize); 9] = [
+    (XStateBv::AVX, 576, 256),
+    (XStateBv::BNDREGS, 960, 64),
+    (XStateBv::BNDCSR, 1024, 64),
+    (XStateBv::AVX512_OPMASK, 1088, 64),
+    (XStateBv::AVX512_ZMM_HI256, 1152, 512),
+    (XStateBv::AVX512_HI16_ZMM, 1664, 1024),
+    (XStateBv::PKRU, 2688, 8),
+    (XStateBv::AMX_TILECFG, 2752, 64),
+    (XStateBv::AMX_TILEDATA, 2816, 8192),
+];
+
+/// An inconsistency found by `XSave::parse_lossy()`
+///
+/// Each issue describes both what was wrong with the input and what was
+/// done to recover from it.
+#[cfg(feature = "alloc")]
+#[non_exhaustive]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParseIssue {
+    /// The buffer was shorter than `XSave`; the missing bytes are defaults
+    Truncated(usize),
+
+    /// The buffer was longer than `XSave`; the trailing bytes were ignored
+    Oversized(usize),
+
+    /// The buffer ended before the header; X87 and SSE are assumed present
+    MissingHeader,
+
+    /// MXCSR had reserved bits set; they were cleared
+    MxCsrReserved(u32),
+
+    /// The reserved header bytes were nonzero; they were cleared
+    HeaderReserved,
+
+    /// The buffer is in the compacted format; the extended area was dropped
+    Compacted(XCompBv),
+
+    /// XCOMP_BV was nonzero in the standard format; it was cleared
+    XCompBvNonzero(XCompBv),
+
+    /// XSTATE_BV referenced unrecognized components; they were cleared
+    UnknownComponents(u64),
+
+    /// XSTATE_BV referenced components which never fit in an `XSave`, such
+    /// as AMX tile data; they were cleared
+    UnsupportedComponents(XStateBv),
+
+    /// A present component was cut off; it was reset to its init state
+    TruncatedComponent(XStateBv),
+}
+
+impl XSave {
+    /// Parse a buffer, rejecting any nonzero reserved bits
+    ///
+    /// The buffer must be exactly the size of an `XSave`. Every reserved
+    /// byte in the legacy area and header must be zero, though the bytes
+    /// available to software may hold anything, and MXCSR must not
+    /// set any bits excluded by `mxcsr_mask`. This makes the function
+    /// suitable as an oracle for conformance testing and fuzzing.
+    #[inline]
+    pub fn from_bytes_strict(bytes: &[u8]) -> Result<Self, XSaveError> {
+        let bytes: &[u8; size_of::<Self>()] = match bytes.try_into() {
+            Ok(bytes) => bytes,
+            Err(..) => return Err(XSaveError::InvalidLength(bytes.len())),
+        };
+
+        for (start, end) in RESERVED {
+            let reserved = bytes.get(start..end).unwrap_or_default();
+            if let Some(i) = reserved.iter().position(|b| *b != 0) {
+                return Err(XSaveError::ReservedByte(start + i));
+            }
+        }
+
+        let mut xsave = Self::DEFAULT;
+        *xsave.as_bytes_mut() = *bytes;
+
+        let reserved = xsave.legacy.mxcsr.bits & !xsave.legacy.mxcsr_writable();
+        if reserved != 0 {
+            return Err(XSaveError::MxCsrReserved(reserved));
+        }
+
+        Ok(xsave)
+    }
+
+    /// Parse a possibly damaged buffer, recovering as much state as possible
+    ///
+    /// Unlike a strict parse, this never fails. Every inconsistency found in
+    /// the input is repaired and reported, in the order in which it was
+    /// found. The returned `XSave` is always safe to pass to `load()` on a
+    /// CPU which supports the components marked present.
+    #[cfg(feature = "alloc")]
+    pub fn parse_lossy(bytes: &[u8]) -> (Self, Vec<ParseIssue>) {
+        let mut issues = Vec::new();
+        let mut xsave = Self::DEFAULT;
+
+        let size = size_of::<Self>();
+        let len = bytes.len().min(size);
+        xsave.as_bytes_mut()[..len].copy_from_slice(&bytes[..len]);
+
+        match bytes.len() {
+            n if n < size => issues.push(ParseIssue::Truncated(n)),
+            n if n > size => issues.push(ParseIssue::Oversized(n)),
+            _ => (),
+        }
+
+        if len < size_of::<XSaveLegacy>() + size_of::<XSaveHeader>() {
+            xsave.header = XSaveHeader::DEFAULT;
+            xsave.header.xstate_bv = XStateBv::X87 | XStateBv::SSE;
+            issues.push(ParseIssue::MissingHeader);
+        }
+
+        let writable = xsave.legacy.mxcsr_writable();
+        let reserved = xsave.legacy.mxcsr.bits & !writable;
+        if len >= 32 && reserved != 0 {
+            xsave.legacy.mxcsr.bits &= writable;
+            issues.push(ParseIssue::MxCsrReserved(reserved));
+        }
+
+        if xsave.header.reserved != [0; 6] {
+            xsave.header.reserved = [0; 6];
+            issues.push(ParseIssue::HeaderReserved);
+        }
+
+        let xcomp_bv = xsave.header.xcomp_bv;
+        if xcomp_bv.contains(XCompBv::COMPACT) {
+            xsave.header.xcomp_bv = XCompBv::empty();
+            xsave.header.xstate_bv &= XStateBv::X87 | XStateBv::SSE;
+            xsave.extend = XSaveExtend::DEFAULT;
+            issues.push(ParseIssue::Compacted(xcomp_bv));
+        } else if xcomp_bv.bits != 0 {
+            xsave.header.xcomp_bv = XCompBv::empty();
+            issues.push(ParseIssue::XCompBvNonzero(xcomp_bv));
+        }
+
+        let unknown = xsave.header.xstate_bv.bits & !XStateBv::all().bits;
+        if unknown != 0 {
+            xsave.header.xstate_bv.bits &= XStateBv::all().bits;
+            issues.push(ParseIssue::UnknownComponents(unknown));
+        }
+
+        let unsupported = EXTENDED
+            .iter()
+            .filter(|(_, offset, length)| offset + length > size)
+            .fold(XStateBv::empty(), |acc, (component, ..)| acc | *component)
+            & xsave.header.xstate_bv;
+        if !unsupported.is_empty() {
+            xsave.header.xstate_bv.remove(unsupported);
+            issues.push(ParseIssue::UnsupportedComponents(unsupported));
+        }
+
+        let present = xsave.header.xstate_bv;
+        if present.contains(XStateBv::X87) && len < 160 {
+            let default = XSaveLegacy::DEFAULT;
+            xsave.legacy.fcw = default.fcw;
+            xsave.legacy.fsw = default.fsw;
+            xsave.legacy.ftw = default.ftw;
+            xsave.legacy.fop = default.fop;
+            xsave.legacy.fip = default.fip;
+            xsave.legacy.fdp = default.fdp;
+            xsave.legacy.mm = default.mm;
+            xsave.header.xstate_bv.remove(XStateBv::X87);
+            issues.push(ParseIssue::TruncatedComponent(XStateBv::X87));
+        }
+
+        if present.contains(XStateBv::SSE) && len < 416 {
+            let default = XSaveLegacy::DEFAULT;
+            if len < 32 {
+                xsave.legacy.mxcsr = default.mxcsr;
+                xsave.legacy.mxcsr_mask = default.mxcsr_mask;
+            }
+            xsave.legacy.xmm = default.xmm;
+            xsave.header.xstate_bv.remove(XStateBv::SSE);
+            issues.push(ParseIssue::TruncatedComponent(XStateBv::SSE));
+        }
+
+        for (component, offset, length) in EXTENDED {
+            if present.contains(component) && len < offset + length {
+                let end = offset + length;
+                xsave.as_bytes_mut()[offset..end].fill(0);
+                xsave.header.xstate_bv.remove(component);
+                issues.push(ParseIssue::TruncatedComponent(component));
+            }
+        }
+
+        (xsave, issues)
+    }
+}
+
+impl From<XSave> for [u8; size_of::<XSave>()] {
+    #[inline]
+    fn from(xsave: XSave) -> Self {
+        *xsave.as_bytes()
+    }
+}
+
+impl From<[u8; size_of::<XSave>()]> for XSave {
+    /// Reinterpret raw bytes as an `XSave`
+    ///
+    /// Every bit pattern is a valid `XSave`, but not every one can be
+    /// restored without faulting. Use `XSave::from_bytes_strict()` for
+    /// untrusted input.
+    #[inline]
+    fn from(bytes: [u8; size_of::<XSave>()]) -> Self {
+        let mut xsave = Self::DEFAULT;
+        *xsave.as_bytes_mut() = bytes;
+        xsave
+    }
+}
+
+impl AsRef<[u8]> for XSave {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsMut<[u8]> for XSave {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        let bytes: [u8; size_of::<XSave>()] = XSave::fxsave_default().into();
+        assert_eq!(bytes[512], 0b11);
+
+        let mut xsave = XSave::from(bytes);
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(xsave.as_ref(), &bytes[..]);
+
+        xsave.as_mut()[512] = 0b1;
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87);
+    }
+
+    #[test]
+    fn strict() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::X87 | XStateBv::SSE;
+        let mut bytes = *xsave.as_bytes_mut();
+
+        let parsed = XSave::from_bytes_strict(&bytes).unwrap();
+        assert_eq!(parsed.header.xstate_bv, xsave.header.xstate_bv);
+
+        assert_eq!(
+            XSave::from_bytes_strict(&bytes[..512]).unwrap_err(),
+            XSaveError::InvalidLength(512)
+        );
+
+        bytes[43] = 1;
+        assert_eq!(
+            XSave::from_bytes_strict(&bytes).unwrap_err(),
+            XSaveError::ReservedByte(43)
+        );
+
+        bytes[43] = 0;
+        bytes[463] = 1;
+        assert_eq!(
+            XSave::from_bytes_strict(&bytes).unwrap_err(),
+            XSaveError::ReservedByte(463)
+        );
+
+        bytes[463] = 0;
+        bytes[464..512].fill(0xAA);
+        let parsed = XSave::from_bytes_strict(&bytes).unwrap();
+        assert_eq!(parsed.as_bytes()[464..512], [0xAA; 48]);
+
+        bytes[26] = 1;
+        assert_eq!(
+            XSave::from_bytes_strict(&bytes).unwrap_err(),
+            XSaveError::MxCsrReserved(0x10000)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn lossy_clean() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::X87 | XStateBv::SSE | XStateBv::AVX;
+        let bytes = *xsave.as_bytes_mut();
+
+        let (parsed, issues) = XSave::parse_lossy(&bytes);
+        assert_eq!(issues, []);
+        assert_eq!(parsed.header.xstate_bv, xsave.header.xstate_bv);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn lossy_fxsave() {
+        let mut legacy = [0u8; 512];
+        legacy[160] = 0xAA;
+        legacy[24..28].copy_from_slice(&0xFFFF_1F80u32.to_le_bytes());
+
+        let (parsed, issues) = XSave::parse_lossy(&legacy);
+        assert_eq!(
+            issues,
+            [
+                ParseIssue::Truncated(512),
+                ParseIssue::MissingHeader,
+                ParseIssue::MxCsrReserved(0xFFFF_0000),
+            ]
+        );
+        assert_eq!(parsed.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(parsed.legacy.mxcsr, MxCsr::DEFAULT);
+        assert_eq!(parsed.legacy.xmm[0].0[0], 0xAA);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn lossy_truncated_component() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::SSE | XStateBv::AVX | XStateBv::PKRU;
+        xsave.header.reserved[0] = 1;
+        let bytes = *xsave.as_bytes_mut();
+
+        let (parsed, issues) = XSave::parse_lossy(&bytes[..1024]);
+        assert_eq!(
+            issues,
+            [
+                ParseIssue::Truncated(1024),
+                ParseIssue::HeaderReserved,
+                ParseIssue::TruncatedComponent(XStateBv::PKRU),
+            ]
+        );
+        assert_eq!(parsed.header.xstate_bv, XStateBv::SSE | XStateBv::AVX);
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn lossy_unsupported_component() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::AVX | XStateBv::AMX_TILECFG | XStateBv::AMX_TILEDATA;
+        let bytes = *xsave.as_bytes_mut();
+
+        let (parsed, issues) = XSave::parse_lossy(&bytes);
+        assert_eq!(
+            issues,
+            [ParseIssue::UnsupportedComponents(XStateBv::AMX_TILEDATA)]
+        );
+        assert_eq!(
+            parsed.header.xstate_bv,
+            XStateBv::AVX | XStateBv::AMX_TILECFG
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Save areas in caller-supplied memory
+//!
+//! Areas handed to hardware or to another privilege domain by physical
+//! address must come from memory which is pinned, and often identity
+//! mapped or shared. Neither the heap nor the stack can promise that, so
+//! the memory is obtained from an allocator supplied by the caller.
+
+use core::alloc::Layout;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use super::*;
+
+/// An allocator of pinned memory for save areas
+///
+/// # Safety
+///
+/// Memory returned by `allocate()` must be valid for reads and writes of
+/// `layout.size()` bytes, must not move, and must stay allocated until it
+/// is passed to `deallocate()`.
+pub unsafe trait PinnedAlloc {
+    /// Allocate memory for `layout`, or `None` if none is available
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Free memory returned by `allocate()`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `allocate()` on this allocator with
+    /// the same `layout`, and must not be used afterwards.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    /// The physical address of memory returned by `allocate()`
+    fn physical_address(&self, ptr: NonNull<u8>) -> u64;
+}
+
+/// An `XSave` in memory from a `PinnedAlloc`, freed on drop
+pub struct PinnedXSave<'a, A: PinnedAlloc> {
+    ptr: NonNull<XSave>,
+    alloc: &'a A,
+}
+
+impl<'a, A: PinnedAlloc> PinnedXSave<'a, A> {
+    const LAYOUT: Layout = Layout::new::<XSave>();
+
+    /// Allocate a default `XSave`
+    ///
+    /// This fails with `XSaveError::OutOfMemory` if the allocator has no
+    /// memory, or with `XSaveError::Misaligned` if it ignored the
+    /// alignment of the layout, in which case the memory is returned.
+    pub fn new_in(alloc: &'a A) -> Result<Self, XSaveError> {
+        let ptr = alloc
+            .allocate(Self::LAYOUT)
+            .ok_or(XSaveError::OutOfMemory(Self::LAYOUT.size()))?;
+
+        let addr = ptr.as_ptr() as usize;
+        if addr % Self::LAYOUT.align() != 0 {
+            // SAFETY: the memory was just allocated with this layout.
+            unsafe { alloc.deallocate(ptr, Self::LAYOUT) };
+            return Err(XSaveError::Misaligned(addr));
+        }
+
+        let ptr = ptr.cast::<XSave>();
+
+        // SAFETY: the memory is valid, aligned and large enough.
+        unsafe { ptr.as_ptr().write(XSave::DEFAULT) };
+        Ok(Self { ptr, alloc })
+    }
+
+    /// The physical address of the area
+    #[inline]
+    pub fn physical_address(&self) -> u64 {
+        self.alloc.physical_address(self.ptr.cast())
+    }
+
+    /// A pointer to the area, for handing to hardware
+    #[inline]
+    pub fn as_ptr(&self) -> *mut XSave {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<A: PinnedAlloc> Deref for PinnedXSave<'_, A> {
+    type Target = XSave;
+
+    #[inline]
+    fn deref(&self) -> &XSave {
+        // SAFETY: the area is initialized and owned by this handle.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<A: PinnedAlloc> DerefMut for PinnedXSave<'_, A> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut XSave {
+        // SAFETY: the area is initialized and owned by this handle.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<A: PinnedAlloc> Drop for PinnedXSave<'_, A> {
+    #[inline]
+    fn drop(&mut self) {
+        // SAFETY: the memory was allocated with this layout in `new_in()`.
+        unsafe { self.alloc.deallocate(self.ptr.cast(), Self::LAYOUT) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, UnsafeCell};
+
+    use super::*;
+
+    /// A single page at a pretend physical address
+    struct Page {
+        memory: UnsafeCell<XSave4K>,
+        offset: usize,
+        used: Cell<bool>,
+    }
+
+    unsafe impl PinnedAlloc for Page {
+        fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+            if self.used.replace(true) || layout.size() + self.offset > size_of::<XSave4K>() {
+                return None;
+            }
+
+            let base = self.memory.get() as *mut u8;
+            NonNull::new(unsafe { base.add(self.offset) })
+        }
+
+        unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {
+            self.used.set(false);
+        }
+
+        fn physical_address(&self, ptr: NonNull<u8>) -> u64 {
+            0x10_0000 + (ptr.as_ptr() as u64 - self.memory.get() as u64)
+        }
+    }
+
+    fn page(offset: usize) -> Page {
+        Page {
+            memory: UnsafeCell::new(XSave4K::default()),
+            offset,
+            used: Cell::new(false),
+        }
+    }
+
+    #[test]
+    fn pinned() {
+        let page = page(64);
+        let mut xsave = PinnedXSave::new_in(&page).unwrap();
+        assert_eq!(xsave.physical_address(), 0x10_0040);
+        xsave.header.xstate_bv = XStateBv::X87;
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87);
+
+        assert_eq!(
+            PinnedXSave::new_in(&page).err(),
+            Some(XSaveError::OutOfMemory(size_of::<XSave>()))
+        );
+        drop(xsave);
+        assert!(PinnedXSave::new_in(&page).is_ok());
+    }
+
+    #[test]
+    fn misaligned() {
+        let page = page(16);
+        let addr = page.memory.get() as usize + 16;
+        assert_eq!(
+            PinnedXSave::new_in(&page).err(),
+            Some(XSaveError::Misaligned(addr))
+        );
+        assert!(!page.used.get());
+    }
+}
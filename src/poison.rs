@@ -0,0 +1,147 @@
+//! Detection of restores from superseded buffers
+//!
+//! A scheduler which keeps more than one buffer per context, or copies
+//! them around, can restore a buffer which a later save has superseded.
+//! The context then silently continues with old register values. With the
+//! `debug-poison` feature, each save made through a `Lineage` stamps the
+//! buffer with the lineage and a new epoch, and a restore through the same
+//! lineage refuses any buffer but the latest.
+//!
+//! Only `XSave::save_tracked()` and `XSave::load_tracked()` are covered.
+//! The feature leaves `save()`, `load()` and every other save and restore
+//! unchanged: they cannot tell which context a buffer belongs to, and
+//! they run in exception handlers, where neither panicking nor reporting
+//! an error is possible. A scheduler must switch to the tracked variants
+//! for its context switches to be checked.
+//!
+//! The stamp occupies the first 16 of the software-available bytes of the
+//! legacy area, which the processor neither writes nor reads.
+//!
+//! Linux uses the same bytes. A signal frame keeps `fpx_sw_bytes` there,
+//! starting with `FP_XSTATE_MAGIC1` and the extended size, and ptrace's
+//! `NT_X86_XSTATE` keeps XCR0 at `interop::linux::USER_XSTATE_XCR0_OFFSET`.
+//! A tracked buffer copied into a signal frame or a ptrace register set
+//! therefore carries the stamp in place of those fields, and the kernel
+//! or debugger rejects or misreads it. Write the fields into the copy
+//! before handing it over; the copy is then no longer stamped, and
+//! `load_tracked()` refuses it.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::*;
+
+/// The offset of the stamp in the legacy area
+const STAMP: usize = 464;
+
+/// The logical context whose saves a stamp refers to
+///
+/// Create one per thread, task or vCPU, and use it for all of its saves
+/// and restores. Saves and restores which bypass it, such as plain
+/// `XSave::save()` and `XSave::load()`, are neither stamped nor checked.
+#[derive(Debug)]
+pub struct Lineage {
+    id: u64,
+    latest: AtomicU64,
+}
+
+impl Default for Lineage {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lineage {
+    /// Create a lineage which has not been saved yet
+    pub fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+
+        Self {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            latest: AtomicU64::new(0),
+        }
+    }
+
+    /// The epoch of the latest save, or zero if there has been none
+    #[inline]
+    pub fn epoch(&self) -> u64 {
+        self.latest.load(Ordering::Acquire)
+    }
+}
+
+impl XSave {
+    /// The lineage and epoch stamped on the buffer, zero if unstamped
+    fn stamp(&self) -> (u64, u64) {
+        let mut id = [0; 8];
+        let mut epoch = [0; 8];
+        id.copy_from_slice(&self.as_bytes()[STAMP..][..8]);
+        epoch.copy_from_slice(&self.as_bytes()[STAMP + 8..][..8]);
+        (u64::from_le_bytes(id), u64::from_le_bytes(epoch))
+    }
+
+    /// Save the extended CPU state as the latest state of `lineage`
+    pub fn save_tracked(&mut self, lineage: &Lineage) {
+        self.save();
+
+        let epoch = lineage.latest.fetch_add(1, Ordering::AcqRel) + 1;
+        self.as_bytes_mut()[STAMP..][..8].copy_from_slice(&lineage.id.to_le_bytes());
+        self.as_bytes_mut()[STAMP + 8..][..8].copy_from_slice(&epoch.to_le_bytes());
+    }
+
+    /// Load the extended CPU state, refusing anything but the latest save
+    ///
+    /// The buffer must hold the latest save made through `lineage`. Before
+    /// the first such save, only an unstamped buffer, such as the initial
+    /// state of a new context, is accepted. Otherwise this fails with
+    /// `XSaveError::StaleState`, holding the epoch of the buffer, without
+    /// loading anything. An epoch of zero means that the buffer belongs to
+    /// another lineage or is unstamped.
+    pub fn load_tracked(&self, lineage: &Lineage) -> Result<(), XSaveError> {
+        let latest = lineage.epoch();
+        let epoch = match self.stamp() {
+            (id, epoch) if id == lineage.id => epoch,
+            _ => 0,
+        };
+
+        if epoch != latest || (epoch == 0 && self.stamp() != (0, 0)) {
+            return Err(XSaveError::StaleState(epoch));
+        }
+
+        self.load();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale() {
+        let mut backup = XSave::DEFAULT;
+        backup.save();
+
+        let lineage = Lineage::new();
+        let initial = XSave::fxsave_default();
+        assert_eq!(initial.load_tracked(&lineage), Ok(()));
+
+        let mut old = XSave::DEFAULT;
+        let mut new = XSave::DEFAULT;
+        old.save_tracked(&lineage);
+        new.save_tracked(&lineage);
+        assert_eq!(lineage.epoch(), 2);
+
+        assert_eq!(old.load_tracked(&lineage), Err(XSaveError::StaleState(1)));
+        assert_eq!(
+            initial.load_tracked(&lineage),
+            Err(XSaveError::StaleState(0))
+        );
+        assert_eq!(new.load_tracked(&lineage), Ok(()));
+        assert_eq!(
+            new.load_tracked(&Lineage::new()),
+            Err(XSaveError::StaleState(0))
+        );
+
+        backup.load();
+    }
+}
@@ -0,0 +1,198 @@
+//! Iteration over the register files held in an `XSave`
+//!
+//! Each iterator yields `(name, value)` pairs in conventional order, using
+//! the lowercase register names of most debuggers. The registers of a
+//! component which is absent from `xstate_bv` are in their initial
+//! configuration, so they are reported as zero.
+
+use interop::FpReg;
+
+use super::*;
+
+const ST: [&str; 8] = ["st0", "st1", "st2", "st3", "st4", "st5", "st6", "st7"];
+const MM: [&str; 8] = ["mm0", "mm1", "mm2", "mm3", "mm4", "mm5", "mm6", "mm7"];
+const K: [&str; 8] = ["k0", "k1", "k2", "k3", "k4", "k5", "k6", "k7"];
+
+const XMM: [&str; 16] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10",
+    "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+const YMM: [&str; 16] = [
+    "ymm0", "ymm1", "ymm2", "ymm3", "ymm4", "ymm5", "ymm6", "ymm7", "ymm8", "ymm9", "ymm10",
+    "ymm11", "ymm12", "ymm13", "ymm14", "ymm15",
+];
+
+const ZMM: [&str; 32] = [
+    "zmm0", "zmm1", "zmm2", "zmm3", "zmm4", "zmm5", "zmm6", "zmm7", "zmm8", "zmm9", "zmm10",
+    "zmm11", "zmm12", "zmm13", "zmm14", "zmm15", "zmm16", "zmm17", "zmm18", "zmm19", "zmm20",
+    "zmm21", "zmm22", "zmm23", "zmm24", "zmm25", "zmm26", "zmm27", "zmm28", "zmm29", "zmm30",
+    "zmm31",
+];
+
+/// Read a little-endian `u128` from the start of a slice, or zero
+#[inline]
+fn u128_at(bytes: Option<&[u8]>, offset: usize) -> u128 {
+    let mut value = [0; 16];
+    if let Some(bytes) = bytes.and_then(|b| b.get(offset..offset + 16)) {
+        value.copy_from_slice(bytes);
+    }
+
+    u128::from_le_bytes(value)
+}
+
+/// Read a little-endian `u64` from the start of a slice, or zero
+#[inline]
+pub(crate) fn u64_at(bytes: Option<&[u8]>, offset: usize) -> u64 {
+    let mut value = [0; 8];
+    if let Some(bytes) = bytes.and_then(|b| b.get(offset..offset + 8)) {
+        value.copy_from_slice(bytes);
+    }
+
+    u64::from_le_bytes(value)
+}
+
+impl XSave {
+    /// The data of an extended component, if it is present
+    #[inline]
+    pub(crate) fn component_bytes(&self, component: Component) -> Option<&[u8]> {
+        if !self.header.xstate_bv.contains(component.bit()) {
+            return None;
+        }
+
+        let (start, end) = self.component_range(component)?;
+        self.as_bytes().get(start..end)
+    }
+
+    /// The x87 registers, from `st0` at the top of the stack to `st7`
+    pub fn st_regs(&self) -> impl Iterator<Item = (&'static str, FpReg)> + '_ {
+        let present = self.header.xstate_bv.contains(XStateBv::X87);
+        ST.into_iter()
+            .zip(self.legacy.mm.iter())
+            .map(move |(name, field)| {
+                let reg = match present {
+                    true => field.mm.into(),
+                    false => FpReg::default(),
+                };
+
+                (name, reg)
+            })
+    }
+
+    /// The MMX registers, which alias the significands of the x87 registers
+    pub fn mm_regs(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.st_regs().zip(MM).map(|((_, reg), name)| {
+            let [a, b, c, d] = reg.significand;
+            let value = u64::from(a) | u64::from(b) << 16 | u64::from(c) << 32 | u64::from(d) << 48;
+            (name, value)
+        })
+    }
+
+    /// The XMM registers
+    pub fn xmm_regs(&self) -> impl Iterator<Item = (&'static str, u128)> + '_ {
+        let present = self.header.xstate_bv.contains(XStateBv::SSE);
+        XMM.into_iter()
+            .zip(self.legacy.xmm.iter())
+            .map(move |(name, xmm)| {
+                let value = match present {
+                    true => u128::from_le_bytes(xmm.0),
+                    false => 0,
+                };
+
+                (name, value)
+            })
+    }
+
+    /// The YMM registers, as their low and high 128-bit halves
+    pub fn ymm_regs(&self) -> impl Iterator<Item = (&'static str, [u128; 2])> + '_ {
+        let avx = self.component_bytes(Component::Avx);
+        YMM.into_iter()
+            .zip(self.xmm_regs())
+            .enumerate()
+            .map(move |(i, (name, (_, low)))| (name, [low, u128_at(avx, i * 16)]))
+    }
+
+    /// The AVX-512 ZMM registers, as four 128-bit lanes from lowest
+    pub fn zmm_regs(&self) -> impl Iterator<Item = (&'static str, [u128; 4])> + '_ {
+        let hi256 = self.component_bytes(Component::Avx512ZmmHi256);
+        let hi16 = self.component_bytes(Component::Avx512Hi16Zmm);
+
+        ZMM.into_iter()
+            .enumerate()
+            .zip(self.ymm_regs().map(Some).chain([None; 16]))
+            .map(move |((i, name), ymm)| {
+                let lanes = match ymm {
+                    Some((_, [a, b])) => {
+                        [a, b, u128_at(hi256, i * 32), u128_at(hi256, i * 32 + 16)]
+                    }
+                    None => {
+                        let base = (i - 16) * 64;
+                        [
+                            u128_at(hi16, base),
+                            u128_at(hi16, base + 16),
+                            u128_at(hi16, base + 32),
+                            u128_at(hi16, base + 48),
+                        ]
+                    }
+                };
+
+                (name, lanes)
+            })
+    }
+
+    /// The AVX-512 opmask registers
+    pub fn k_regs(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        let opmask = self.component_bytes(Component::Avx512Opmask);
+        K.into_iter()
+            .enumerate()
+            .map(move |(i, name)| (name, u64_at(opmask, i * 8)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[2] = Xmm(7u128.to_le_bytes());
+        xsave.legacy.mm[1].mm = FpReg {
+            significand: [1, 0, 0, 0x8000],
+            exponent: 0x3FFF,
+        }
+        .into();
+
+        let (name, value) = xsave.xmm_regs().nth(2).unwrap();
+        assert_eq!((name, value), ("xmm2", 7));
+        assert_eq!(xsave.xmm_regs().count(), 16);
+
+        let (name, reg) = xsave.st_regs().nth(1).unwrap();
+        assert_eq!(name, "st1");
+        assert_eq!(reg.exponent, 0x3FFF);
+        assert_eq!(xsave.mm_regs().nth(1), Some(("mm1", 0x8000_0000_0000_0001)));
+
+        xsave.header.xstate_bv = XStateBv::empty();
+        assert!(xsave.xmm_regs().all(|(_, value)| value == 0));
+        assert!(xsave.st_regs().all(|(_, reg)| reg == FpReg::default()));
+    }
+
+    #[test]
+    fn extended() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[1] = Xmm(1u128.to_le_bytes());
+        xsave.as_bytes_mut()[576 + 16] = 2;
+        xsave.as_bytes_mut()[1088 + 8] = 3;
+        xsave.as_bytes_mut()[1152 + 32 + 16] = 4;
+        xsave.as_bytes_mut()[1664 + 64 * 15 + 48] = 5;
+
+        assert_eq!(xsave.ymm_regs().nth(1), Some(("ymm1", [1, 0])));
+
+        xsave.header.xstate_bv |= XStateBv::from_bits_truncate(0b1110_0100);
+        assert_eq!(xsave.ymm_regs().nth(1), Some(("ymm1", [1, 2])));
+        assert_eq!(xsave.zmm_regs().nth(1), Some(("zmm1", [1, 2, 0, 4])));
+        assert_eq!(xsave.zmm_regs().nth(31), Some(("zmm31", [0, 0, 0, 5])));
+        assert_eq!(xsave.zmm_regs().count(), 32);
+        assert_eq!(xsave.k_regs().nth(1), Some(("k1", 3)));
+    }
+}
@@ -0,0 +1,193 @@
+//! Scoped management of the extended CPU state
+
+use core::marker::PhantomData;
+
+use super::*;
+
+/// Saves the extended CPU state and restores it when dropped
+///
+/// This makes it possible to bracket calls into code with unknown FPU
+/// discipline (such as FFI calls or signal handlers) with a single line:
+///
+/// ```rust
+/// use xsave::XSaveGuard;
+///
+/// let guard = XSaveGuard::new();
+/// // ... call code which may clobber the extended state ...
+/// drop(guard);
+/// ```
+///
+/// The guard must be dropped on the thread which created it, so it is
+/// neither `Send` nor `Sync`.
+pub struct XSaveGuard {
+    xsave: XSave,
+    thread: PhantomData<*const ()>,
+}
+
+impl XSaveGuard {
+    /// Save the current extended CPU state
+    #[inline]
+    #[must_use = "the state is restored when the guard is dropped"]
+    pub fn new() -> Self {
+        let mut xsave = XSave::DEFAULT;
+        xsave.save();
+
+        Self {
+            xsave,
+            thread: PhantomData,
+        }
+    }
+
+    /// The saved extended CPU state which will be restored
+    #[inline]
+    pub fn state(&self) -> &XSave {
+        &self.xsave
+    }
+}
+
+impl Default for XSaveGuard {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for XSaveGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.xsave.load();
+    }
+}
+
+/// Isolates the extended CPU state of untrusted code from its host
+///
+/// Around each callback, the data bearing registers are cleared on entry,
+/// the untrusted code's state is saved on exit and the host's state is
+/// restored. This is the FPU part of the host/guest transition performed
+/// by an Enarx keep.
+///
+/// If the callback panics, the host's state is still restored but the
+/// untrusted code's state is not saved.
+pub struct Isolated {
+    xsave: XSave,
+}
+
+impl Isolated {
+    /// Create a new isolation context
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            xsave: XSave::DEFAULT,
+        }
+    }
+
+    /// Run untrusted code in isolation
+    #[inline]
+    pub fn run<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let _host = XSaveGuard::new();
+        XSave::DEFAULT.load();
+
+        let result = f();
+        self.xsave.save();
+        result
+    }
+
+    /// The state of the untrusted code at the end of the last run
+    #[inline]
+    pub fn state(&self) -> &XSave {
+        &self.xsave
+    }
+}
+
+impl Default for Isolated {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XSave {
+    /// Run a closure with this extended CPU state loaded
+    ///
+    /// The previous state is saved beforehand and restored afterwards, even
+    /// if the closure panics.
+    #[inline]
+    pub fn with_loaded<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = XSaveGuard::new();
+        self.load();
+        f()
+    }
+}
+
+#[cfg(all(test, xsave_hw))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_feature = "sse")]
+    fn guard() {
+        let xmm0: f32 = 0.7;
+        unsafe { asm!("", in("xmm0") xmm0) };
+
+        let guard = XSaveGuard::new();
+        XSave::DEFAULT.load();
+
+        let xmm0: f32;
+        unsafe { asm!("", out("xmm0") xmm0) };
+        assert_eq!(xmm0, 0.0);
+
+        drop(guard);
+
+        let xmm0: f32;
+        unsafe { asm!("", out("xmm0") xmm0) };
+        assert_eq!(xmm0, 0.7);
+    }
+
+    #[test]
+    #[cfg(target_feature = "sse")]
+    fn with_loaded() {
+        let xmm0: f32 = 0.7;
+        unsafe { asm!("", in("xmm0") xmm0) };
+
+        let inner = XSave::DEFAULT.with_loaded(|| {
+            let xmm0: f32;
+            unsafe { asm!("", out("xmm0") xmm0) };
+            xmm0.to_bits()
+        });
+
+        let outer: f32;
+        unsafe { asm!("", out("xmm0") outer) };
+        assert_eq!(f32::from_bits(inner), 0.0);
+        assert_eq!(outer, 0.7);
+    }
+
+    #[test]
+    #[cfg(target_feature = "sse")]
+    fn isolated() {
+        let xmm0: f32 = 0.7;
+        unsafe { asm!("", in("xmm0") xmm0) };
+
+        let mut isolated = Isolated::new();
+        let inner = isolated.run(|| {
+            let bits: u32;
+            unsafe {
+                asm!(
+                    "movd    {0:e}, xmm0",
+                    "movd    xmm0, {1:e}",
+                    out(reg) bits,
+                    in(reg) 0x3E99_999Au32,
+                    out("xmm0") _,
+                )
+            };
+            bits
+        });
+
+        let outer: f32;
+        unsafe { asm!("", out("xmm0") outer) };
+        assert_eq!(f32::from_bits(inner), 0.0);
+        assert_eq!(outer, 0.7);
+
+        let saved = isolated.state().legacy.xmm[0].0;
+        assert_eq!(saved[..4], 0.3f32.to_le_bytes());
+    }
+}
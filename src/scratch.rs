@@ -0,0 +1,270 @@
+//! Statically allocated save areas for boot, interrupt handlers and CPUs
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::*;
+
+/// A save area which can live in a `static`
+///
+/// Interrupt and exception handlers often run on a stack of unknown
+/// alignment, where an `XSave` cannot safely be placed. This keeps one in
+/// static storage, with its alignment intact, and hands it out to one
+/// claimant at a time. Claiming never blocks, so a nested handler which
+/// finds the area taken can fall back rather than deadlock.
+///
+/// ```
+/// use xsave::{AlignedStatic, XSave};
+///
+/// static SCRATCH: AlignedStatic = AlignedStatic::new(XSave::fxsave_default());
+///
+/// let scratch = SCRATCH.claim().unwrap();
+/// assert!(SCRATCH.claim().is_none());
+/// drop(scratch);
+/// assert!(SCRATCH.claim().is_some());
+/// ```
+pub struct AlignedStatic<T = XSave> {
+    claimed: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is only reachable through a `Claim`, and at most one
+// exists at a time.
+unsafe impl<T: Send> Sync for AlignedStatic<T> {}
+
+impl<T> AlignedStatic<T> {
+    /// Create an unclaimed area
+    #[inline]
+    pub const fn new(value: T) -> Self {
+        Self {
+            claimed: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Claim the area, or `None` if it is already claimed
+    ///
+    /// The area is released when the `Claim` is dropped.
+    #[inline]
+    pub fn claim(&self) -> Option<Claim<'_, T>> {
+        self.claimed
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Claim(self))
+    }
+
+    /// Whether the area is currently claimed
+    #[inline]
+    pub fn is_claimed(&self) -> bool {
+        self.claimed.load(Ordering::Relaxed)
+    }
+
+    /// Get the value through exclusive access, which needs no claim
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// Exclusive access to an `AlignedStatic`, released on drop
+pub struct Claim<'a, T = XSave>(&'a AlignedStatic<T>);
+
+impl<T> Deref for Claim<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        // SAFETY: the claim grants exclusive access.
+        unsafe { &*self.0.value.get() }
+    }
+}
+
+impl<T> DerefMut for Claim<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the claim grants exclusive access.
+        unsafe { &mut *self.0.value.get() }
+    }
+}
+
+impl<T> Drop for Claim<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.claimed.store(false, Ordering::Release);
+    }
+}
+
+/// A save area which can be taken exactly once
+///
+/// Early in boot, before any allocator or per-CPU infrastructure exists, a
+/// kernel may still need somewhere to save the state of the bootstrap
+/// processor. This is const-initialized, so it can be placed in any
+/// section with `#[link_section]`, and hands out a `'static` reference to
+/// its area only once.
+///
+/// ```
+/// use xsave::StaticXSave;
+///
+/// #[link_section = ".data.xsave"]
+/// static BSP: StaticXSave = StaticXSave::new();
+///
+/// let xsave = BSP.take().unwrap();
+/// assert!(BSP.take().is_none());
+/// ```
+pub struct StaticXSave {
+    taken: AtomicBool,
+    xsave: UnsafeCell<XSave>,
+}
+
+// SAFETY: the area is only reachable through `take()`, which succeeds once.
+unsafe impl Sync for StaticXSave {}
+
+impl StaticXSave {
+    /// Create an untaken slot holding `XSave::DEFAULT`
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            taken: AtomicBool::new(false),
+            xsave: UnsafeCell::new(XSave::DEFAULT),
+        }
+    }
+
+    /// Take the area, or `None` if it was already taken
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub fn take(&'static self) -> Option<&'static mut XSave> {
+        self.taken
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            // SAFETY: this is the only reference ever handed out.
+            .map(|_| unsafe { &mut *self.xsave.get() })
+    }
+
+    /// Whether the area has been taken
+    #[inline]
+    pub fn is_taken(&self) -> bool {
+        self.taken.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for StaticXSave {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One save area per CPU
+///
+/// Kernels handling `#NM` or switching contexts need a save area for each
+/// CPU. The current CPU is found with a caller-supplied function, such as
+/// one reading the APIC ID or a per-CPU segment. Each area is claimed
+/// like an `AlignedStatic`, so a handler which interrupts another on the
+/// same CPU, or a thread which migrates while holding a claim, cannot
+/// alias it.
+///
+/// ```
+/// use xsave::PerCpu;
+///
+/// fn current_cpu() -> usize {
+///     0
+/// }
+///
+/// static AREAS: PerCpu<fn() -> usize, 4> = PerCpu::new(current_cpu);
+///
+/// let area = AREAS.current().unwrap();
+/// assert!(AREAS.current().is_none());
+/// assert!(AREAS.get(1).is_some());
+/// ```
+pub struct PerCpu<F, const N: usize> {
+    slots: [AlignedStatic; N],
+    cpu: F,
+}
+
+impl<F, const N: usize> PerCpu<F, N> {
+    // Only used to repeat a fresh area into each slot
+    #[allow(clippy::declare_interior_mutable_const)]
+    const SLOT: AlignedStatic = AlignedStatic::new(XSave::fxsave_default());
+
+    /// Create unclaimed areas, found with `cpu`
+    #[inline]
+    pub const fn new(cpu: F) -> Self {
+        Self {
+            slots: [Self::SLOT; N],
+            cpu,
+        }
+    }
+}
+
+impl<F: Fn() -> usize, const N: usize> PerCpu<F, N> {
+    /// Claim the area of the current CPU
+    ///
+    /// This is `None` if the area is already claimed or the CPU index is
+    /// out of range.
+    #[inline]
+    pub fn current(&self) -> Option<Claim<'_>> {
+        self.get((self.cpu)())
+    }
+
+    /// Claim the area of the CPU with the given index
+    #[inline]
+    pub fn get(&self, cpu: usize) -> Option<Claim<'_>> {
+        self.slots.get(cpu)?.claim()
+    }
+
+    /// Run a function with the area of the current CPU
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&mut XSave) -> R) -> Option<R> {
+        self.current().map(|mut claim| f(&mut claim))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SCRATCH: AlignedStatic = AlignedStatic::new(XSave::fxsave_default());
+
+    #[test]
+    fn claim() {
+        let mut claim = SCRATCH.claim().unwrap();
+        assert_eq!(&*claim as *const XSave as usize % 64, 0);
+        assert!(SCRATCH.is_claimed());
+        assert!(SCRATCH.claim().is_none());
+
+        claim.header.xstate_bv = XStateBv::X87;
+        drop(claim);
+        assert!(!SCRATCH.is_claimed());
+        assert_eq!(SCRATCH.claim().unwrap().header.xstate_bv, XStateBv::X87);
+    }
+
+    #[test]
+    fn once() {
+        static SLOT: StaticXSave = StaticXSave::new();
+
+        let xsave = SLOT.take().unwrap();
+        assert_eq!(xsave as *mut XSave as usize % 64, 0);
+        assert!(SLOT.is_taken());
+        assert!(SLOT.take().is_none());
+    }
+
+    #[test]
+    fn per_cpu() {
+        static AREAS: PerCpu<fn() -> usize, 2> = PerCpu::new(|| 1);
+
+        let area = AREAS.current().unwrap();
+        assert_eq!(&*area as *const XSave as usize % 64, 0);
+        assert!(AREAS.with(|_| ()).is_none());
+        assert!(AREAS.get(0).is_some());
+        assert!(AREAS.get(2).is_none());
+
+        drop(area);
+        let bv = AREAS.with(|xsave| {
+            xsave.header.xstate_bv = XStateBv::SSE;
+            xsave.header.xstate_bv
+        });
+        assert_eq!(bv, Some(XStateBv::SSE));
+        assert_eq!(AREAS.get(1).unwrap().header.xstate_bv, XStateBv::SSE);
+    }
+}
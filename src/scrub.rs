@@ -0,0 +1,157 @@
+//! Saving into reused buffers without leaking their previous contents
+//!
+//! `xsave` only writes the components it is asked for and which are not
+//! in their initial configuration. Every other byte of the buffer keeps
+//! whatever it held before, which may be the registers of an unrelated
+//! context. `save_scrubbed()` clears those bytes after saving.
+
+#[cfg(all(xsave_hw, not(doc)))]
+use core::arch::asm;
+
+use super::component::EXTENDED;
+use super::parse::RESERVED;
+use super::*;
+
+/// The bytes of the legacy area which are available to software
+///
+/// `xsave` never writes them, so they keep the previous contents too.
+const SOFTWARE: (usize, usize) = (464, 512);
+
+/// The user components whose standard format data fits in an `XSave`
+fn fitting() -> XStateBv {
+    Component::ALL
+        .iter()
+        .filter_map(|c| Some((c, ComponentTable::ARCHITECTURAL.get(c.index())?)))
+        .filter(|(_, info)| !info.supervisor)
+        .filter(|(_, info)| (info.offset + info.size) as usize <= size_of::<XSave>())
+        .fold(XStateBv::X87 | XStateBv::SSE, |acc, (c, _)| acc | c.bit())
+}
+
+impl XSave {
+    /// Zero every byte not holding a component saved under `mask`
+    ///
+    /// Components outside the mask are dropped from `xstate_bv`. The x87
+    /// and SSE registers are returned to their initial configuration
+    /// rather than zeroed, and MXCSR to its default, since it is restored
+    /// even when SSE is absent.
+    fn scrub(&mut self, mask: XStateBv) {
+        self.header.xstate_bv &= mask;
+        self.header.xcomp_bv = XCompBv::empty();
+        self.header.reserved = [0; 6];
+
+        for (start, end) in RESERVED.into_iter().chain([SOFTWARE]) {
+            self.as_bytes_mut()[start..end].fill(0);
+        }
+
+        let present = self.header.xstate_bv;
+        if !present.contains(XStateBv::X87) {
+            let _ = self.reset(Component::X87);
+        }
+
+        if !present.contains(XStateBv::SSE) {
+            let _ = self.reset(Component::Sse);
+        }
+
+        if (mask & (XStateBv::SSE | XStateBv::AVX)).is_empty() {
+            self.legacy.mxcsr = MxCsr::DEFAULT;
+            self.legacy.mxcsr_mask = MxCsr::empty();
+        }
+
+        let saved = *self;
+        self.as_bytes_mut()[EXTENDED..].fill(0);
+
+        let kept = Component::ALL
+            .iter()
+            .filter(|c| !matches!(c, Component::X87 | Component::Sse))
+            .filter(|c| present.contains(c.bit()))
+            .filter_map(|c| saved.component_range(*c));
+
+        for (start, end) in kept {
+            self.as_bytes_mut()[start..end].copy_from_slice(&saved.as_bytes()[start..end]);
+        }
+    }
+
+    /// Save the components in `mask`, zeroing the rest of the buffer
+    ///
+    /// The save requests only the components in `mask` which fit in an
+    /// `XSave`. Afterwards, the data of every other component, of the
+    /// components left in their initial configuration, and of the reserved
+    /// and software-available bytes is cleared, so nothing from a previous
+    /// use of the buffer remains. The result is in the standard format.
+    pub fn save_scrubbed(&mut self, mask: XStateBv) {
+        let mask = mask & fitting();
+
+        #[cfg(xsave_soft)]
+        soft::save(self);
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "xsave   [{}]",
+                in(reg) &mut *self,
+                in("eax") mask.bits as u32,
+                in("edx") (mask.bits >> 32) as u32,
+                options(nostack),
+            )
+        }
+
+        self.scrub(mask);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer with every byte set, as if left over from another context
+    fn dirty() -> XSave {
+        let mut xsave = XSave::DEFAULT;
+        xsave.as_bytes_mut().fill(0xA5);
+        xsave
+    }
+
+    #[test]
+    fn fits() {
+        let fits = fitting();
+        assert!(fits.contains(XStateBv::AVX512 | XStateBv::PKRU | XStateBv::AMX_TILECFG));
+        assert!(!fits.intersects(XStateBv::PT | XStateBv::AMX_TILEDATA));
+    }
+
+    #[test]
+    fn scrub() {
+        let mut xsave = dirty();
+        xsave.header.xstate_bv = XStateBv::X87 | XStateBv::AVX | XStateBv::PKRU;
+        xsave.scrub(XStateBv::X87 | XStateBv::SSE | XStateBv::AVX);
+
+        let bytes = xsave.as_bytes();
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87 | XStateBv::AVX);
+        assert_eq!(xsave.header.xcomp_bv, XCompBv::empty());
+        assert!(bytes[520..576].iter().all(|b| *b == 0));
+        assert!(bytes[416..512].iter().all(|b| *b == 0));
+        assert!(bytes[42..48].iter().all(|b| *b == 0));
+
+        // The saved components are kept, the rest is cleared.
+        assert_eq!(xsave.legacy.fcw.bits, 0xA5A5);
+        assert!(bytes[576..832].iter().all(|b| *b == 0xA5));
+        assert!(bytes[832..].iter().all(|b| *b == 0));
+        assert!(xsave.legacy.xmm.iter().all(|x| x.0 == [0; 16]));
+        assert_eq!(xsave.legacy.mxcsr.bits, 0xA5A5_A5A5);
+
+        let mut xsave = dirty();
+        xsave.scrub(XStateBv::empty());
+        assert_eq!(xsave.header.xstate_bv, XStateBv::empty());
+        assert_eq!(xsave.legacy.fcw, Fcw::DEFAULT);
+        assert_eq!(xsave.legacy.mxcsr, MxCsr::DEFAULT);
+        assert!(xsave.as_bytes()[EXTENDED..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn save_scrubbed() {
+        let mut xsave = dirty();
+        xsave.save_scrubbed(XStateBv::X87 | XStateBv::SSE);
+
+        assert!((xsave.header.xstate_bv - (XStateBv::X87 | XStateBv::SSE)).is_empty());
+        assert!(xsave.as_bytes()[EXTENDED..].iter().all(|b| *b == 0));
+        assert!(XSave::from_bytes_strict(xsave.as_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,177 @@
+//! Verification of the crate's assumptions on the running CPU
+//!
+//! Kernels can run `XSave::self_test()` once at boot and refuse to
+//! continue if the CPU or the OS configuration does not behave as this
+//! crate expects, rather than discovering it later as corrupted state.
+
+#[cfg(all(xsave_hw, not(doc)))]
+use core::arch::asm;
+
+use super::*;
+
+/// The results of `XSave::self_test()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SelfTest {
+    /// The components enabled in XCR0, or `None` if XSAVE is not enabled
+    pub xcr0: Option<XStateBv>,
+
+    /// The size of the standard format area for the components in XCR0
+    pub enabled_size: usize,
+
+    /// Whether the area for the components in XCR0 fits in an `XSave`
+    pub fits: bool,
+
+    /// Whether modified x87, SSE and AVX registers survived a round-trip
+    pub roundtrip: bool,
+
+    /// Whether the saved header was valid for the standard format
+    ///
+    /// `xstate_bv` must be within the requested components, and `xsave`
+    /// must have left `xcomp_bv` and the reserved bytes untouched.
+    pub header: bool,
+}
+
+impl SelfTest {
+    /// Whether every check passed
+    #[inline]
+    pub fn passed(&self) -> bool {
+        self.xcr0.is_some() && self.fits && self.roundtrip && self.header
+    }
+}
+
+/// The size CPUID reports for the components in XCR0
+#[cfg(xsave_hw)]
+fn enabled_size() -> usize {
+    component::cpuid(0xD, 0).ebx as usize
+}
+
+/// The size of the area for the components in the model's XCR0
+#[cfg(xsave_soft)]
+fn enabled_size() -> usize {
+    size_for(SoftBackend::XCR0, false, &ComponentTable::ARCHITECTURAL)
+}
+
+/// Restore `input` and save it again into a zeroed header
+///
+/// The current state is saved beforehand and restored afterwards, all
+/// within one block so that the compiler cannot observe the intermediate
+/// state.
+fn roundtrip(input: &XSave, mask: XStateBv) -> XSave {
+    let mut backup = XSave::DEFAULT;
+    let mut output = XSave::DEFAULT;
+
+    #[cfg(xsave_soft)]
+    {
+        let _ = mask;
+        soft::save(&mut backup);
+        soft::load(input);
+        soft::save(&mut output);
+        soft::load(&backup);
+    }
+
+    #[cfg(all(xsave_hw, not(doc)))]
+    unsafe {
+        asm!(
+            "xsave   [{backup}]",
+            "xrstor  [{input}]",
+            "xsave   [{output}]",
+            "xrstor  [{backup}]",
+            backup = in(reg) &mut backup,
+            input = in(reg) input,
+            output = in(reg) &mut output,
+            in("eax") mask.bits as u32,
+            in("edx") (mask.bits >> 32) as u32,
+            options(nostack),
+        )
+    }
+
+    output
+}
+
+impl XSave {
+    /// Check the behavior of the running CPU against this crate
+    ///
+    /// This compares the size CPUID reports for XCR0 against the size of
+    /// an `XSave`, then restores a buffer with distinctive x87, SSE and
+    /// AVX registers, saves it again and checks the result. The current
+    /// state is preserved. Only the components which this touches are
+    /// requested, so the round-trip is safe even when XCR0 enables
+    /// components which do not fit.
+    pub fn self_test() -> SelfTest {
+        let xcr0 = match component::xcr0() {
+            Some(xcr0) => xcr0,
+            None => {
+                return SelfTest {
+                    xcr0: None,
+                    enabled_size: 0,
+                    fits: false,
+                    roundtrip: false,
+                    header: false,
+                }
+            }
+        };
+
+        let enabled_size = enabled_size();
+        let mask = xcr0 & (XStateBv::X87 | XStateBv::SSE | XStateBv::AVX);
+
+        let mut input = XSave::fxsave_default();
+        input.legacy.mxcsr = MxCsr::DEFAULT | MxCsr::ROUNDING_CONTROL0 | MxCsr::ROUNDING_CONTROL1;
+        input.legacy.mm[0].mm = Mm([0x5A; 10]);
+        for (i, xmm) in input.legacy.xmm.iter_mut().enumerate() {
+            *xmm = Xmm([i as u8 + 1; 16]);
+        }
+
+        if mask.contains(XStateBv::AVX) {
+            let _ = input.enable(Component::Avx);
+            if let Some((start, end)) = input.component_range(Component::Avx) {
+                input.as_bytes_mut()[start..end].fill(0xA5);
+            }
+        }
+
+        let output = roundtrip(&input, mask);
+
+        let avx = match output.component_range(Component::Avx) {
+            Some((start, end)) if mask.contains(XStateBv::AVX) => start..end,
+            _ => 0..0,
+        };
+
+        let roundtrip = output.legacy.mxcsr == input.legacy.mxcsr
+            && output.legacy.mm[0].mm.0 == input.legacy.mm[0].mm.0
+            && output
+                .legacy
+                .xmm
+                .iter()
+                .map(|x| x.0)
+                .eq(input.legacy.xmm.iter().map(|x| x.0))
+            && output.as_bytes()[avx.clone()] == input.as_bytes()[avx];
+
+        let header = (output.header.xstate_bv - mask).is_empty()
+            && output.header.xcomp_bv.bits == 0
+            && output.header.reserved == [0; 6];
+
+        SelfTest {
+            xcr0: Some(xcr0),
+            enabled_size,
+            fits: enabled_size <= size_of::<XSave>(),
+            roundtrip,
+            header,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test() {
+        let report = XSave::self_test();
+        if report.xcr0.is_none() {
+            return;
+        }
+
+        assert!(report.roundtrip);
+        assert!(report.header);
+        assert_eq!(report.passed(), report.fits);
+    }
+}
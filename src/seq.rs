@@ -0,0 +1,151 @@
+//! Torn-read detection for XSAVE areas in shared memory
+
+use core::cell::UnsafeCell;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use super::*;
+
+/// An XSAVE area guarded by a sequence counter
+///
+/// For areas shared between a guest and its host, or between cores, the
+/// counter is stored in the first reserved header word. It is odd while a
+/// write is in progress, so readers can detect and retry torn reads. There
+/// must be at most one writer at a time.
+///
+/// Since the counter occupies a reserved word, the raw area cannot be
+/// restored directly; the copies returned by `read_consistent()` have the
+/// counter cleared.
+#[repr(transparent)]
+pub struct SeqXSave(UnsafeCell<XSave>);
+
+// SAFETY: all access to the shared area is through volatile copies guarded
+// by the atomic sequence counter.
+unsafe impl Sync for SeqXSave {}
+
+impl SeqXSave {
+    /// Create a guarded area with a sequence counter of zero
+    #[inline]
+    pub fn new(mut xsave: XSave) -> Self {
+        xsave.header.reserved[0] = 0;
+        Self(UnsafeCell::new(xsave))
+    }
+
+    /// Guard an XSAVE area in shared memory
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes for `'a` and every party
+    /// accessing the area must follow the sequence counter protocol.
+    #[inline]
+    pub unsafe fn from_ptr<'a>(ptr: *mut XSave) -> &'a Self {
+        &*(ptr as *const Self)
+    }
+
+    #[inline]
+    fn seq(&self) -> &AtomicU64 {
+        // SAFETY: the header word is 8-byte aligned and only ever accessed
+        // atomically.
+        unsafe { &*(&(*self.0.get()).header.reserved[0] as *const u64 as *const AtomicU64) }
+    }
+
+    /// The number of completed writes, doubled
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.seq().load(Ordering::Acquire)
+    }
+
+    /// Read a consistent copy of the area, retrying on concurrent writes
+    #[inline]
+    pub fn read_consistent(&self) -> XSave {
+        loop {
+            let before = self.seq().load(Ordering::Acquire);
+            if before % 2 == 0 {
+                // SAFETY: the area is valid for reads and every bit pattern
+                // is a valid `XSave`; torn copies are discarded below.
+                let mut xsave = unsafe { read_volatile(self.0.get()) };
+
+                fence(Ordering::Acquire);
+                if self.seq().load(Ordering::Relaxed) == before {
+                    xsave.header.reserved[0] = 0;
+                    return xsave;
+                }
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Replace the contents of the area
+    ///
+    /// The sequence counter is preserved and advanced.
+    #[inline]
+    pub fn write(&self, xsave: &XSave) {
+        let seq = self.seq().load(Ordering::Relaxed);
+        self.seq().store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        // SAFETY: the area is valid for writes and readers discard any
+        // copy made while the counter is odd. The counter itself is only
+        // written atomically.
+        unsafe {
+            let area = self.0.get();
+            write_volatile(&mut (*area).legacy, xsave.legacy);
+            write_volatile(&mut (*area).header.xstate_bv, xsave.header.xstate_bv);
+            write_volatile(&mut (*area).header.xcomp_bv, xsave.header.xcomp_bv);
+            for i in 1..6 {
+                write_volatile(&mut (*area).header.reserved[i], xsave.header.reserved[i]);
+            }
+            write_volatile(&mut (*area).extend, xsave.extend);
+        }
+
+        self.seq().store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn sequential() {
+        let seq = SeqXSave::new(XSave::fxsave_default());
+        assert_eq!(seq.generation(), 0);
+
+        let mut xsave = XSave::DEFAULT;
+        xsave.legacy.xmm[0] = Xmm([0xAA; 16]);
+        seq.write(&xsave);
+        assert_eq!(seq.generation(), 2);
+
+        let read = seq.read_consistent();
+        assert_eq!(read.legacy.xmm[0].0, [0xAA; 16]);
+        assert_eq!(read.header.reserved, [0; 6]);
+    }
+
+    #[test]
+    fn concurrent() {
+        let seq = Arc::new(SeqXSave::new(XSave::DEFAULT));
+
+        let writer = {
+            let seq = seq.clone();
+            std::thread::spawn(move || {
+                let mut xsave = XSave::DEFAULT;
+                for i in 0..1000u32 {
+                    xsave.legacy.xmm = [Xmm([i as u8; 16]); 16];
+                    seq.write(&xsave);
+                }
+            })
+        };
+
+        for _ in 0..1000 {
+            let xsave = seq.read_consistent();
+            let first = xsave.legacy.xmm[0].0[0];
+            assert!(xsave.legacy.xmm.iter().all(|x| x.0 == [first; 16]));
+        }
+
+        writer.join().unwrap();
+    }
+}
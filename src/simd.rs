@@ -0,0 +1,131 @@
+//! Conversions between saved registers and `core::arch` vector types
+//!
+//! These let a register pulled out of an `XSave` be used with intrinsics
+//! directly. The vector types have no particular element type when held
+//! in a register, so each conversion preserves the bytes exactly.
+
+use core::arch::x86_64::{__m128, __m128d, __m128i, __m256i};
+use core::mem::transmute;
+
+use super::*;
+
+/// A YMM register, as its low and high halves
+///
+/// The low half is the XMM register of the same index.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ymm {
+    pub low: Xmm,
+    pub high: Xmm,
+}
+
+macro_rules! xmm {
+    ($($vector:ty),*) => {
+        $(
+            impl From<Xmm> for $vector {
+                #[inline]
+                fn from(xmm: Xmm) -> Self {
+                    // SAFETY: both are 16 bytes and every bit pattern is valid.
+                    unsafe { transmute::<[u8; 16], $vector>(xmm.0) }
+                }
+            }
+
+            impl From<$vector> for Xmm {
+                #[inline]
+                fn from(vector: $vector) -> Self {
+                    // SAFETY: both are 16 bytes and every bit pattern is valid.
+                    Self(unsafe { transmute::<$vector, [u8; 16]>(vector) })
+                }
+            }
+        )*
+    };
+}
+
+xmm!(__m128i, __m128, __m128d);
+
+impl From<Ymm> for __m256i {
+    #[inline]
+    fn from(ymm: Ymm) -> Self {
+        // SAFETY: both are 32 bytes and every bit pattern is valid.
+        unsafe { transmute::<[[u8; 16]; 2], Self>([ymm.low.0, ymm.high.0]) }
+    }
+}
+
+impl From<__m256i> for Ymm {
+    #[inline]
+    fn from(vector: __m256i) -> Self {
+        // SAFETY: both are 32 bytes and every bit pattern is valid.
+        let [low, high] = unsafe { transmute::<__m256i, [[u8; 16]; 2]>(vector) };
+        Self {
+            low: Xmm(low),
+            high: Xmm(high),
+        }
+    }
+}
+
+impl XSave {
+    /// Read a YMM register
+    ///
+    /// The high half is zero if the AVX component is absent. This fails
+    /// with `XSaveError::InvalidRegister` unless `index` is below 16.
+    pub fn ymm(&self, index: u8) -> Result<Ymm, XSaveError> {
+        let low = *self
+            .legacy
+            .xmm
+            .get(usize::from(index))
+            .ok_or(XSaveError::InvalidRegister(index))?;
+
+        let mut high = Xmm::default();
+        let avx = self.component_bytes(Component::Avx);
+        let offset = usize::from(index) * 16;
+        if let Some(bytes) = avx.and_then(|b| b.get(offset..offset + 16)) {
+            high.0.copy_from_slice(bytes);
+        }
+
+        Ok(Ymm { low, high })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::arch::x86_64::{_mm_cvtsd_f64, _mm_cvtsi128_si64};
+
+    use super::*;
+
+    #[test]
+    fn xmm() {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&1.5f64.to_le_bytes());
+
+        let vector: __m128i = Xmm(bytes).into();
+        assert_eq!(
+            unsafe { _mm_cvtsi128_si64(vector) },
+            1.5f64.to_bits() as i64
+        );
+
+        let vector: __m128d = Xmm(bytes).into();
+        assert_eq!(unsafe { _mm_cvtsd_f64(vector) }, 1.5);
+        assert_eq!(Xmm::from(vector).0, bytes);
+        assert_eq!(Xmm::from(__m128::from(Xmm(bytes))).0, bytes);
+    }
+
+    #[test]
+    fn ymm() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[3].0[0] = 1;
+        assert_eq!(xsave.ymm(3).unwrap().high.0, [0; 16]);
+        assert_eq!(xsave.ymm(16).err(), Some(XSaveError::InvalidRegister(16)));
+
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576 + 3 * 16 + 9] = 2;
+        let ymm = xsave.ymm(3).unwrap();
+        assert_eq!(ymm.low.0[0], 1);
+
+        let vector = __m256i::from(ymm);
+        let lanes = unsafe { transmute::<__m256i, [u64; 4]>(vector) };
+        assert_eq!(lanes, [1, 0, 0, 0x200]);
+
+        let back = Ymm::from(vector);
+        assert_eq!((back.low.0, back.high.0), (ymm.low.0, ymm.high.0));
+    }
+}
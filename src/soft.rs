@@ -0,0 +1,116 @@
+//! A software model of the extended CPU state
+//!
+//! Miri cannot execute the XSAVE instructions, so under Miri, when the
+//! `soft` feature is enabled, or on wasm32, `save()` and `load()` operate
+//! on a per-thread `SoftBackend` instead. Restoring a buffer which would
+//! fault on hardware aborts the process.
+//!
+//! The per-thread state needs `std`, which is therefore only linked when
+//! this backend is selected; see `build.rs`.
+
+use std::cell::Cell;
+use std::io::Write;
+
+use super::backend::{Backend, SoftBackend};
+use super::*;
+
+std::thread_local! {
+    static STATE: Cell<SoftBackend> = const { Cell::new(SoftBackend::new()) };
+}
+
+/// Read the model's XCR0
+#[inline]
+pub(crate) fn xcr0() -> Option<XStateBv> {
+    Some(SoftBackend::XCR0)
+}
+
+/// The model always supports and enables XSAVE
+#[inline]
+pub(crate) fn preflight() -> Result<(), XSaveError> {
+    Ok(())
+}
+
+/// The model's equivalent of a general protection fault
+fn fault(reason: &str) -> ! {
+    let _ = writeln!(std::io::stderr(), "xsave: #GP in xrstor: {}", reason);
+    std::process::abort()
+}
+
+/// Model `fxsave`
+pub(crate) fn fxsave(legacy: &mut XSaveLegacy) {
+    *legacy = STATE.with(Cell::get).state().legacy;
+}
+
+/// Model `fxrstor`
+pub(crate) fn fxrstor(legacy: &XSaveLegacy) {
+    let mut xsave = XSave::DEFAULT;
+    save(&mut xsave);
+    xsave.legacy = *legacy;
+    xsave.header.xstate_bv |= XStateBv::X87 | XStateBv::SSE;
+    load(&xsave);
+}
+
+/// Model `xsave` with every enabled component requested
+pub(crate) fn save(xsave: &mut XSave) {
+    STATE.with(Cell::get).save(xsave);
+}
+
+/// Model `xrstor` with every enabled component requested
+pub(crate) fn load(xsave: &XSave) {
+    let mut model = STATE.with(Cell::get);
+    if let Some(reason) = model.fault(xsave) {
+        fault(reason);
+    }
+
+    model.restore(xsave);
+    STATE.with(|cell| cell.set(model));
+}
+
+#[cfg(all(test, xsave_soft))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[0] = Xmm([0xAA; 16]);
+        xsave.legacy.fcw = Fcw::from_bits_truncate(0x027F);
+        xsave.load();
+
+        let mut saved = XSave::DEFAULT;
+        saved.save();
+        assert_eq!(saved.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(saved.legacy.xmm[0].0, [0xAA; 16]);
+        assert_eq!(saved.legacy.fcw.bits, 0x027F);
+        assert_eq!(saved.legacy.mxcsr_mask, SoftBackend::MXCSR_MASK);
+        assert_eq!(MxCsr::detect_mask(), SoftBackend::MXCSR_MASK);
+    }
+
+    #[test]
+    fn init() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[0] = Xmm([0xAA; 16]);
+        xsave.enable(Component::Avx).unwrap();
+        xsave.as_bytes_mut()[576] = 0xAA;
+        xsave.load();
+
+        xsave.header.xstate_bv = XStateBv::X87;
+        xsave.load();
+
+        let mut saved = XSave::DEFAULT;
+        saved.save();
+        assert_eq!(saved.header.xstate_bv, XStateBv::X87);
+        assert_eq!(saved.legacy.xmm[0].0, [0; 16]);
+        assert_eq!(saved.as_bytes()[576], 0);
+    }
+
+    #[test]
+    fn try_load() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.header.xstate_bv = XStateBv::AMX_TILECFG;
+        assert_eq!(
+            xsave.try_load(),
+            Err(XSaveError::UnsupportedComponents(XStateBv::AMX_TILECFG))
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Snapshots of the SSE state alone
+
+use super::*;
+
+/// The SSE registers and MXCSR
+///
+/// This is a small alternative to `XSave` for code, such as coroutine
+/// libraries, which only needs to preserve the SIMD state that the System V
+/// ABI leaves to the caller. It is saved and restored with `stmxcsr`,
+/// `ldmxcsr` and `movaps`, without touching the x87 or extended state.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, ConstDefault)]
+pub struct SseState {
+    pub xmm: [Xmm; 16],
+    pub mxcsr: MxCsr,
+}
+
+impl Default for SseState {
+    #[inline]
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl SseState {
+    /// Save the SSE registers and MXCSR
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub extern "C" fn save(&mut self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            soft::save(&mut xsave);
+            *self = Self::from(&xsave);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "movaps  [{0} + 0x00], xmm0",
+                "movaps  [{0} + 0x10], xmm1",
+                "movaps  [{0} + 0x20], xmm2",
+                "movaps  [{0} + 0x30], xmm3",
+                "movaps  [{0} + 0x40], xmm4",
+                "movaps  [{0} + 0x50], xmm5",
+                "movaps  [{0} + 0x60], xmm6",
+                "movaps  [{0} + 0x70], xmm7",
+                "movaps  [{0} + 0x80], xmm8",
+                "movaps  [{0} + 0x90], xmm9",
+                "movaps  [{0} + 0xA0], xmm10",
+                "movaps  [{0} + 0xB0], xmm11",
+                "movaps  [{0} + 0xC0], xmm12",
+                "movaps  [{0} + 0xD0], xmm13",
+                "movaps  [{0} + 0xE0], xmm14",
+                "movaps  [{0} + 0xF0], xmm15",
+                "stmxcsr [{0} + 0x100]",
+                in(reg) self,
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+
+    /// Restore the SSE registers and MXCSR
+    ///
+    /// Setting reserved MXCSR bits raises a general protection fault.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub extern "C" fn load(&self) {
+        #[cfg(xsave_soft)]
+        {
+            let mut xsave = XSave::DEFAULT;
+            soft::save(&mut xsave);
+            self.apply(&mut xsave);
+            soft::load(&xsave);
+        }
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            asm!(
+                "movaps  xmm0, [{0} + 0x00]",
+                "movaps  xmm1, [{0} + 0x10]",
+                "movaps  xmm2, [{0} + 0x20]",
+                "movaps  xmm3, [{0} + 0x30]",
+                "movaps  xmm4, [{0} + 0x40]",
+                "movaps  xmm5, [{0} + 0x50]",
+                "movaps  xmm6, [{0} + 0x60]",
+                "movaps  xmm7, [{0} + 0x70]",
+                "movaps  xmm8, [{0} + 0x80]",
+                "movaps  xmm9, [{0} + 0x90]",
+                "movaps  xmm10, [{0} + 0xA0]",
+                "movaps  xmm11, [{0} + 0xB0]",
+                "movaps  xmm12, [{0} + 0xC0]",
+                "movaps  xmm13, [{0} + 0xD0]",
+                "movaps  xmm14, [{0} + 0xE0]",
+                "movaps  xmm15, [{0} + 0xF0]",
+                "ldmxcsr [{0} + 0x100]",
+                in(reg) self,
+                clobber_abi("C"),
+                options(nostack, preserves_flags),
+            )
+        }
+    }
+
+    /// Copy the SSE state into an `XSave`, marking SSE as present
+    #[inline]
+    pub fn apply(&self, xsave: &mut XSave) {
+        xsave.legacy.xmm = self.xmm;
+        xsave.legacy.mxcsr = self.mxcsr;
+        xsave.header.xstate_bv |= XStateBv::SSE;
+    }
+}
+
+impl From<&XSave> for SseState {
+    #[inline]
+    fn from(xsave: &XSave) -> Self {
+        Self {
+            xmm: xsave.legacy.xmm,
+            mxcsr: xsave.legacy.mxcsr,
+        }
+    }
+}
+
+impl From<&SseState> for XSave {
+    /// Build an `XSave` holding this SSE state and the initial x87 state
+    #[inline]
+    fn from(sse: &SseState) -> Self {
+        let mut xsave = XSave::fxsave_default();
+        sse.apply(&mut xsave);
+        xsave
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<SseState>(), 272);
+        assert_eq!(core::mem::align_of::<SseState>(), 16);
+    }
+
+    #[test]
+    fn convert() {
+        let mut sse = SseState::DEFAULT;
+        sse.xmm[5] = Xmm([0x55; 16]);
+        sse.mxcsr = MxCsr::from_bits_truncate(0x9FC0);
+
+        let xsave = XSave::from(&sse);
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(xsave.legacy.xmm[5].0, [0x55; 16]);
+
+        let back = SseState::from(&xsave);
+        assert_eq!(back.xmm[5].0, [0x55; 16]);
+        assert_eq!(back.mxcsr, sse.mxcsr);
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn roundtrip() {
+        let mut original = SseState::DEFAULT;
+        original.save();
+
+        let mut sse = original;
+        sse.xmm[15] = Xmm([0xA5; 16]);
+        sse.mxcsr |= MxCsr::FLUSH_TO_ZERO;
+        sse.load();
+
+        let mut saved = SseState::DEFAULT;
+        saved.save();
+        original.load();
+
+        assert_eq!(saved.xmm[15].0, [0xA5; 16]);
+        assert_eq!(saved.mxcsr, sse.mxcsr);
+    }
+}
@@ -0,0 +1,214 @@
+//! Use from UEFI applications and drivers
+//!
+//! Loaders running in boot services execute at CPL 0 on a stack owned by
+//! the firmware, which need not have enabled XSAVE. `check()` reports
+//! whether it has, and `enable()` sets up CR0, CR4 and XCR0 otherwise.
+//!
+//! The UEFI targets disable the red zone. None of the instructions issued
+//! by this crate touch the stack, so nothing here depends on it.
+//!
+//! `save()` and `load()` follow the UEFI calling convention, which on
+//! x86_64 is the Microsoft x64 one, and return an `EFI_STATUS`, so that
+//! they can be called from C or installed in a protocol interface. They
+//! are declared `extern "win64"`, since `extern "efiapi"` requires a newer
+//! compiler than this crate supports.
+
+#[cfg(not(doc))]
+use core::arch::asm;
+
+use super::component;
+use super::*;
+
+/// An `EFI_STATUS` code
+pub type Status = usize;
+
+/// The high bit, set in every error code
+const ERROR: Status = 1 << (usize::BITS - 1);
+
+/// The operation completed successfully
+pub const EFI_SUCCESS: Status = 0;
+
+/// A parameter was incorrect
+pub const EFI_INVALID_PARAMETER: Status = ERROR | 2;
+
+/// The operation is not supported
+pub const EFI_UNSUPPORTED: Status = ERROR | 3;
+
+/// There is no data pending upon return
+pub const EFI_NOT_READY: Status = ERROR | 6;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// Convert a result into the matching `EFI_STATUS`
+///
+/// Missing CPU support becomes `EFI_UNSUPPORTED`, XSAVE not being enabled
+/// `EFI_NOT_READY`, and anything else `EFI_INVALID_PARAMETER`.
+pub fn status(result: Result<(), XSaveError>) -> Status {
+    match result {
+        Ok(()) => EFI_SUCCESS,
+        Err(XSaveError::UnsupportedCpu | XSaveError::UnsupportedComponents(..)) => EFI_UNSUPPORTED,
+        Err(XSaveError::NotEnabled) => EFI_NOT_READY,
+        Err(..) => EFI_INVALID_PARAMETER,
+    }
+}
+
+/// Check that XSAVE can be used without faulting
+///
+/// This fails with `XSaveError::UnsupportedCpu` if the CPU lacks XSAVE
+/// and with `XSaveError::NotEnabled` if the firmware has not enabled it.
+#[inline]
+pub fn check() -> Result<(), XSaveError> {
+    component::preflight()
+}
+
+/// Compute CR0 and CR4 with the x87, SSE and XSAVE instructions enabled
+///
+/// In CR0, emulation and task switching are cleared and native x87 error
+/// reporting selected. In CR4, FXSAVE, SIMD exceptions and XSAVE are
+/// enabled. Every other bit is kept.
+#[inline]
+pub const fn enable_bits(cr0: u64, cr4: u64) -> (u64, u64) {
+    (
+        (cr0 & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE,
+        cr4 | CR4_OSFXSR | CR4_OSXMMEXCPT | CR4_OSXSAVE,
+    )
+}
+
+/// Enable XSAVE with the requested user components, returning XCR0
+///
+/// The components enabled are those of `request` which the CPU supports,
+/// along with x87, which is always enabled. This fails without changing
+/// anything with `XSaveError::UnsupportedCpu` if the CPU lacks XSAVE,
+/// with `XSaveError::NotEnabled` if not running at CPL 0, and with
+/// `XSaveError::InvalidMask` if the resulting XCR0 would be rejected.
+///
+/// # Safety
+///
+/// This changes control registers which the firmware relies on too. The
+/// caller must ensure that no code on this CPU has live state in any
+/// component which this disables.
+pub unsafe fn enable(request: XStateBv) -> Result<XStateBv, XSaveError> {
+    if !component::has_xsave() {
+        return Err(XSaveError::UnsupportedCpu);
+    }
+
+    let cr0 = component::cr0().ok_or(XSaveError::NotEnabled)?;
+
+    let xcr0 = (request | XStateBv::X87) & XStateMasks::supported().xcr0;
+    let masks = XStateMasks {
+        xcr0,
+        xss: XStateBv::empty(),
+    };
+    masks.validate()?;
+
+    let mut cr4 = 0u64;
+
+    #[cfg(not(doc))]
+    asm!("mov {}, cr4", inout(reg) cr4, options(nomem, nostack, preserves_flags));
+
+    let (cr0, cr4) = enable_bits(cr0, cr4);
+
+    #[cfg(not(doc))]
+    asm!(
+        "mov cr0, {cr0}",
+        "mov cr4, {cr4}",
+        "xsetbv",
+        cr0 = in(reg) cr0,
+        cr4 = in(reg) cr4,
+        in("ecx") 0u32,
+        in("eax") xcr0.bits as u32,
+        in("edx") (xcr0.bits >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+
+    Ok(xcr0)
+}
+
+/// Whether a pointer is a usable `XSave`
+#[inline]
+fn usable(ptr: *const XSave) -> bool {
+    !ptr.is_null() && ptr as usize % core::mem::align_of::<XSave>() == 0
+}
+
+/// Save the extended CPU state, as `XSave::try_save()`
+///
+/// A null or misaligned pointer gives `EFI_INVALID_PARAMETER`.
+///
+/// # Safety
+///
+/// A non-null, aligned `xsave` must be valid for writes.
+pub unsafe extern "win64" fn save(xsave: *mut XSave) -> Status {
+    if !usable(xsave) {
+        return EFI_INVALID_PARAMETER;
+    }
+
+    status((*xsave).try_save())
+}
+
+/// Load the extended CPU state, as `XSave::try_load()`
+///
+/// A null or misaligned pointer gives `EFI_INVALID_PARAMETER`.
+///
+/// # Safety
+///
+/// A non-null, aligned `xsave` must be valid for reads.
+pub unsafe extern "win64" fn load(xsave: *const XSave) -> Status {
+    if !usable(xsave) {
+        return EFI_INVALID_PARAMETER;
+    }
+
+    status((*xsave).try_load())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits() {
+        let (cr0, cr4) = enable_bits(0x8000_001D, 0x20);
+        assert_eq!(cr0, 0x8000_0033);
+        assert_eq!(cr4, 0x4_0620);
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(status(Ok(())), EFI_SUCCESS);
+        assert_eq!(status(Err(XSaveError::UnsupportedCpu)), EFI_UNSUPPORTED);
+        assert_eq!(status(Err(XSaveError::NotEnabled)), EFI_NOT_READY);
+        assert_eq!(
+            status(Err(XSaveError::MxCsrReserved(1))),
+            EFI_INVALID_PARAMETER
+        );
+        assert_eq!(EFI_UNSUPPORTED, 0x8000_0000_0000_0003);
+    }
+
+    #[test]
+    fn pointers() {
+        unsafe {
+            assert_eq!(save(core::ptr::null_mut()), EFI_INVALID_PARAMETER);
+            assert_eq!(load(8 as *const XSave), EFI_INVALID_PARAMETER);
+        }
+    }
+
+    #[test]
+    fn user_mode() {
+        if !component::has_xsave() {
+            assert_eq!(check(), Err(XSaveError::UnsupportedCpu));
+            return;
+        }
+
+        assert_eq!(check(), Ok(()));
+        assert_eq!(
+            unsafe { enable(XStateBv::SSE) },
+            Err(XSaveError::NotEnabled)
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! XSave buffers without the alignment requirement
+
+use super::*;
+
+/// The bytes of an `XSave`, with an alignment of one
+///
+/// The instructions require a 64-byte aligned area, which prevents an
+/// `XSave` from being embedded in packed network messages or foreign
+/// structures. This type holds the same bytes at any address and converts
+/// to and from `XSave` by copying.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct XSaveUnaligned([u8; size_of::<XSave>()]);
+
+impl ConstDefault for XSaveUnaligned {
+    const DEFAULT: Self = Self::new(&XSave::DEFAULT);
+}
+
+impl Default for XSaveUnaligned {
+    #[inline]
+    fn default() -> Self {
+        Self::from(&XSave::default())
+    }
+}
+
+impl XSaveUnaligned {
+    /// Copy an `XSave`
+    #[inline]
+    pub const fn new(xsave: &XSave) -> Self {
+        // SAFETY: `XSave` has no padding, so every byte is initialized.
+        Self(unsafe { *(xsave as *const XSave as *const [u8; size_of::<XSave>()]) })
+    }
+
+    /// Copy the bytes back into an aligned `XSave`
+    #[inline]
+    pub fn get(&self) -> XSave {
+        let mut xsave = XSave::DEFAULT;
+        xsave.as_bytes_mut().copy_from_slice(&self.0);
+        xsave
+    }
+
+    /// The bytes of the buffer
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; size_of::<XSave>()] {
+        &self.0
+    }
+
+    /// The bytes of the buffer, mutably
+    #[inline]
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; size_of::<XSave>()] {
+        &mut self.0
+    }
+}
+
+impl From<&XSave> for XSaveUnaligned {
+    #[inline]
+    fn from(xsave: &XSave) -> Self {
+        Self::new(xsave)
+    }
+}
+
+impl From<&XSaveUnaligned> for XSave {
+    #[inline]
+    fn from(unaligned: &XSaveUnaligned) -> Self {
+        unaligned.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::align_of;
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<XSaveUnaligned>(), size_of::<XSave>());
+        assert_eq!(align_of::<XSaveUnaligned>(), 1);
+    }
+
+    #[test]
+    fn packed() {
+        #[repr(C, packed)]
+        struct Message {
+            tag: u8,
+            xsave: XSaveUnaligned,
+        }
+
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.xmm[1] = Xmm([0x11; 16]);
+
+        let mut message = Message {
+            tag: 7,
+            xsave: XSaveUnaligned::from(&xsave),
+        };
+        message.xsave.as_bytes_mut()[176] = 0x12;
+
+        let back = XSave::from(&{ message.xsave });
+        assert_eq!(message.tag, 7);
+        assert_eq!(back.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(back.legacy.xmm[1].0[0], 0x12);
+        assert_eq!(back.legacy.xmm[1].0[1], 0x11);
+        assert_eq!(XSaveUnaligned::DEFAULT.get().legacy.mxcsr, MxCsr::DEFAULT);
+    }
+}
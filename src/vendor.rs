@@ -0,0 +1,128 @@
+//! Vendor differences in the saved x87 pointers
+//!
+//! Intel CPUs always save the last instruction pointer, and since the
+//! Pentium 4 record the last opcode only for instructions raising an
+//! unmasked exception; see `FpPointerUpdate`. AMD CPUs which do not
+//! enumerate `RstrFpErrPtrs` (CPUID.80000008h:EBX[2]) go further: the save
+//! instructions write FIP, FDP and FOP only while an unmasked exception is
+//! pending, and the restore instructions load them only then, leaving
+//! whatever was there before otherwise. Saving the same state on both
+//! vendors therefore gives different bytes. `XSave::normalize()` clears
+//! the pointers whenever they carry no architectural meaning, so that the
+//! results can be compared.
+//!
+//! AMD's fast FXSAVE/FXRSTOR (CPUID.80000001h:EDX[25]) additionally skips
+//! the XMM registers when EFER.FFXSR is set at CPL 0 in 64-bit mode. This
+//! only concerns `fxsave` and `fxrstor`, not the XSAVE instructions.
+
+#[cfg(target_arch = "x86_64")]
+use super::component::cpuid;
+use super::*;
+
+/// The vendor of a CPU, as far as the saved state is concerned
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Vendor {
+    /// Intel
+    Intel,
+
+    /// AMD, and Hygon, which derives from it
+    Amd,
+
+    /// Any other vendor
+    Other,
+}
+
+impl Vendor {
+    /// Identify the vendor from the CPUID leaf 0 vendor string
+    ///
+    /// The string is the concatenation of EBX, EDX and ECX.
+    pub fn from_id(id: &[u8; 12]) -> Self {
+        match id {
+            b"GenuineIntel" => Self::Intel,
+            b"AuthenticAMD" | b"HygonGenuine" => Self::Amd,
+            _ => Self::Other,
+        }
+    }
+
+    /// Identify the vendor of the current CPU
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        let leaf = cpuid(0, 0);
+
+        let mut id = [0; 12];
+        id[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        id[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+        id[8..].copy_from_slice(&leaf.ecx.to_le_bytes());
+        Self::from_id(&id)
+    }
+
+    /// Whether the current CPU saves the x87 pointers only on exceptions
+    ///
+    /// This is the case for AMD CPUs which do not enumerate
+    /// `RstrFpErrPtrs`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn pointers_on_exception_only(self) -> bool {
+        self == Self::Amd
+            && cpuid(0x8000_0000, 0).eax >= 0x8000_0008
+            && cpuid(0x8000_0008, 0).ebx & (1 << 2) == 0
+    }
+}
+
+impl XSave {
+    /// Clear the x87 pointers unless an unmasked exception is pending
+    ///
+    /// Without a pending exception, FIP, FDP and FOP are only of
+    /// diagnostic interest, and whether they are saved depends on the
+    /// vendor. Normalizing both sides before comparing two saves avoids
+    /// reporting that as a difference.
+    #[inline]
+    pub fn normalize(&mut self) {
+        let legacy = &mut self.legacy;
+        if !legacy.fsw.contains(Fsw::EXCEPTION_SUMMARY) {
+            legacy.fop = 0;
+            legacy.fip = 0;
+            legacy.fdp = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_id() {
+        assert_eq!(Vendor::from_id(b"GenuineIntel"), Vendor::Intel);
+        assert_eq!(Vendor::from_id(b"AuthenticAMD"), Vendor::Amd);
+        assert_eq!(Vendor::from_id(b"HygonGenuine"), Vendor::Amd);
+        assert_eq!(Vendor::from_id(b"CentaurHauls"), Vendor::Other);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn detect() {
+        let vendor = Vendor::detect();
+        if vendor != Vendor::Amd {
+            assert!(!vendor.pointers_on_exception_only());
+        }
+    }
+
+    #[test]
+    fn normalize() {
+        let mut xsave = XSave::DEFAULT;
+        xsave.legacy.fop = 0x123;
+        xsave.legacy.fip = 0x1000;
+        xsave.legacy.fdp = 0x2000;
+
+        let mut pending = xsave;
+        pending.legacy.fsw |= Fsw::EXCEPTION_SUMMARY;
+        pending.normalize();
+        assert_eq!(pending.legacy.fip, 0x1000);
+
+        xsave.normalize();
+        assert_eq!(
+            (xsave.legacy.fop, xsave.legacy.fip, xsave.legacy.fdp),
+            (0, 0, 0)
+        );
+    }
+}
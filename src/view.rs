@@ -0,0 +1,445 @@
+//! Borrowed views of XSAVE areas in foreign memory
+
+#[cfg(xsave_hw)]
+use core::arch::asm;
+use core::mem::align_of;
+use core::ops::{Deref, DerefMut};
+
+use super::component::EXTENDED;
+use super::*;
+
+/// Check that a buffer can be viewed as an `XSave`
+#[inline]
+fn check(addr: usize, len: usize) -> Result<(), XSaveError> {
+    if len < size_of::<XSave>() {
+        return Err(XSaveError::InvalidLength(len));
+    }
+
+    if addr % align_of::<XSave>() != 0 {
+        return Err(XSaveError::Misaligned(addr));
+    }
+
+    Ok(())
+}
+
+impl XSave {
+    /// The raw bytes of the area
+    #[inline]
+    pub fn bytes_of(&self) -> &[u8; size_of::<XSave>()] {
+        self.as_bytes()
+    }
+
+    /// The mutable raw bytes of the area
+    ///
+    /// Every bit pattern is a valid `XSave`, so any bytes may be written.
+    #[inline]
+    pub fn mut_bytes_of(&mut self) -> &mut [u8; size_of::<XSave>()] {
+        self.as_bytes_mut()
+    }
+
+    /// Reinterpret a buffer as an `XSave` without copying
+    ///
+    /// The buffer must be 64-byte aligned and at least as large as an
+    /// `XSave`; only the leading bytes are used.
+    #[inline]
+    pub fn ref_from_bytes(bytes: &[u8]) -> Result<&Self, XSaveError> {
+        XSaveRef::new(bytes).map(XSaveRef::get)
+    }
+
+    /// Reinterpret a mutable buffer as an `XSave` without copying
+    ///
+    /// The requirements are the same as for `XSave::ref_from_bytes()`.
+    #[inline]
+    pub fn mut_from_bytes(bytes: &mut [u8]) -> Result<&mut Self, XSaveError> {
+        XSaveMut::new(bytes).map(XSaveMut::into_mut)
+    }
+
+    /// Copy the area to a new location, returning the copy
+    ///
+    /// This is for memory managers which move save areas, for instance
+    /// while defragmenting or migrating. The destination is checked in
+    /// the same way as by `XSave::mut_from_bytes()`.
+    #[inline]
+    pub fn relocate_into<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut XSave, XSaveError> {
+        let xsave = Self::mut_from_bytes(dst)?;
+        *xsave = *self;
+        Ok(xsave)
+    }
+
+    /// Copy the area to a new location by address, returning the copy
+    ///
+    /// The copy is not performed if the address is null, which fails with
+    /// `XSaveError::NullPointer`, or not 64-byte aligned.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `size_of::<XSave>()` bytes and must
+    /// not overlap `self`, and the memory must not be accessed through any
+    /// other pointer for `'a`.
+    #[inline]
+    pub unsafe fn relocate_to<'a>(&self, dst: *mut u8) -> Result<&'a mut XSave, XSaveError> {
+        if dst.is_null() {
+            return Err(XSaveError::NullPointer);
+        }
+
+        check(dst as usize, size_of::<XSave>())?;
+
+        let dst = dst as *mut XSave;
+        core::ptr::copy_nonoverlapping(self, dst, 1);
+        Ok(&mut *dst)
+    }
+}
+
+/// A shared view of an XSAVE area in foreign memory
+///
+/// This gives access to the full `XSave` API without copying, which is
+/// useful for inspecting areas in mapped guest memory or core files. Only
+/// the first `size_of::<XSave>()` bytes of the buffer are viewed.
+#[derive(Copy, Clone, Debug)]
+pub struct XSaveRef<'a>(&'a XSave);
+
+impl<'a> XSaveRef<'a> {
+    /// Create a view of a buffer
+    ///
+    /// The buffer must be 64-byte aligned and at least as large as an
+    /// `XSave`.
+    #[inline]
+    pub fn new(bytes: &'a [u8]) -> Result<Self, XSaveError> {
+        check(bytes.as_ptr() as usize, bytes.len())?;
+
+        // SAFETY: the buffer is large enough and aligned, and every bit
+        // pattern is a valid `XSave`.
+        Ok(Self(unsafe { &*(bytes.as_ptr() as *const XSave) }))
+    }
+
+    /// The viewed area, for the lifetime of the buffer
+    #[inline]
+    pub fn get(self) -> &'a XSave {
+        self.0
+    }
+}
+
+impl Deref for XSaveRef<'_> {
+    type Target = XSave;
+
+    #[inline]
+    fn deref(&self) -> &XSave {
+        self.0
+    }
+}
+
+/// An exclusive view of an XSAVE area in foreign memory
+///
+/// Like `XSaveRef`, but allowing the area to be modified in place.
+#[derive(Debug)]
+pub struct XSaveMut<'a>(&'a mut XSave);
+
+impl<'a> XSaveMut<'a> {
+    /// Create a view of a buffer
+    ///
+    /// The buffer must be 64-byte aligned and at least as large as an
+    /// `XSave`.
+    #[inline]
+    pub fn new(bytes: &'a mut [u8]) -> Result<Self, XSaveError> {
+        check(bytes.as_ptr() as usize, bytes.len())?;
+
+        // SAFETY: the buffer is large enough and aligned, and every bit
+        // pattern is a valid `XSave`.
+        Ok(Self(unsafe { &mut *(bytes.as_mut_ptr() as *mut XSave) }))
+    }
+
+    /// The viewed area, for the lifetime of the buffer
+    #[inline]
+    pub fn into_mut(self) -> &'a mut XSave {
+        self.0
+    }
+}
+
+impl Deref for XSaveMut<'_> {
+    type Target = XSave;
+
+    #[inline]
+    fn deref(&self) -> &XSave {
+        self.0
+    }
+}
+
+impl DerefMut for XSaveMut<'_> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut XSave {
+        self.0
+    }
+}
+
+/// The size of a 4KB region
+const REGION: usize = 4096;
+
+/// A view of an externally owned 4KB XSAVE region
+///
+/// This is the size of the region in `struct kvm_xsave` and several other
+/// ABIs. The view exposes the structure of the region in place and can
+/// save and restore the CPU state directly into and out of it, so VMMs can
+/// operate on the ioctl buffer without an intermediate copy.
+#[derive(Debug)]
+pub struct XSaveView<'a>(&'a mut [u8; REGION]);
+
+impl<'a> XSaveView<'a> {
+    /// The size of the region
+    pub const SIZE: usize = REGION;
+
+    /// The components whose standard format data fits within the region
+    #[cfg(xsave_asm)]
+    #[cfg_attr(xsave_soft, allow(dead_code))]
+    fn rfbm() -> u64 {
+        let caps = &ComponentTable::ARCHITECTURAL;
+        let mut rfbm = (XStateBv::X87 | XStateBv::SSE).bits;
+
+        for i in 2..64 {
+            if let Some(info) = caps.get(i) {
+                let end = info.offset as usize + info.size as usize;
+                if !info.supervisor && info.size != 0 && end <= Self::SIZE {
+                    rfbm |= 1 << i;
+                }
+            }
+        }
+
+        rfbm
+    }
+
+    /// Create a view of a region
+    ///
+    /// The region must be 64-byte aligned.
+    #[inline]
+    pub fn new(region: &'a mut [u8; REGION]) -> Result<Self, XSaveError> {
+        check(region.as_ptr() as usize, region.len())?;
+        Ok(Self(region))
+    }
+
+    /// The region viewed as an `XSave`
+    #[inline]
+    pub fn xsave(&self) -> &XSave {
+        // SAFETY: the region is aligned and larger than an `XSave`, and
+        // every bit pattern is a valid `XSave`.
+        unsafe { &*(self.0.as_ptr() as *const XSave) }
+    }
+
+    /// The region viewed as a mutable `XSave`
+    #[inline]
+    pub fn xsave_mut(&mut self) -> &mut XSave {
+        // SAFETY: as in `xsave()`.
+        unsafe { &mut *(self.0.as_mut_ptr() as *mut XSave) }
+    }
+
+    /// The legacy area
+    #[inline]
+    pub fn legacy(&self) -> &XSaveLegacy {
+        &self.xsave().legacy
+    }
+
+    /// The mutable legacy area
+    #[inline]
+    pub fn legacy_mut(&mut self) -> &mut XSaveLegacy {
+        &mut self.xsave_mut().legacy
+    }
+
+    /// The header
+    #[inline]
+    pub fn header(&self) -> &XSaveHeader {
+        &self.xsave().header
+    }
+
+    /// The mutable header
+    #[inline]
+    pub fn header_mut(&mut self) -> &mut XSaveHeader {
+        &mut self.xsave_mut().header
+    }
+
+    /// The extended area, which runs to the end of the region
+    #[inline]
+    pub fn extended(&self) -> &[u8] {
+        &self.0[EXTENDED..]
+    }
+
+    /// The mutable extended area
+    #[inline]
+    pub fn extended_mut(&mut self) -> &mut [u8] {
+        &mut self.0[EXTENDED..]
+    }
+
+    /// Save the extended CPU state into the region
+    ///
+    /// Only the components which fit within the region are saved.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn save(&mut self) {
+        #[cfg(xsave_soft)]
+        soft::save(self.xsave_mut());
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            let rfbm = Self::rfbm();
+            asm!(
+                "xsave   [{}]",
+                in(reg) self.0.as_mut_ptr(),
+                in("eax") rfbm as u32,
+                in("edx") (rfbm >> 32) as u32,
+                options(nostack),
+            )
+        }
+    }
+
+    /// Load the extended CPU state from the region
+    ///
+    /// Only the components which fit within the region are restored.
+    #[inline]
+    #[cfg(xsave_asm)]
+    pub fn load(&self) {
+        #[cfg(xsave_soft)]
+        soft::load(self.xsave());
+
+        #[cfg(all(xsave_hw, not(doc)))]
+        unsafe {
+            let rfbm = Self::rfbm();
+            asm!(
+                "xrstor  [{}]",
+                in(reg) self.0.as_ptr(),
+                in("eax") rfbm as u32,
+                in("edx") (rfbm >> 32) as u32,
+                clobber_abi("C"),
+                options(nostack),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(64))]
+    struct Buffer([u8; 4096]);
+
+    #[repr(C, align(64))]
+    struct Misaligned([u8; 4097]);
+
+    #[test]
+    fn view() {
+        let mut buffer = Buffer([0; 4096]);
+        buffer.0[..size_of::<XSave>()].copy_from_slice(XSave::fxsave_default().as_bytes());
+
+        let view = XSaveRef::new(&buffer.0).unwrap();
+        assert_eq!(view.header.xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(view.legacy.fcw, Fcw::DEFAULT);
+
+        let mut view = XSaveMut::new(&mut buffer.0).unwrap();
+        view.enable(Component::Avx).unwrap();
+        assert!(view.header.xstate_bv.contains(XStateBv::AVX));
+        assert_eq!(buffer.0[512], 0b111);
+    }
+
+    #[test]
+    fn region() {
+        let mut buffer = Buffer([0; 4096]);
+        let mut view = XSaveView::new(&mut buffer.0).unwrap();
+        *view.xsave_mut() = XSave::fxsave_default();
+        view.extended_mut()[4095 - EXTENDED] = 0xAA;
+
+        assert_eq!(view.legacy().fcw, Fcw::DEFAULT);
+        assert_eq!(view.header().xstate_bv, XStateBv::X87 | XStateBv::SSE);
+        assert_eq!(view.extended().len(), 4096 - EXTENDED);
+
+        view.header_mut().xstate_bv = XStateBv::X87;
+        assert_eq!(buffer.0[512], 1);
+        assert_eq!(buffer.0[4095], 0xAA);
+
+        let mut buffer = Misaligned([0; 4097]);
+        let addr = buffer.0.as_ptr() as usize + 1;
+        let region = (&mut buffer.0[1..]).try_into().unwrap();
+        assert_eq!(
+            XSaveView::new(region).unwrap_err(),
+            XSaveError::Misaligned(addr)
+        );
+    }
+
+    #[test]
+    #[cfg(xsave_asm)]
+    fn region_save() {
+        let mut buffer = Buffer([0xFF; 4096]);
+        let mut view = XSaveView::new(&mut buffer.0).unwrap();
+        *view.header_mut() = XSaveHeader::DEFAULT;
+        view.save();
+        view.load();
+
+        let fits = XStateBv::from_bits_truncate(XSaveView::rfbm());
+        assert!(!fits.contains(XStateBv::AMX_TILEDATA));
+        assert!(fits.contains(view.header().xstate_bv));
+        assert_eq!(view.legacy().mxcsr_mask, MxCsr::detect_mask());
+    }
+
+    #[test]
+    fn relocate() {
+        let mut xsave = XSave::fxsave_default();
+        xsave.legacy.fip = 0x1234;
+
+        #[repr(C, align(64))]
+        struct Buffer([u8; 3136]);
+
+        let mut buffer = Buffer([0; 3136]);
+        let bytes = &mut buffer.0;
+        let copy = xsave.relocate_into(&mut bytes[..]).unwrap();
+        assert_eq!(copy.legacy.fip, 0x1234);
+        assert_eq!(
+            xsave.relocate_into(&mut bytes[1..]).err(),
+            Some(XSaveError::Misaligned(bytes.as_ptr() as usize + 1))
+        );
+
+        let other = XSave::DEFAULT;
+        let dst = bytes.as_mut_ptr();
+        let copy = unsafe { other.relocate_to(dst) }.unwrap();
+        assert_eq!(copy.legacy.fip, 0);
+        assert_eq!(
+            unsafe { xsave.relocate_to(dst.wrapping_add(8)) }.err(),
+            Some(XSaveError::Misaligned(dst as usize + 8))
+        );
+        assert_eq!(
+            unsafe { xsave.relocate_to(core::ptr::null_mut()) }.err(),
+            Some(XSaveError::NullPointer)
+        );
+    }
+
+    #[test]
+    fn casts() {
+        let mut xsave = XSave::fxsave_default();
+        assert_eq!(xsave.bytes_of()[512], 0b11);
+
+        xsave.mut_bytes_of()[512] = 0b1;
+        assert_eq!(xsave.header.xstate_bv, XStateBv::X87);
+
+        let mut buffer = Buffer([0; 4096]);
+        let cast = XSave::mut_from_bytes(&mut buffer.0).unwrap();
+        cast.header.xstate_bv = XStateBv::SSE;
+        assert_eq!(buffer.0[512], 0b10);
+
+        let cast = XSave::ref_from_bytes(&buffer.0).unwrap();
+        assert_eq!(cast.header.xstate_bv, XStateBv::SSE);
+        assert!(XSave::ref_from_bytes(&buffer.0[8..]).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut buffer = Buffer([0; 4096]);
+        let addr = buffer.0.as_ptr() as usize;
+
+        assert_eq!(
+            XSaveRef::new(&buffer.0[..100]).unwrap_err(),
+            XSaveError::InvalidLength(100)
+        );
+        assert_eq!(
+            XSaveRef::new(&buffer.0[1..]).unwrap_err(),
+            XSaveError::Misaligned(addr + 1)
+        );
+
+        let view = XSaveMut::new(&mut buffer.0[64..]).unwrap();
+        assert_eq!(view.into_mut() as *mut XSave as usize, addr + 64);
+    }
+}
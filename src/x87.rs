@@ -0,0 +1,240 @@
+//! Simulation of the x87 register stack
+//!
+//! The saved registers are in stack order, `mm[0]` being ST(0), while the
+//! abridged tag word is indexed by physical register. Pushing and popping
+//! rotate the former and update the latter, along with TOP and the stack
+//! fault flags, the way an x87 instruction would. The last instruction and
+//! operand pointers can be recorded as well, so that emulated faults look
+//! authentic to guests.
+
+use interop::FpReg;
+
+use super::*;
+
+impl Fsw {
+    /// Get the physical register at the top of the stack
+    #[inline]
+    pub const fn top(self) -> u8 {
+        ((self.bits >> 11) & 0b111) as u8
+    }
+
+    /// Set the physical register at the top of the stack
+    #[inline]
+    pub fn set_top(&mut self, top: u8) {
+        self.bits = (self.bits & !(0b111 << 11)) | u16::from(top & 0b111) << 11;
+    }
+}
+
+impl FpReg {
+    /// The QNaN floating-point indefinite, produced by masked stack faults
+    pub const INDEFINITE: Self = Self {
+        significand: [0, 0, 0, 0xC000],
+        exponent: 0xFFFF,
+    };
+}
+
+/// When the CPU records the last opcode and operand pointer
+///
+/// Since the Pentium 4, Intel CPUs record FOP only for instructions which
+/// raise an unmasked exception, unless fopcode compatibility is enabled.
+/// CPUs enumerating `FDP_EXCPTN_ONLY` (`CPUID.7.0:EBX[6]`) treat FDP alike.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FpPointerUpdate {
+    /// Record for every non-control x87 instruction
+    Always,
+
+    /// Record only when an unmasked exception is pending
+    UnmaskedException,
+}
+
+impl XSaveLegacy {
+    /// Whether a pointer should be recorded under the given policy
+    #[inline]
+    fn should_record(&self, update: FpPointerUpdate) -> bool {
+        match update {
+            FpPointerUpdate::Always => true,
+            FpPointerUpdate::UnmaskedException => self.fsw.contains(Fsw::EXCEPTION_SUMMARY),
+        }
+    }
+
+    /// Record a non-control x87 instruction at `fip` with opcode `fop`
+    ///
+    /// FOP holds the low three bits of the first opcode byte followed by
+    /// the ModR/M byte, so only the low 11 bits of `fop` are kept. FIP is
+    /// always recorded, while FOP follows `update`. Call this after the
+    /// instruction's exception flags are set.
+    #[inline]
+    pub fn record_fp_instruction(&mut self, fip: u64, fop: u16, update: FpPointerUpdate) {
+        self.fip = fip;
+        if self.should_record(update) {
+            self.fop = fop & 0x7FF;
+        }
+    }
+
+    /// Record the memory operand of an x87 instruction at `fdp`
+    ///
+    /// Call this after the instruction's exception flags are set.
+    #[inline]
+    pub fn record_fp_operand(&mut self, fdp: u64, update: FpPointerUpdate) {
+        if self.should_record(update) {
+            self.fdp = fdp;
+        }
+    }
+
+    /// Signal a stack fault, which is an invalid operation
+    ///
+    /// If masked, the caller applies the masked response; otherwise the
+    /// exception is pending and the operation is abandoned.
+    fn stack_fault(&mut self) -> Result<(), FpException> {
+        self.fsw |= Fsw::INVALID_OPERATION | Fsw::STACK_FAULT;
+        if self.fcw.contains(Fcw::INVALID_OPERATION) {
+            return Ok(());
+        }
+
+        self.fsw |= Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY;
+        Err(FpException::InvalidOperation)
+    }
+
+    /// Push a value onto the x87 stack, as `fld` does
+    ///
+    /// If the register below the top is in use, the stack overflows: C1 is
+    /// set and `FpException::InvalidOperation` is returned. When that is
+    /// masked, the indefinite is pushed instead of the value; otherwise the
+    /// stack is left unchanged.
+    pub fn fpush(&mut self, value: FpReg) -> Result<(), FpException> {
+        let top = self.fsw.top().wrapping_sub(1) & 0b111;
+        let overflow = self.ftw & (1 << top) != 0;
+        self.fsw.set(Fsw::CONDITION1, overflow);
+
+        let value = match overflow {
+            true => {
+                self.stack_fault()?;
+                FpReg::INDEFINITE
+            }
+            false => value,
+        };
+
+        self.mm.rotate_right(1);
+        self.mm[0] = MmField::DEFAULT;
+        self.mm[0].mm = value.into();
+        self.ftw |= 1 << top;
+        self.fsw.set_top(top);
+
+        match overflow {
+            true => Err(FpException::InvalidOperation),
+            false => Ok(()),
+        }
+    }
+
+    /// Pop a value from the x87 stack, as `fstp` does
+    ///
+    /// If the top register is empty, the stack underflows: C1 is cleared
+    /// and `FpException::InvalidOperation` is returned. When that is
+    /// masked, the stack is popped regardless and the indefinite is the
+    /// value produced; otherwise the stack is left unchanged.
+    pub fn fpop(&mut self) -> Result<FpReg, FpException> {
+        let top = self.fsw.top();
+        let underflow = self.ftw & (1 << top) == 0;
+        self.fsw.remove(Fsw::CONDITION1);
+
+        if underflow {
+            self.stack_fault()?;
+        }
+
+        let value = FpReg::from(self.mm[0].mm);
+        self.mm.rotate_left(1);
+        self.ftw &= !(1 << top);
+        self.fsw.set_top(top + 1);
+
+        match underflow {
+            true => Err(FpException::InvalidOperation),
+            false => Ok(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg(exponent: u16) -> FpReg {
+        FpReg {
+            significand: [0, 0, 0, 0x8000],
+            exponent,
+        }
+    }
+
+    #[test]
+    fn stack() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.fsw.set_top(0);
+
+        legacy.fpush(reg(0x3FFF)).unwrap();
+        legacy.fpush(reg(0x4000)).unwrap();
+        assert_eq!(legacy.fsw.top(), 6);
+        assert_eq!(legacy.ftw, 0b1100_0000);
+        assert_eq!(FpReg::from(legacy.mm[1].mm), reg(0x3FFF));
+
+        assert_eq!(legacy.fpop(), Ok(reg(0x4000)));
+        assert_eq!(legacy.fpop(), Ok(reg(0x3FFF)));
+        assert_eq!(legacy.fsw.top(), 0);
+        assert_eq!(legacy.ftw, 0);
+        assert!(!legacy.fsw.contains(Fsw::STACK_FAULT));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        for i in 0..8 {
+            legacy.fpush(reg(i)).unwrap();
+        }
+
+        assert_eq!(legacy.fpush(reg(8)), Err(FpException::InvalidOperation));
+        assert!(legacy
+            .fsw
+            .contains(Fsw::INVALID_OPERATION | Fsw::STACK_FAULT | Fsw::CONDITION1));
+        assert!(!legacy.fsw.contains(Fsw::EXCEPTION_SUMMARY));
+        assert_eq!(FpReg::from(legacy.mm[0].mm), FpReg::INDEFINITE);
+        assert_eq!(FpReg::from(legacy.mm[1].mm), reg(7));
+    }
+
+    #[test]
+    fn pointers() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        legacy.record_fp_instruction(0x1000, 0xFDE9, FpPointerUpdate::Always);
+        legacy.record_fp_operand(0x2000, FpPointerUpdate::Always);
+        assert_eq!(
+            (legacy.fip, legacy.fop, legacy.fdp),
+            (0x1000, 0x5E9, 0x2000)
+        );
+
+        let update = FpPointerUpdate::UnmaskedException;
+        legacy.record_fp_instruction(0x1004, 0x1C1, update);
+        legacy.record_fp_operand(0x3000, update);
+        assert_eq!(
+            (legacy.fip, legacy.fop, legacy.fdp),
+            (0x1004, 0x5E9, 0x2000)
+        );
+
+        legacy.fsw |= Fsw::DIVIDE_BY_ZERO | Fsw::EXCEPTION_SUMMARY;
+        legacy.record_fp_instruction(0x1008, 0x1F9, update);
+        legacy.record_fp_operand(0x3000, update);
+        assert_eq!(
+            (legacy.fip, legacy.fop, legacy.fdp),
+            (0x1008, 0x1F9, 0x3000)
+        );
+    }
+
+    #[test]
+    fn underflow() {
+        let mut legacy = XSaveLegacy::DEFAULT;
+        assert_eq!(legacy.fpop(), Err(FpException::InvalidOperation));
+        assert_eq!(legacy.fsw.top(), 1);
+
+        legacy.fcw.remove(Fcw::INVALID_OPERATION);
+        legacy.fsw = Fsw::empty();
+        assert_eq!(legacy.fpop(), Err(FpException::InvalidOperation));
+        assert_eq!(legacy.fsw.top(), 0);
+        assert!(legacy.fsw.contains(Fsw::EXCEPTION_SUMMARY | Fsw::FPU_BUSY));
+    }
+}